@@ -0,0 +1,114 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, Host, SupportedStreamConfig};
+use log::{debug, warn};
+use crate::backend::{AudioBackend, AudioFormat, AudioStream, BackendError, InputHandler, OutputFiller, DEFAULT_FORMAT};
+use crate::devices::{self, DeviceError, DeviceSelector, Direction};
+use crate::sample;
+
+struct OpenDevice {
+    device: Device,
+    config: SupportedStreamConfig,
+}
+
+impl OpenDevice {
+    fn format(&self) -> AudioFormat {
+        AudioFormat { sample_rate: self.config.sample_rate().0, channels: self.config.channels() }
+    }
+}
+
+// Sound cards through cpal, in whatever sample format they prefer
+pub struct CpalBackend {
+    input: Option<OpenDevice>,
+    output: Option<OpenDevice>,
+    // Output buffer length at 48 kHz, scaled to the device rate
+    period: usize,
+}
+
+impl CpalBackend {
+    // A default device that doesn't exist is only an error once a stream
+    // is started on it, so an output-only server runs without a microphone.
+    // A device asked for by name or index has to exist.
+    pub fn open(host: &Host, input: &DeviceSelector, output: &DeviceSelector, period: usize) -> Result<Self, DeviceError> {
+        Ok(Self {
+            input: open_device(host, Direction::Input, input)?,
+            output: open_device(host, Direction::Output, output)?,
+            period,
+        })
+    }
+
+    fn device(&self, direction: Direction) -> Result<&OpenDevice, BackendError> {
+        let device = match direction {
+            Direction::Input => self.input.as_ref(),
+            Direction::Output => self.output.as_ref(),
+        };
+        device.ok_or_else(|| DeviceError::NoDevice(format!("default {} device", direction)).into())
+    }
+}
+
+fn open_device(host: &Host, direction: Direction, selector: &DeviceSelector) -> Result<Option<OpenDevice>, DeviceError> {
+    let device = match devices::select_device(host, direction, selector) {
+        Ok(device) => device,
+        Err(DeviceError::NoDevice(e)) if *selector == DeviceSelector::Default => {
+            warn!("BACKEND: No {}", e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    let config = match direction {
+        Direction::Input => device.default_input_config(),
+        Direction::Output => device.default_output_config(),
+    }
+    .map_err(|e| DeviceError::Backend(e.to_string()))?;
+    debug!("BACKEND: {} device {} at {} {}", direction, device.name().unwrap_or_default(), config.sample_rate().0, config.sample_format());
+    Ok(Some(OpenDevice { device, config }))
+}
+
+impl AudioBackend for CpalBackend {
+    fn name(&self) -> &'static str {
+        "cpal"
+    }
+    fn input_format(&self) -> AudioFormat {
+        self.input.as_ref().map(OpenDevice::format).unwrap_or(DEFAULT_FORMAT)
+    }
+    fn output_format(&self) -> AudioFormat {
+        self.output.as_ref().map(OpenDevice::format).unwrap_or(DEFAULT_FORMAT)
+    }
+
+    // The host picks the input buffer size, capture is chunked into encoder
+    // frames further down
+    fn start_input(&self, handler: InputHandler) -> Result<AudioStream, BackendError> {
+        let input = self.device(Direction::Input)?;
+        let config = cpal::StreamConfig {
+            channels: input.config.channels(),
+            sample_rate: input.config.sample_rate(),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let stream = sample::build_input_stream(&input.device, &config, input.config.sample_format(), handler)
+            .map_err(|e| BackendError::Stream(e.to_string()))?;
+        stream.play().map_err(|e| BackendError::Stream(e.to_string()))?;
+        Ok(AudioStream::new(stream))
+    }
+
+    // Output buffers last as long as one codec frame where the device
+    // allows it
+    fn start_output(&self, fill: OutputFiller) -> Result<AudioStream, BackendError> {
+        let output = self.device(Direction::Output)?;
+        let sample_rate = output.config.sample_rate();
+        let frames = self.period as u64 * sample_rate.0 as u64 / DEFAULT_FORMAT.sample_rate as u64;
+        let mut config = cpal::StreamConfig {
+            channels: output.config.channels(),
+            sample_rate,
+            buffer_size: cpal::BufferSize::Fixed(frames as u32),
+        };
+        if let cpal::SupportedBufferSize::Range { min, max } = output.config.buffer_size() {
+            if !(*min as u64..=*max as u64).contains(&frames) {
+                debug!("BACKEND: {} frame buffers unsupported, using the device default", frames);
+                config.buffer_size = cpal::BufferSize::Default;
+            }
+        }
+        let stream = sample::build_output_stream(&output.device, &config, output.config.sample_format(), fill)
+            .map_err(|e| BackendError::Stream(e.to_string()))?;
+        stream.play().map_err(|e| BackendError::Stream(e.to_string()))?;
+        Ok(AudioStream::new(stream))
+    }
+}
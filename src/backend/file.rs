@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use crate::backend::{clocked, AudioBackend, AudioFormat, AudioStream, BackendError, InputHandler, OutputFiller, DEFAULT_FORMAT};
+
+// Captures from a WAV file and records playback into another, in real time
+// or `speed` times faster. Once the input file runs out it captures silence.
+// Either side may be left out, it then behaves like the null backend.
+pub struct FileBackend {
+    input: Option<(PathBuf, AudioFormat)>,
+    output: Option<PathBuf>,
+    output_format: AudioFormat,
+    speed: f32,
+}
+
+impl FileBackend {
+    // The input format is whatever the file has; it's read here so a
+    // missing or broken file is reported up front
+    pub fn new(input: Option<&Path>, output: Option<&Path>, output_format: AudioFormat, speed: f32) -> Result<Self, BackendError> {
+        let input = match input {
+            Some(path) => {
+                let reader = hound::WavReader::open(path).map_err(|e| BackendError::File(path.to_path_buf(), e))?;
+                let spec = reader.spec();
+                Some((path.to_path_buf(), AudioFormat { sample_rate: spec.sample_rate, channels: spec.channels }))
+            }
+            None => None,
+        };
+        Ok(Self {
+            input,
+            output: output.map(Path::to_path_buf),
+            output_format,
+            speed,
+        })
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+    fn input_format(&self) -> AudioFormat {
+        self.input.as_ref().map(|(_, format)| *format).unwrap_or(DEFAULT_FORMAT)
+    }
+    fn output_format(&self) -> AudioFormat {
+        self.output_format
+    }
+
+    fn start_input(&self, mut handler: InputHandler) -> Result<AudioStream, BackendError> {
+        let format = self.input_format();
+        let samples = match &self.input {
+            Some((path, _)) => {
+                info!("BACKEND: Capturing from {}", path.display());
+                read_wav(path)?.1
+            }
+            None => Vec::new(),
+        };
+        let channels = format.channels as usize;
+        let mut position = 0;
+        let mut block = Vec::new();
+        clocked("file input", format, self.speed, move |frames| {
+            block.clear();
+            let end = (position + frames * channels).min(samples.len());
+            block.extend_from_slice(&samples[position..end]);
+            block.resize(frames * channels, 0.0);
+            position = end;
+            handler(&block);
+        })
+    }
+
+    fn start_output(&self, mut fill: OutputFiller) -> Result<AudioStream, BackendError> {
+        let format = self.output_format;
+        let mut writer = match &self.output {
+            Some(path) => {
+                info!("BACKEND: Recording to {}", path.display());
+                Some(create_wav(path, format)?)
+            }
+            None => None,
+        };
+        let channels = format.channels as usize;
+        let mut block = Vec::new();
+        // The writer finalizes the file when the clock stops and drops it
+        clocked("file output", format, self.speed, move |frames| {
+            block.clear();
+            block.resize(frames * channels, 0.0);
+            fill(&mut block);
+            if let Some(recording) = writer.as_mut() {
+                if let Err(e) = block.iter().try_for_each(|sample| recording.write_sample(*sample)) {
+                    warn!("BACKEND: Stopped recording: {}", e);
+                    writer = None;
+                }
+            }
+        })
+    }
+}
+
+// A whole WAV file as interleaved f32, with its format
+pub fn read_wav(path: &Path) -> Result<(AudioFormat, Vec<f32>), BackendError> {
+    let error = |e| BackendError::File(path.to_path_buf(), e);
+    let mut reader = hound::WavReader::open(path).map_err(error)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>().map_err(error)?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()
+                .map_err(error)?
+        }
+    };
+    Ok((AudioFormat { sample_rate: spec.sample_rate, channels: spec.channels }, samples))
+}
+
+fn create_wav(path: &Path, format: AudioFormat) -> Result<hound::WavWriter<BufWriter<File>>, BackendError> {
+    let spec = hound::WavSpec {
+        channels: format.channels,
+        sample_rate: format.sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    hound::WavWriter::create(path, spec).map_err(|e| BackendError::File(path.to_path_buf(), e))
+}
@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::backend::{clocked, AudioBackend, AudioFormat, AudioStream, BackendError, InputHandler, OutputFiller};
+
+// Audio kept in memory, with the moment its clock started so both ends of
+// a pipeline can be lined up in time
+#[derive(Debug, Clone, Default)]
+pub struct Tape {
+    pub started: Option<Instant>,
+    pub samples: Vec<f32>,
+}
+
+// Captures from a buffer and records playback into another, in real time.
+// For driving the pipelines from code; once the input runs out it captures
+// silence.
+pub struct MemoryBackend {
+    format: AudioFormat,
+    capture: Arc<Mutex<Tape>>,
+    playback: Arc<Mutex<Tape>>,
+}
+
+impl MemoryBackend {
+    pub fn new(format: AudioFormat, input: Vec<f32>) -> Self {
+        Self {
+            format,
+            capture: Arc::new(Mutex::new(Tape { started: None, samples: input })),
+            playback: Arc::new(Mutex::new(Tape::default())),
+        }
+    }
+    // When capture started, if it has
+    pub fn capture_started(&self) -> Option<Instant> {
+        self.capture.lock().unwrap().started
+    }
+    // Everything played so far
+    pub fn played(&self) -> Tape {
+        self.playback.lock().unwrap().clone()
+    }
+}
+
+impl AudioBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    fn input_format(&self) -> AudioFormat {
+        self.format
+    }
+    fn output_format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn start_input(&self, mut handler: InputHandler) -> Result<AudioStream, BackendError> {
+        let channels = self.format.channels as usize;
+        let capture = Arc::clone(&self.capture);
+        let mut position = 0;
+        let mut block = Vec::new();
+        clocked("memory input", self.format, 1.0, move |frames| {
+            {
+                let mut tape = capture.lock().unwrap();
+                tape.started.get_or_insert_with(Instant::now);
+                let end = (position + frames * channels).min(tape.samples.len());
+                block.clear();
+                block.extend_from_slice(&tape.samples[position.min(end)..end]);
+                position = end;
+            }
+            block.resize(frames * channels, 0.0);
+            handler(&block);
+        })
+    }
+
+    fn start_output(&self, mut fill: OutputFiller) -> Result<AudioStream, BackendError> {
+        let channels = self.format.channels as usize;
+        let playback = Arc::clone(&self.playback);
+        let mut block = Vec::new();
+        clocked("memory output", self.format, 1.0, move |frames| {
+            let now = Instant::now();
+            block.clear();
+            block.resize(frames * channels, 0.0);
+            fill(&mut block);
+            let mut tape = playback.lock().unwrap();
+            tape.started.get_or_insert(now);
+            tape.samples.extend_from_slice(&block);
+        })
+    }
+}
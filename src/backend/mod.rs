@@ -0,0 +1,167 @@
+pub mod cpal_backend;
+pub mod null;
+pub mod file;
+pub mod memory;
+
+use std::any::Any;
+use std::fmt;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use log::debug;
+use crate::devices::DeviceError;
+use crate::session::StopFlag;
+
+pub use cpal_backend::CpalBackend;
+pub use null::NullBackend;
+pub use file::FileBackend;
+pub use memory::MemoryBackend;
+
+// Where audio comes from and goes to. The cpal backend drives real sound
+// cards; the null, file and memory backends run on a clock of their own, so
+// the client and server also work on machines without any audio hardware.
+//
+// Audio crosses the trait as interleaved f32 in the backend's own format.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz, {} ch", self.sample_rate, self.channels)
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Device(DeviceError),
+    Stream(String),
+    File(PathBuf, hound::Error),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Device(e) => write!(f, "{}", e),
+            BackendError::Stream(e) => write!(f, "Audio stream error: {}", e),
+            BackendError::File(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Error for BackendError {}
+
+impl From<DeviceError> for BackendError {
+    fn from(e: DeviceError) -> Self {
+        BackendError::Device(e)
+    }
+}
+
+// Called with every block of captured samples
+pub type InputHandler = Box<dyn FnMut(&[f32]) + Send>;
+// Called to fill every block of samples to play
+pub type OutputFiller = Box<dyn FnMut(&mut [f32]) + Send>;
+
+// A running input or output. It stops when dropped, so keep it alive for as
+// long as it should run, e.g. with Session::hold.
+pub struct AudioStream {
+    _inner: Box<dyn Any>,
+}
+
+impl AudioStream {
+    pub fn new<T: 'static>(inner: T) -> Self {
+        Self { _inner: Box::new(inner) }
+    }
+}
+
+pub trait AudioBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn input_format(&self) -> AudioFormat;
+    fn output_format(&self) -> AudioFormat;
+    fn start_input(&self, handler: InputHandler) -> Result<AudioStream, BackendError>;
+    fn start_output(&self, fill: OutputFiller) -> Result<AudioStream, BackendError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackendKind {
+    #[default]
+    Cpal,
+    Null,
+    File,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpal" => Ok(BackendKind::Cpal),
+            "null" => Ok(BackendKind::Null),
+            "file" => Ok(BackendKind::File),
+            other => Err(format!("Unknown audio backend '{}', expected cpal, null or file", other)),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendKind::Cpal => write!(f, "cpal"),
+            BackendKind::Null => write!(f, "null"),
+            BackendKind::File => write!(f, "file"),
+        }
+    }
+}
+
+// Format of the null backend and of the file backend's missing sides
+pub const DEFAULT_FORMAT: AudioFormat = AudioFormat { sample_rate: 48000, channels: 2 };
+
+// Clocked backends hand out audio in blocks this long
+const PERIOD: Duration = Duration::from_millis(10);
+
+// A thread calling `tick` once per PERIOD with the number of frames due,
+// `speed` times faster than real time. Stops when the stream is dropped.
+struct ClockedThread {
+    stop: StopFlag,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ClockedThread {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn clocked<F>(name: &str, format: AudioFormat, speed: f32, mut tick: F) -> Result<AudioStream, BackendError>
+where
+    F: FnMut(usize) + Send + 'static,
+{
+    let frames = (format.sample_rate as u64 * PERIOD.as_millis() as u64 / 1000) as usize;
+    let interval = PERIOD.div_f32(speed);
+    let stop = StopFlag::new();
+    let thread_stop = stop.clone();
+    debug!("BACKEND: Starting {} clock, {} frames every {:?}", name, frames, interval);
+    let thread = std::thread::Builder::new()
+        .name(format!("{} clock", name))
+        .spawn(move || {
+            // Scheduled against the start time so sleeps don't add up to drift
+            let mut next = Instant::now();
+            loop {
+                tick(frames);
+                next += interval;
+                if thread_stop.sleep(next.saturating_duration_since(Instant::now())) {
+                    break;
+                }
+            }
+        })
+        .map_err(|e| BackendError::Stream(e.to_string()))?;
+    Ok(AudioStream::new(ClockedThread { stop, thread: Some(thread) }))
+}
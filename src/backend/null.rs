@@ -0,0 +1,42 @@
+use crate::backend::{clocked, AudioBackend, AudioFormat, AudioStream, BackendError, InputHandler, OutputFiller};
+
+// Captures silence and throws playback away, on a real time clock
+pub struct NullBackend {
+    format: AudioFormat,
+}
+
+impl NullBackend {
+    pub fn new(format: AudioFormat) -> Self {
+        Self { format }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn name(&self) -> &'static str {
+        "null"
+    }
+    fn input_format(&self) -> AudioFormat {
+        self.format
+    }
+    fn output_format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn start_input(&self, mut handler: InputHandler) -> Result<AudioStream, BackendError> {
+        let channels = self.format.channels as usize;
+        let mut block = Vec::new();
+        clocked("null input", self.format, 1.0, move |frames| {
+            block.resize(frames * channels, 0.0);
+            handler(&block);
+        })
+    }
+
+    fn start_output(&self, mut fill: OutputFiller) -> Result<AudioStream, BackendError> {
+        let channels = self.format.channels as usize;
+        let mut block = Vec::new();
+        clocked("null output", self.format, 1.0, move |frames| {
+            block.resize(frames * channels, 0.0);
+            fill(&mut block);
+        })
+    }
+}
@@ -0,0 +1,236 @@
+// Opus carries one or two channels, devices have however many they have.
+// These maps sit between the two: InputMap turns device frames into stream
+// frames before encoding, OutputMap spreads decoded stream frames over the
+// output device's channels.
+//
+// Channels are numbered from 1 on the command line, like on the hardware,
+// and from 0 everywhere else.
+
+pub const MAX_STREAM_CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputMap {
+    // Average the device channels into a mono or stereo stream. For stereo,
+    // odd hardware inputs go left and even ones right.
+    Downmix { stream_channels: usize },
+    // Send a single device channel as mono, e.g. the boom mic on input 3
+    Pick(usize),
+}
+
+impl InputMap {
+    // Everything folded into as many stream channels as the device allows
+    pub fn downmix(device_channels: usize) -> Self {
+        InputMap::Downmix { stream_channels: device_channels.clamp(1, MAX_STREAM_CHANNELS) }
+    }
+
+    // Picks `input_channel` (counted from 1) when given, otherwise
+    // downmixes to `stream_channels`, or to as many as the device allows
+    pub fn new(input_channel: Option<usize>, stream_channels: Option<usize>, device_channels: usize) -> Result<Self, String> {
+        if let Some(channel) = input_channel {
+            return Ok(InputMap::Pick(check_channel(channel, device_channels)?));
+        }
+        match stream_channels {
+            None => Ok(Self::downmix(device_channels)),
+            Some(stream_channels) if (1..=MAX_STREAM_CHANNELS).contains(&stream_channels) => {
+                Ok(InputMap::Downmix { stream_channels })
+            }
+            Some(other) => Err(format!("{} stream channels, expected 1 or 2", other)),
+        }
+    }
+
+    pub fn stream_channels(&self) -> usize {
+        match self {
+            InputMap::Downmix { stream_channels } => *stream_channels,
+            InputMap::Pick(_) => 1,
+        }
+    }
+
+    // Maps a block of interleaved device frames to stream frames
+    pub fn apply(&self, block: &[f32], device_channels: usize) -> Vec<f32> {
+        let stream_channels = self.stream_channels();
+        if let InputMap::Downmix { .. } = self {
+            if device_channels == stream_channels {
+                return block.to_vec();
+            }
+        }
+        let mut output = Vec::with_capacity(block.len() / device_channels * stream_channels);
+        for frame in block.chunks_exact(device_channels) {
+            match self {
+                InputMap::Pick(channel) => output.push(frame[*channel]),
+                InputMap::Downmix { stream_channels: 1 } => {
+                    output.push(frame.iter().sum::<f32>() / device_channels as f32);
+                }
+                InputMap::Downmix { .. } => {
+                    // A mono device feeds both sides
+                    if device_channels == 1 {
+                        output.extend([frame[0], frame[0]]);
+                        continue;
+                    }
+                    for side in 0..MAX_STREAM_CHANNELS {
+                        let (sum, count) = frame
+                            .iter()
+                            .skip(side)
+                            .step_by(MAX_STREAM_CHANNELS)
+                            .fold((0.0, 0), |(sum, count), sample| (sum + sample, count + 1));
+                        output.push(sum / count as f32);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+impl std::fmt::Display for InputMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputMap::Downmix { stream_channels: 1 } => write!(f, "downmix to mono"),
+            InputMap::Downmix { .. } => write!(f, "downmix to stereo"),
+            InputMap::Pick(channel) => write!(f, "input {} as mono", channel + 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OutputMap {
+    // Mono goes to every device channel, stereo alternates left and right
+    #[default]
+    Spread,
+    // Only these device channels play, the rest stay silent. A stereo stream
+    // alternates left and right across them.
+    Channels(Vec<usize>),
+}
+
+impl OutputMap {
+    // Channels like "3,4", counted from 1. Without any, every channel plays.
+    pub fn parse(channels: Option<&str>, device_channels: usize) -> Result<Self, String> {
+        let Some(value) = channels else {
+            return Ok(OutputMap::Spread);
+        };
+        let channels = value
+            .split(',')
+            .map(|channel| match channel.trim().parse::<usize>() {
+                Ok(channel) => check_channel(channel, device_channels),
+                Err(_) => Err(format!("'{}' is not a channel number", channel.trim())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(OutputMap::Channels(channels))
+    }
+
+    // How many channels are worth decoding for this layout
+    pub fn stream_channels(&self, device_channels: usize) -> usize {
+        match self {
+            OutputMap::Spread => device_channels.clamp(1, MAX_STREAM_CHANNELS),
+            OutputMap::Channels(channels) => channels.len().clamp(1, MAX_STREAM_CHANNELS),
+        }
+    }
+
+    // Maps a block of interleaved stream frames to device frames
+    pub fn apply(&self, block: &[f32], stream_channels: usize, device_channels: usize) -> Vec<f32> {
+        if *self == OutputMap::Spread && stream_channels == device_channels {
+            return block.to_vec();
+        }
+        let mut output = vec![0.0; block.len() / stream_channels * device_channels];
+        for (frame, out) in block
+            .chunks_exact(stream_channels)
+            .zip(output.chunks_exact_mut(device_channels))
+        {
+            match self {
+                // Stereo on a mono device is folded down
+                OutputMap::Spread if device_channels == 1 => {
+                    out[0] = frame.iter().sum::<f32>() / stream_channels as f32;
+                }
+                OutputMap::Spread => {
+                    for (channel, sample) in out.iter_mut().enumerate() {
+                        *sample = frame[channel % stream_channels];
+                    }
+                }
+                OutputMap::Channels(channels) => {
+                    for (i, channel) in channels.iter().enumerate() {
+                        out[*channel] = frame[i % stream_channels];
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+impl std::fmt::Display for OutputMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputMap::Spread => write!(f, "all outputs"),
+            OutputMap::Channels(channels) => {
+                let channels: Vec<String> = channels.iter().map(|c| (c + 1).to_string()).collect();
+                write!(f, "outputs {}", channels.join(","))
+            }
+        }
+    }
+}
+
+// A 1-based channel number from the settings, checked against the device
+fn check_channel(channel: usize, device_channels: usize) -> Result<usize, String> {
+    if (1..=device_channels).contains(&channel) {
+        return Ok(channel - 1);
+    }
+    Err(format!("channel {} is not on the device, it has channels 1 to {}", channel, device_channels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_maps_come_from_the_settings() {
+        assert_eq!(InputMap::new(None, None, 8), Ok(InputMap::Downmix { stream_channels: 2 }));
+        assert_eq!(InputMap::new(None, None, 1), Ok(InputMap::Downmix { stream_channels: 1 }));
+        assert_eq!(InputMap::new(None, Some(1), 8), Ok(InputMap::Downmix { stream_channels: 1 }));
+        // An input channel wins over stream channels
+        assert_eq!(InputMap::new(Some(3), Some(2), 8), Ok(InputMap::Pick(2)));
+        assert!(InputMap::new(Some(9), None, 8).is_err());
+        assert!(InputMap::new(Some(0), None, 8).is_err());
+        assert!(InputMap::new(None, Some(3), 8).is_err());
+    }
+
+    #[test]
+    fn input_maps_downmix_and_pick() {
+        // Stereo into a stereo stream is left alone
+        let stereo = [0.1, 0.2, 0.3, 0.4];
+        assert_eq!(InputMap::downmix(2).apply(&stereo, 2), stereo.to_vec());
+        // Stereo into mono averages each frame
+        let mono = InputMap::Downmix { stream_channels: 1 }.apply(&[0.2, 0.4, -0.5, 0.5], 2);
+        assert_eq!(mono.len(), 2);
+        assert!((mono[0] - 0.3).abs() < 1e-6 && mono[1].abs() < 1e-6, "{:?}", mono);
+        // Four inputs into stereo: 1 and 3 left, 2 and 4 right
+        assert_eq!(InputMap::downmix(4).apply(&[1.0, 2.0, 3.0, 4.0], 4), vec![2.0, 3.0]);
+        // A mono device feeds both sides
+        assert_eq!(InputMap::Downmix { stream_channels: 2 }.apply(&[0.5, -0.25], 1), vec![0.5, 0.5, -0.25, -0.25]);
+        // One input out of four, as mono
+        assert_eq!(InputMap::Pick(2).apply(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0], 4), vec![3.0, 7.0]);
+    }
+
+    #[test]
+    fn output_maps_come_from_the_settings() {
+        assert_eq!(OutputMap::parse(None, 2), Ok(OutputMap::Spread));
+        assert_eq!(OutputMap::parse(Some("3, 4"), 4), Ok(OutputMap::Channels(vec![2, 3])));
+        assert!(OutputMap::parse(Some("5"), 4).is_err());
+        assert!(OutputMap::parse(Some("left"), 4).is_err());
+        assert_eq!(OutputMap::Spread.stream_channels(1), 1);
+        assert_eq!(OutputMap::Spread.stream_channels(8), 2);
+        assert_eq!(OutputMap::Channels(vec![4]).stream_channels(8), 1);
+    }
+
+    #[test]
+    fn output_maps_upmix_and_downmix() {
+        // Mono is duplicated onto both sides
+        assert_eq!(OutputMap::Spread.apply(&[0.1, 0.2], 1, 2), vec![0.1, 0.1, 0.2, 0.2]);
+        // Stereo on a mono device is averaged
+        assert_eq!(OutputMap::Spread.apply(&[0.25, 0.75], 2, 1), vec![0.5]);
+        // Stereo over four outputs alternates left and right
+        assert_eq!(OutputMap::Spread.apply(&[1.0, 2.0], 2, 4), vec![1.0, 2.0, 1.0, 2.0]);
+        // Stereo on outputs 3 and 4 only
+        assert_eq!(OutputMap::Channels(vec![2, 3]).apply(&[1.0, 2.0], 2, 4), vec![0.0, 0.0, 1.0, 2.0]);
+        // Mono on output 2 only
+        assert_eq!(OutputMap::Channels(vec![1]).apply(&[0.5, 0.25], 1, 2), vec![0.0, 0.5, 0.0, 0.25]);
+    }
+}
@@ -0,0 +1,63 @@
+use opus::Decoder;
+use log::debug;
+
+// Largest frame Opus can produce: 120 ms at 48 kHz
+pub const MAX_FRAME_SIZE: usize = 5760;
+
+pub fn opus_channels(channels: u16) -> opus::Channels {
+    if channels == 1 {
+        opus::Channels::Mono
+    } else {
+        opus::Channels::Stereo
+    }
+}
+
+// Decoder for a single incoming stream. Besides plain decoding it hides lost
+// frames, either by rebuilding them from the in-band FEC data carried by the
+// next frame, or with the decoder's own packet loss concealment.
+pub struct StreamDecoder {
+    decoder: Decoder,
+    channels: usize,
+    // Samples per channel of the last frame we decoded, used to size concealment
+    frame_size: usize,
+}
+
+impl StreamDecoder {
+    pub fn new(sample_rate: u32, channels: u16, frame_size: usize) -> Result<Self, opus::Error> {
+        Ok(Self {
+            decoder: Decoder::new(sample_rate, opus_channels(channels))?,
+            channels: channels.clamp(1, 2) as usize,
+            frame_size,
+        })
+    }
+
+    pub fn decode(&mut self, frame: &[u8]) -> Result<Vec<f32>, opus::Error> {
+        let mut decoded = vec![0.0; MAX_FRAME_SIZE * self.channels];
+        let len = self.decoder.decode_float(frame, &mut decoded, false)?;
+        self.frame_size = len;
+        decoded.truncate(len * self.channels);
+        Ok(decoded)
+    }
+
+    // Produces a replacement for one lost frame. When the frame that follows
+    // the gap is already at hand its FEC data is used, otherwise the decoder
+    // extrapolates from its own state (an empty packet triggers PLC).
+    pub fn conceal(&mut self, next_frame: Option<&[u8]>) -> Result<Vec<f32>, opus::Error> {
+        let mut concealed = vec![0.0; self.frame_size * self.channels];
+        let len = match next_frame {
+            Some(next) => {
+                debug!("CODEC: Recovering lost frame with in-band FEC");
+                match self.decoder.decode_float(next, &mut concealed, true) {
+                    Ok(len) => len,
+                    Err(_) => self.decoder.decode_float(&[], &mut concealed, false)?,
+                }
+            }
+            None => {
+                debug!("CODEC: Concealing lost frame with PLC");
+                self.decoder.decode_float(&[], &mut concealed, false)?
+            }
+        };
+        concealed.truncate(len * self.channels);
+        Ok(concealed)
+    }
+}
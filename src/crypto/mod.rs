@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::Sha256;
+use crate::jitter_buffer::SequenceNumber;
+use crate::protocol;
+
+// Bytes the Poly1305 tag adds to every sealed payload
+pub const TAG_SIZE: usize = 16;
+// Salt is fixed so every crew member derives the same key from the same
+// passphrase; the iteration count makes guessing it from a capture costly.
+const KEY_SALT: &[u8] = b"udp_voice crew key v1";
+const KEY_ROUNDS: u32 = 100_000;
+// Packets this far behind the newest one are rejected outright
+const REPLAY_WINDOW_SIZE: u64 = 64;
+// A sender id not heard from for this long has its window dropped
+pub const REPLAY_TIMEOUT: Duration = Duration::from_secs(300);
+// How often ReplayGuard looks for windows to drop
+const REPLAY_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+// How far apart the clocks of a sender and a server may be
+pub const CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    AuthenticationFailed,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::AuthenticationFailed => write!(f, "packet failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+// ChaCha20-Poly1305 key shared by the whole crew
+#[derive(Clone)]
+pub struct CrewKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl CrewKey {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), KEY_SALT, KEY_ROUNDS, &mut key);
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    // `header` is authenticated but not encrypted, so nobody can move a
+    // payload to a different group, kind or sequence number.
+    pub fn seal(&self, sender_id: u64, sequence_number: u32, header: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let payload = Payload { msg: plaintext, aad: header };
+        self.cipher
+            .encrypt(&nonce(sender_id, sequence_number), payload)
+            .expect("ChaCha20-Poly1305 encryption failed")
+    }
+
+    pub fn open(
+        &self,
+        sender_id: u64,
+        sequence_number: u32,
+        header: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let payload = Payload { msg: ciphertext, aad: header };
+        self.cipher
+            .decrypt(&nonce(sender_id, sequence_number), payload)
+            .map_err(|_| CryptoError::AuthenticationFailed)
+    }
+}
+
+// Sender id followed by the sequence number. Senders pick a fresh random id
+// whenever their sequence numbers restart, so a nonce is never reused.
+fn nonce(sender_id: u64, sequence_number: u32) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&sender_id.to_be_bytes());
+    nonce[8..].copy_from_slice(&sequence_number.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+// Sliding window over the last 64 sequence numbers of one sender, as in
+// IPsec (RFC 4303). Only feed it packets that passed authentication.
+// Sequence numbers compare wrap-safely, so the window slides on past
+// u32::MAX.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    highest: Option<u32>,
+    // Bit n set means `highest - n` has been seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false if the sequence number was already seen or is too old
+    pub fn accept(&mut self, sequence_number: u32) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence_number);
+            self.seen = 1;
+            return true;
+        };
+        let ahead = sequence_number.distance(highest);
+        if ahead > 0 {
+            let shift = ahead as u64;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = Some(sequence_number);
+            return true;
+        }
+        let offset = ahead.unsigned_abs();
+        if offset >= REPLAY_WINDOW_SIZE || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+// Replay windows by sender id. Senders pick a new id every time they start
+// sending, so ids nobody has used for `timeout` are dropped rather than kept
+// forever. That is safe because packets are also judged by their
+// authenticated timestamp: one stamped more than `timeout` less CLOCK_SKEW
+// ago is rejected, so a capture is stale before its window goes.
+#[derive(Debug, Clone)]
+pub struct ReplayGuard {
+    windows: HashMap<u64, (ReplayWindow, Instant)>,
+    timeout: Duration,
+    last_sweep: Instant,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(REPLAY_TIMEOUT)
+    }
+}
+
+impl ReplayGuard {
+    pub fn new(timeout: Duration) -> Self {
+        Self { windows: HashMap::new(), timeout, last_sweep: Instant::now() }
+    }
+
+    // `sent` is the packet's timestamp, in ms since the Unix epoch
+    pub fn accept(&mut self, sender_id: u64, sequence_number: u32, sent: u128) -> bool {
+        self.accept_at(sender_id, sequence_number, sent, Instant::now(), protocol::current_time_in_ms())
+    }
+
+    // `accept` as if the packet arrived at `now`, `now_ms` on the wall clock
+    pub fn accept_at(&mut self, sender_id: u64, sequence_number: u32, sent: u128, now: Instant, now_ms: u128) -> bool {
+        let max_age = self.timeout.saturating_sub(CLOCK_SKEW).as_millis();
+        if sent.saturating_add(max_age) < now_ms || sent > now_ms + CLOCK_SKEW.as_millis() {
+            return false;
+        }
+        if now.saturating_duration_since(self.last_sweep) >= REPLAY_SWEEP_INTERVAL.min(self.timeout) {
+            let timeout = self.timeout;
+            self.windows.retain(|_, (_, last_heard)| now.saturating_duration_since(*last_heard) < timeout);
+            self.last_sweep = now;
+        }
+        let (window, last_heard) = self.windows.entry(sender_id).or_insert_with(|| (ReplayWindow::new(), now));
+        *last_heard = now;
+        window.accept(sequence_number)
+    }
+
+    // Sender ids with a window
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window_slides_across_the_wrap() {
+        let mut window = ReplayWindow::new();
+        let start = u32::MAX - 4;
+        for i in 0..10 {
+            assert!(window.accept(start.wrapping_add(i)), "Rejected {}", start.wrapping_add(i));
+        }
+        // Everything around the wrap is now a replay
+        assert!(!window.accept(u32::MAX));
+        assert!(!window.accept(0));
+        assert!(!window.accept(start));
+        // A late packet from before the wrap that never arrived still counts
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(u32::MAX - 1));
+        assert!(window.accept(2));
+        assert!(window.accept(u32::MAX));
+        assert!(!window.accept(u32::MAX));
+    }
+
+    #[test]
+    fn replay_window_rejects_what_fell_out_of_it() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(10));
+        assert!(window.accept(10 + REPLAY_WINDOW_SIZE as u32));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+    }
+
+    // A packet sent and received `after` into the test, both clocks agreeing
+    fn fresh(guard: &mut ReplayGuard, sender_id: u64, sequence_number: u32, after: Duration) -> bool {
+        let (start, start_ms) = clocks();
+        let sent = start_ms + after.as_millis();
+        guard.accept_at(sender_id, sequence_number, sent, start + after, sent)
+    }
+
+    // Both clocks at the start of a test, fixed so every call agrees
+    fn clocks() -> (Instant, u128) {
+        thread_local! {
+            static START: (Instant, u128) = (Instant::now(), protocol::current_time_in_ms());
+        }
+        START.with(|start| *start)
+    }
+
+    #[test]
+    fn replay_guard_forgets_quiet_senders() {
+        let timeout = Duration::from_secs(60);
+        let mut guard = ReplayGuard::new(timeout);
+        assert!(fresh(&mut guard, 1, 5, Duration::ZERO));
+        assert!(fresh(&mut guard, 2, 5, timeout / 2));
+        assert!(!fresh(&mut guard, 1, 5, timeout / 2));
+        assert_eq!(guard.len(), 2);
+
+        // Sender 1 was last heard at timeout / 2 and is gone a timeout later,
+        // sender 2 keeps talking and keeps its window
+        assert!(fresh(&mut guard, 2, 6, timeout * 3 / 2));
+        assert_eq!(guard.len(), 1);
+        assert!(!fresh(&mut guard, 2, 6, timeout * 2));
+        assert!(fresh(&mut guard, 1, 5, timeout * 2));
+    }
+
+    #[test]
+    fn replay_guard_rejects_a_capture_replayed_after_the_sweep() {
+        let mut guard = ReplayGuard::default();
+        let (start, start_ms) = clocks();
+        assert!(guard.accept_at(1, 5, start_ms, start, start_ms));
+
+        // Another sender keeps the guard sweeping; sender 1's window is gone
+        let later = REPLAY_TIMEOUT + REPLAY_SWEEP_INTERVAL;
+        assert!(fresh(&mut guard, 2, 0, later));
+        assert_eq!(guard.len(), 1);
+
+        // The capture still carries its original, authenticated timestamp
+        let now_ms = start_ms + later.as_millis();
+        assert!(!guard.accept_at(1, 5, start_ms, start + later, now_ms));
+        assert!(!guard.accept_at(1, 6, start_ms, start + later, now_ms));
+        assert_eq!(guard.len(), 1);
+    }
+
+    #[test]
+    fn replay_guard_allows_for_clock_skew() {
+        let mut guard = ReplayGuard::default();
+        let (start, start_ms) = clocks();
+        let skew = CLOCK_SKEW.as_millis();
+        // A sender whose clock runs ahead or behind by up to CLOCK_SKEW
+        assert!(guard.accept_at(1, 0, start_ms + skew, start, start_ms));
+        assert!(guard.accept_at(2, 0, start_ms - skew, start, start_ms));
+        assert!(!guard.accept_at(3, 0, start_ms + skew + 1, start, start_ms));
+        // Too old, even for a sender never heard from
+        let max_age = (REPLAY_TIMEOUT - CLOCK_SKEW).as_millis();
+        assert!(guard.accept_at(4, 0, start_ms - max_age, start, start_ms));
+        assert!(!guard.accept_at(5, 0, start_ms - max_age - 1, start, start_ms));
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{Device, Host, SupportedStreamConfigRange};
+use log::debug;
+
+// Listing and picking audio hosts and devices. Devices are picked by their
+// exact name or by their index in the host's input or output list, as shown
+// by `--list-devices`.
+
+#[derive(Debug)]
+pub enum DeviceError {
+    NoHost(String),
+    NoDevice(String),
+    Backend(String),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::NoHost(name) => write!(f, "No audio host named '{}'", name),
+            DeviceError::NoDevice(device) => write!(f, "No {}", device),
+            DeviceError::Backend(e) => write!(f, "Audio backend error: {}", e),
+        }
+    }
+}
+
+impl Error for DeviceError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+        }
+    }
+}
+
+// Which device to use. Parses a bare number as an index, anything else as
+// a name.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DeviceSelector {
+    #[default]
+    Default,
+    Name(String),
+    Index(usize),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Empty device name".to_string());
+        }
+        Ok(match s.parse::<usize>() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) if s == "default" => DeviceSelector::Default,
+            Err(_) => DeviceSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Default => write!(f, "default"),
+            DeviceSelector::Name(name) => write!(f, "'{}'", name),
+            DeviceSelector::Index(index) => write!(f, "#{}", index),
+        }
+    }
+}
+
+// A device as listed, with everything it says it supports
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<SupportedStreamConfigRange>,
+}
+
+// Names of the hosts compiled in and available, e.g. ALSA and JACK
+pub fn host_names() -> Vec<String> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| id.name().to_string())
+        .collect()
+}
+
+// The named host, case-insensitively, or the platform default
+pub fn select_host(name: Option<&str>) -> Result<Host, DeviceError> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| DeviceError::NoHost(name.to_string()))?;
+    cpal::host_from_id(id).map_err(|e| DeviceError::Backend(e.to_string()))
+}
+
+fn devices(host: &Host, direction: Direction) -> Result<Vec<Device>, DeviceError> {
+    let devices = match direction {
+        Direction::Input => host.input_devices(),
+        Direction::Output => host.output_devices(),
+    }
+    .map_err(|e| DeviceError::Backend(e.to_string()))?;
+    Ok(devices.collect())
+}
+
+fn default_device(host: &Host, direction: Direction) -> Option<Device> {
+    match direction {
+        Direction::Input => host.default_input_device(),
+        Direction::Output => host.default_output_device(),
+    }
+}
+
+pub fn list_devices(host: &Host, direction: Direction) -> Result<Vec<DeviceInfo>, DeviceError> {
+    let default_name = default_device(host, direction).and_then(|device| device.name().ok());
+    let mut infos = Vec::new();
+    for (index, device) in devices(host, direction)?.into_iter().enumerate() {
+        let name = device.name().unwrap_or_else(|_| "<unnamed>".to_string());
+        // A device that can't report its configs is still worth listing
+        let configs: Vec<SupportedStreamConfigRange> = match direction {
+            Direction::Input => device.supported_input_configs().map(|c| c.collect()),
+            Direction::Output => device.supported_output_configs().map(|c| c.collect()),
+        }
+        .unwrap_or_default();
+        infos.push(DeviceInfo {
+            index,
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            name,
+            configs,
+        });
+    }
+    Ok(infos)
+}
+
+// Finds a device, returning an error rather than panicking when it isn't
+// there, as on machines without sound hardware
+pub fn select_device(host: &Host, direction: Direction, selector: &DeviceSelector) -> Result<Device, DeviceError> {
+    debug!("DEVICES: Selecting {} {} device", selector, direction);
+    match selector {
+        DeviceSelector::Default => default_device(host, direction)
+            .ok_or_else(|| DeviceError::NoDevice(format!("default {} device", direction))),
+        DeviceSelector::Name(name) => devices(host, direction)?
+            .into_iter()
+            .find(|device| device.name().map(|n| n == *name).unwrap_or(false))
+            .ok_or_else(|| DeviceError::NoDevice(format!("{} device named '{}'", direction, name))),
+        DeviceSelector::Index(index) => devices(host, direction)?
+            .into_iter()
+            .nth(*index)
+            .ok_or_else(|| DeviceError::NoDevice(format!("{} device #{}", direction, index))),
+    }
+}
+
+// True when the binary was started with `--list-devices`
+pub fn list_requested() -> bool {
+    std::env::args().any(|arg| arg == "--list-devices")
+}
+
+// Prints every host with its input and output devices and their configs
+pub fn print_devices() {
+    let default_host = cpal::default_host().id();
+    for id in cpal::available_hosts() {
+        let marker = if id == default_host { " (default)" } else { "" };
+        println!("Host {}{}", id.name(), marker);
+        let host = match cpal::host_from_id(id) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("  unavailable: {}", e);
+                continue;
+            }
+        };
+        for direction in [Direction::Input, Direction::Output] {
+            println!("  {} devices:", direction);
+            match list_devices(&host, direction) {
+                Ok(infos) if infos.is_empty() => println!("    none"),
+                Ok(infos) => infos.iter().for_each(print_device),
+                Err(e) => println!("    {}", e),
+            }
+        }
+    }
+}
+
+fn print_device(info: &DeviceInfo) {
+    let marker = if info.is_default { " (default)" } else { "" };
+    println!("    {}: {}{}", info.index, info.name, marker);
+    for config in &info.configs {
+        println!("       {} ch, {}-{} Hz, {}",
+            config.channels(),
+            config.min_sample_rate().0,
+            config.max_sample_rate().0,
+            config.sample_format());
+    }
+}
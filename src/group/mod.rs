@@ -0,0 +1,137 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+// The channel everyone is in unless they say otherwise, so peers that don't
+// know about groups still hear each other.
+pub const ALL: &str = "all";
+// mDNS TXT key listing a peer's groups, comma separated
+pub const GROUPS_PROPERTY: &str = "groups";
+const MAX_NAME_LENGTH: usize = 32;
+
+// A named channel such as "camera", "sound" or "production", like a radio
+// channel. Names are lowercase ASCII letters, digits, '-' and '_'.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TalkGroup(String);
+
+impl TalkGroup {
+    pub fn all() -> Self {
+        TalkGroup(ALL.to_string())
+    }
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+    // What goes on the wire. The `all` group is 0; every other name is
+    // hashed with FNV-1a so the header stays a fixed size.
+    pub fn id(&self) -> u32 {
+        group_id(&self.0)
+    }
+}
+
+impl Default for TalkGroup {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl fmt::Display for TalkGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for TalkGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        if name.is_empty() {
+            return Err("Missing group name".to_string());
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(format!("Group name '{}' is longer than {} characters", name, MAX_NAME_LENGTH));
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Invalid group name '{}', use letters, digits, '-' and '_'", name));
+        }
+        Ok(TalkGroup(name))
+    }
+}
+
+pub fn group_id(name: &str) -> u32 {
+    if name == ALL {
+        return 0;
+    }
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in name.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    // Keep 0 for `all`
+    hash.max(1)
+}
+
+// Parses the value of the `groups` TXT property or setting. Peers that don't
+// advertise one are treated as members of `all` only.
+pub fn parse_groups(value: Option<&str>) -> BTreeSet<TalkGroup> {
+    let groups: BTreeSet<TalkGroup> = value
+        .unwrap_or(ALL)
+        .split(',')
+        .filter_map(|name| name.parse().ok())
+        .collect();
+    if groups.is_empty() {
+        BTreeSet::from([TalkGroup::all()])
+    } else {
+        groups
+    }
+}
+
+pub fn format_groups(groups: &BTreeSet<TalkGroup>) -> String {
+    groups.iter().map(TalkGroup::name).collect::<Vec<_>>().join(",")
+}
+
+// The groups a client listens to and the one it currently talks on
+#[derive(Debug, Clone)]
+pub struct Membership {
+    joined: BTreeSet<TalkGroup>,
+    talk_group: TalkGroup,
+}
+
+impl Membership {
+    pub fn new() -> Self {
+        Self {
+            joined: BTreeSet::from([TalkGroup::all()]),
+            talk_group: TalkGroup::all(),
+        }
+    }
+    pub fn joined(&self) -> &BTreeSet<TalkGroup> {
+        &self.joined
+    }
+    pub fn talk_group(&self) -> &TalkGroup {
+        &self.talk_group
+    }
+    // Returns false if already a member
+    pub fn join(&mut self, group: TalkGroup) -> bool {
+        self.joined.insert(group)
+    }
+    pub fn leave(&mut self, group: &TalkGroup) -> Result<(), String> {
+        if group == &self.talk_group {
+            return Err(format!("Currently talking on '{}', switch groups before leaving it", group));
+        }
+        if !self.joined.remove(group) {
+            return Err(format!("Not a member of '{}'", group));
+        }
+        Ok(())
+    }
+    // Talking on a group implies listening to it
+    pub fn talk_on(&mut self, group: TalkGroup) {
+        self.joined.insert(group.clone());
+        self.talk_group = group;
+    }
+}
+
+impl Default for Membership {
+    fn default() -> Self {
+        Self::new()
+    }
+}
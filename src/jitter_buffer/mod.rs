@@ -0,0 +1,332 @@
+pub mod sequence;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use log::debug;
+
+pub use sequence::SequenceNumber;
+
+// Smoothing factor for the inter-arrival jitter estimate (RFC 3550, 6.4.1)
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+// How many jitter deviations of headroom the target delay keeps
+const JITTER_HEADROOM: f64 = 4.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout<T = Vec<u8>> {
+    // The next packet in sequence, ready to be decoded
+    Frame(T),
+    // The packet with this sequence number never arrived in time. The packet
+    // that follows it is handed along when already buffered, so the decoder
+    // can rebuild the gap from its forward error correction data.
+    Lost { sequence_number: u32, next: Option<T> },
+    // The buffer ran dry, playback pauses until the target delay is refilled
+    Underrun,
+    // Still filling up to the target delay, nothing to play yet
+    Buffering,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JitterStats {
+    pub played: u64,
+    pub lost: u64,
+    pub late: u64,
+    pub duplicates: u64,
+    pub underruns: u64,
+    pub overruns: u64,
+}
+
+// Holds packets of type `T`, ordered by sequence number, until their playout time.
+//
+// Sequence numbers may wrap. Each one is placed on a 64-bit count that never
+// does, next to the highest seen so far, and the buffer works on that count.
+pub struct JitterBuffer<T = Vec<u8>> {
+    packets: BTreeMap<u64, T>,
+    frame_duration_ms: f64,
+    min_delay_ms: f64,
+    max_delay_ms: f64,
+    target_delay_ms: f64,
+    jitter_ms: f64,
+    last_arrival: Option<(u64, Instant)>,
+    next_sequence: Option<u64>,
+    highest_sequence: Option<u64>,
+    buffering: bool,
+    stats: JitterStats,
+}
+
+impl<T: Clone> JitterBuffer<T> {
+    pub fn new(frame_duration: Duration) -> Self {
+        let frame_duration_ms = frame_duration.as_secs_f64() * 1000.0;
+        Self {
+            packets: BTreeMap::new(),
+            frame_duration_ms,
+            min_delay_ms: frame_duration_ms,
+            max_delay_ms: frame_duration_ms * 25.0,
+            target_delay_ms: frame_duration_ms * 2.0,
+            jitter_ms: 0.0,
+            last_arrival: None,
+            next_sequence: None,
+            highest_sequence: None,
+            buffering: true,
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn with_delay_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay_ms = min.as_secs_f64() * 1000.0;
+        self.max_delay_ms = (max.as_secs_f64() * 1000.0).max(self.min_delay_ms);
+        self.target_delay_ms = self.target_delay_ms.clamp(self.min_delay_ms, self.max_delay_ms);
+        self
+    }
+
+    pub fn push(&mut self, sequence_number: u32, payload: T) {
+        self.push_at(sequence_number, payload, Instant::now());
+    }
+
+    pub fn push_at(&mut self, sequence_number: u32, payload: T, arrival: Instant) {
+        let sequence = self.extend(sequence_number);
+        self.update_jitter(sequence, arrival);
+
+        if let Some(next) = self.next_sequence {
+            if sequence < next {
+                debug!("JITTER: Dropping late packet {} (expecting {})", sequence_number, next as u32);
+                self.stats.late += 1;
+                return;
+            }
+        }
+        if self.packets.contains_key(&sequence) {
+            self.stats.duplicates += 1;
+            return;
+        }
+        self.packets.insert(sequence, payload);
+
+        // Never hold more than the maximum delay; the oldest audio goes first.
+        while self.buffered_ms() > self.max_delay_ms {
+            if let Some((dropped, _)) = self.packets.pop_first() {
+                debug!("JITTER: Overrun, dropping packet {}", dropped as u32);
+                self.stats.overruns += 1;
+                self.next_sequence = Some(dropped + 1);
+            }
+        }
+    }
+
+    // Called once per frame duration by the playout clock.
+    pub fn pop(&mut self) -> Playout<T> {
+        if self.buffering {
+            if self.packets.is_empty() || self.buffered_ms() < self.target_delay_ms {
+                return Playout::Buffering;
+            }
+            self.buffering = false;
+            // Resume from the oldest packet we hold, anything before it is gone.
+            let first = *self.packets.keys().next().unwrap();
+            self.next_sequence = Some(match self.next_sequence {
+                Some(next) if next > first => next,
+                _ => first,
+            });
+        }
+
+        if self.packets.is_empty() {
+            self.stats.underruns += 1;
+            self.buffering = true;
+            debug!("JITTER: Underrun, target delay {:.1} ms", self.target_delay_ms);
+            return Playout::Underrun;
+        }
+
+        let next = self.next_sequence.unwrap();
+        self.next_sequence = Some(next + 1);
+        match self.packets.remove(&next) {
+            Some(payload) => {
+                self.stats.played += 1;
+                Playout::Frame(payload)
+            }
+            None => {
+                self.stats.lost += 1;
+                Playout::Lost {
+                    sequence_number: next as u32,
+                    next: self.packets.get(&(next + 1)).cloned(),
+                }
+            }
+        }
+    }
+
+    // The sender stopped talking on purpose. Playback goes back to buffering
+    // for the next talkspurt without counting the silence as an underrun.
+    // The gap until the next talkspurt says nothing about network jitter,
+    // so the arrival history is dropped too.
+    pub fn end_talkspurt(&mut self) {
+        self.buffering = true;
+        self.last_arrival = None;
+    }
+
+    // Where a wire sequence number falls on the buffer's count: the nearest
+    // value to the highest seen that it could stand for. The count starts
+    // one wrap in, so packets from before the first one still fit.
+    fn extend(&mut self, sequence_number: u32) -> u64 {
+        let sequence = match self.highest_sequence {
+            Some(highest) => (highest as i64 + sequence_number.distance(highest as u32)) as u64,
+            None => (1 << 32) + sequence_number as u64,
+        };
+        if self.highest_sequence < Some(sequence) {
+            self.highest_sequence = Some(sequence);
+        }
+        sequence
+    }
+
+    fn update_jitter(&mut self, sequence: u64, arrival: Instant) {
+        if let Some((last_sequence, last_arrival)) = self.last_arrival {
+            let expected_ms = (sequence as f64 - last_sequence as f64) * self.frame_duration_ms;
+            let actual_ms = if arrival >= last_arrival {
+                arrival.duration_since(last_arrival).as_secs_f64() * 1000.0
+            } else {
+                -(last_arrival.duration_since(arrival).as_secs_f64() * 1000.0)
+            };
+            let deviation = (actual_ms - expected_ms).abs();
+            self.jitter_ms += (deviation - self.jitter_ms) * JITTER_GAIN;
+            self.target_delay_ms = (self.frame_duration_ms + JITTER_HEADROOM * self.jitter_ms)
+                .clamp(self.min_delay_ms, self.max_delay_ms);
+        }
+        self.last_arrival = Some((sequence, arrival));
+    }
+
+    fn buffered_ms(&self) -> f64 {
+        self.packets.len() as f64 * self.frame_duration_ms
+    }
+
+    pub fn target_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.target_delay_ms / 1000.0)
+    }
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter_ms / 1000.0)
+    }
+    pub fn buffered(&self) -> Duration {
+        Duration::from_secs_f64(self.buffered_ms() / 1000.0)
+    }
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(20);
+
+    // Pushes `sequence_number`, carrying itself, arriving `after` the start
+    fn push(buffer: &mut JitterBuffer<u32>, sequence_number: u32, start: Instant, after: Duration) {
+        buffer.push_at(sequence_number, sequence_number, start + after);
+    }
+
+    fn close_to(actual: Duration, expected: Duration) -> bool {
+        (actual.as_secs_f64() - expected.as_secs_f64()).abs() < 1e-6
+    }
+
+    #[test]
+    fn target_delay_follows_the_jitter() {
+        let start = Instant::now();
+        let mut steady = JitterBuffer::new(FRAME);
+        for i in 0..50 {
+            push(&mut steady, i, start, FRAME * i);
+        }
+        assert!(close_to(steady.jitter(), Duration::ZERO), "{:?}", steady.jitter());
+        assert!(close_to(steady.target_delay(), FRAME), "{:?}", steady.target_delay());
+
+        // Every other packet 15 ms late
+        let mut jittery = JitterBuffer::new(FRAME);
+        for i in 0..50 {
+            let wobble = if i % 2 == 1 { Duration::from_millis(15) } else { Duration::ZERO };
+            push(&mut jittery, i, start, FRAME * i + wobble);
+        }
+        assert!(jittery.jitter() > Duration::from_millis(10), "{:?}", jittery.jitter());
+        assert!(jittery.target_delay() > FRAME * 3, "{:?}", jittery.target_delay());
+
+        // The same jitter can't push the target past the maximum
+        let mut bounded = JitterBuffer::new(FRAME).with_delay_bounds(FRAME, FRAME * 2);
+        for i in 0..50 {
+            let wobble = if i % 2 == 1 { Duration::from_millis(15) } else { Duration::ZERO };
+            push(&mut bounded, i, start, FRAME * i + wobble);
+        }
+        assert!(close_to(bounded.target_delay(), FRAME * 2), "{:?}", bounded.target_delay());
+    }
+
+    #[test]
+    fn overruns_drop_the_oldest_packets() {
+        let start = Instant::now();
+        // Room for five frames
+        let mut buffer = JitterBuffer::new(FRAME).with_delay_bounds(FRAME, Duration::from_millis(110));
+        for i in 0..8 {
+            push(&mut buffer, i, start, Duration::ZERO);
+        }
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(buffer.stats().overruns, 3);
+        assert_eq!(buffer.pop(), Playout::Frame(3));
+        // What was trimmed counts as gone, not late, when it turns up again
+        push(&mut buffer, 1, start, Duration::ZERO);
+        assert_eq!(buffer.stats().late, 1);
+    }
+
+    #[test]
+    fn gaps_are_lost_and_an_empty_buffer_underruns() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(FRAME);
+        push(&mut buffer, 0, start, Duration::ZERO);
+        push(&mut buffer, 1, start, FRAME);
+        push(&mut buffer, 3, start, FRAME * 3);
+
+        assert_eq!(buffer.pop(), Playout::Frame(0));
+        assert_eq!(buffer.pop(), Playout::Frame(1));
+        // The packet after the gap comes along for FEC
+        assert_eq!(buffer.pop(), Playout::Lost { sequence_number: 2, next: Some(3) });
+        assert_eq!(buffer.pop(), Playout::Frame(3));
+        assert_eq!(buffer.pop(), Playout::Underrun);
+        assert_eq!(buffer.pop(), Playout::Buffering);
+
+        let stats = buffer.stats();
+        assert_eq!((stats.played, stats.lost, stats.underruns), (3, 1, 1));
+    }
+
+    #[test]
+    fn late_and_duplicate_packets_are_dropped() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(FRAME);
+        for i in 0..4 {
+            push(&mut buffer, i, start, FRAME * i);
+        }
+        for i in 0..3 {
+            assert_eq!(buffer.pop(), Playout::Frame(i));
+        }
+        push(&mut buffer, 1, start, FRAME * 4);
+        push(&mut buffer, 2, start, FRAME * 4);
+        push(&mut buffer, 3, start, FRAME * 4);
+        let stats = buffer.stats();
+        assert_eq!((stats.late, stats.duplicates), (2, 1));
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.pop(), Playout::Frame(3));
+    }
+
+    #[test]
+    fn a_talkspurt_ends_without_an_underrun() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(FRAME);
+        push(&mut buffer, 0, start, Duration::ZERO);
+        push(&mut buffer, 1, start, FRAME);
+        assert_eq!(buffer.pop(), Playout::Frame(0));
+        assert_eq!(buffer.pop(), Playout::Frame(1));
+        buffer.end_talkspurt();
+        assert_eq!(buffer.pop(), Playout::Buffering);
+        assert_eq!(buffer.stats().underruns, 0);
+
+        // Ten seconds of silence say nothing about the network
+        let pause = Duration::from_secs(10);
+        push(&mut buffer, 2, start, pause);
+        push(&mut buffer, 3, start, pause + FRAME);
+        assert!(close_to(buffer.jitter(), Duration::ZERO), "{:?}", buffer.jitter());
+        assert_eq!(buffer.pop(), Playout::Frame(2));
+        assert_eq!(buffer.pop(), Playout::Frame(3));
+    }
+}
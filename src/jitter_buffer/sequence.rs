@@ -0,0 +1,29 @@
+use std::cmp::Ordering;
+
+// Sequence numbers wrap, so they are compared with serial number arithmetic
+// (RFC 1982): one number comes after another if it is less than half the
+// number space ahead of it. 0 follows u32::MAX, and 65535 precedes 0 as a
+// u16.
+pub trait SequenceNumber: Copy + Eq {
+    // How far `self` is ahead of `other`, negative when it is behind
+    fn distance(self, other: Self) -> i64;
+
+    fn cmp_wrapping(self, other: Self) -> Ordering {
+        self.distance(other).cmp(&0)
+    }
+    fn precedes(self, other: Self) -> bool {
+        self.distance(other) < 0
+    }
+}
+
+impl SequenceNumber for u16 {
+    fn distance(self, other: Self) -> i64 {
+        self.wrapping_sub(other) as i16 as i64
+    }
+}
+
+impl SequenceNumber for u32 {
+    fn distance(self, other: Self) -> i64 {
+        self.wrapping_sub(other) as i32 as i64
+    }
+}
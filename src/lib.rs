@@ -6,3 +6,21 @@ pub mod settings;
 pub mod utils;
 pub mod sine;
 pub mod sound;
+pub mod protocol;
+pub mod jitter_buffer;
+pub mod codec;
+pub mod rtp;
+pub mod source;
+pub mod talk;
+pub mod mixer;
+pub mod group;
+pub mod crypto;
+pub mod session;
+pub mod sample;
+pub mod resample;
+pub mod channel_map;
+pub mod devices;
+pub mod backend;
+pub mod pipeline;
+pub mod netsim;
+pub mod network;
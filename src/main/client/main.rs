@@ -1,100 +1,238 @@
 #[allow(unused_imports)]
 use std::{
     io::{Write, stdout},
-    collections::HashMap,
+    collections::{HashMap, BTreeSet},
     sync::{
         Arc, Mutex,
         mpsc::{channel, Sender, Receiver},
     },
-    net::{UdpSocket, IpAddr},
+    net::{UdpSocket, IpAddr, SocketAddr},
     error::Error,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
 };
 #[allow(unused_imports)]
 use ringbuf::{
     traits::{Consumer, Producer, Split, Observer},
     HeapRb,
 };
-use opus::{Encoder, Application};
-use byteorder::{BigEndian, WriteBytesExt};
 #[allow(unused_imports)]
 use log::{debug, info, warn, error};
 #[allow(unused_imports)]
 use selflib::{
     utils::{clear_terminal, username_take},
-    mdns_service::MdnsService,
-    settings::{Settings, ApplicationSettings},
+    mdns_service::{name, peer, MdnsService, PeerEvent, PROBE_TIME},
+    settings::{ApplicationSettings, SettingsBuilder, DEFAULT_CLIENT_PORT},
+    protocol::PROTOCOL_VERSION,
     sine::Sine,
+    source::AudioSource,
+    session::Session,
+    talk::{TalkGate, TalkSwitch, TransmitMode},
+    group::{self, Membership, TalkGroup},
+    channel_map::InputMap,
+    devices,
+    network::{self, InterfaceSelection},
+    rtp::Framing,
+    pipeline::send::{self, Directory, Transport},
 };
 use colored::*;
 
 fn main () -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
-    let settings: ApplicationSettings = Settings::get_default_settings();
-    let (sample_rate, channels, buffer_size) = get_audio_config(&settings);
+    if devices::list_requested() {
+        devices::print_devices();
+        return Ok(());
+    }
+    if network::list_requested() {
+        network::print_interfaces();
+        return Ok(());
+    }
+    let settings = SettingsBuilder::new()
+        .port(DEFAULT_CLIENT_PORT)
+        .load()?
+        .build()?;
     println!("");
     println!("{}", "Enter Username:".cyan());
-    let username = username_take();
+    let display_name = loop {
+        match name::validate_display_name(&username_take()) {
+            Ok(display_name) => break display_name,
+            Err(e) => println!("{}", format!("{}, try again:", e).red()),
+        }
+    };
     println!("");
-    let instance_name = Arc::new(Mutex::new(username));
-    let ip =  local_ip_address::local_ip().unwrap();
-    let port: u16 = 18522;
-
-    let mdns = setup_mdns(instance_name, ip, port);
-    let user_table = mdns.get_user_table();
+    let interfaces = settings.get_interfaces();
+    let addresses = interfaces.addresses();
+    let port = settings.get_port();
+
+    let membership = Membership::new();
+    let transport = Transport {
+        framing: settings.get_framing(),
+        frames_per_packet: settings.get_frames_per_packet(),
+        key: settings.get_key(),
+    };
+    let mdns = setup_mdns(settings.get_service_type(), &display_name, interfaces, &addresses, port, membership.joined(), &transport);
+    mdns.follow_addresses(network::watch_addresses(interfaces.clone()));
+    print_peer_events(mdns.subscribe());
+    let directory = Directory {
+        peers: mdns.get_peer_table(),
+        membership: Arc::new(Mutex::new(membership)),
+    };
+    match (&transport.key, transport.framing) {
+        (Some(_), Framing::Rtp) => {
+            return Err("Encryption is not supported in RTP mode, drop --rtp or the crew key".into());
+        }
+        (Some(_), Framing::Native) => println!("{}", "Encryption on".green()),
+        (None, _) => println!("{}", "No crew key set, audio is unencrypted".yellow()),
+    }
 
-    event_loop(sample_rate, channels, buffer_size, ip, port, user_table)
+    let input_map = settings.get_input_map()?;
+    println!("Sending {} of {} input channels", input_map, settings.get_input_channels());
 
-}
+    event_loop(&settings, &input_map, port, &mdns, directory, transport)
 
-fn get_audio_config(settings: &ApplicationSettings) -> (f32, u16, usize) {
-    (
-        settings.get_sample_rate(),
-        settings.get_channels(),
-        settings.get_buffer_size(),
-    )
 }
 
-fn setup_mdns(instance_name: Arc<Mutex<String>>, ip: IpAddr, port: u16) -> MdnsService {
+fn setup_mdns(
+    service_type: &str,
+    display_name: &str,
+    interfaces: &InterfaceSelection,
+    addresses: &[IpAddr],
+    port: u16,
+    groups: &BTreeSet<TalkGroup>,
+    transport: &Transport,
+) -> MdnsService {
+    let groups = group::format_groups(groups);
+    let protocol_version = PROTOCOL_VERSION.to_string();
+    let capabilities = peer::format_capabilities(&transport.capabilities());
     let properties = vec![
         ("service name", "udp voice"),
-        ("service type", "_udp_voice._udp_local."),
+        ("service type", service_type),
         ("version", "0.0.2"),
-        ("interface", "client")
+        (peer::ROLE_PROPERTY, "client"),
+        (peer::PROTOCOL_PROPERTY, protocol_version.as_str()),
+        (peer::CAPABILITIES_PROPERTY, capabilities.as_str()),
+        (group::GROUPS_PROPERTY, groups.as_str()),
+        (peer::DISPLAY_NAME_PROPERTY, display_name),
     ];
-    let mdns = MdnsService::new("_udp_voice._udp.local.", properties);
-    mdns.register_service(&instance_name.lock().unwrap(), ip, port);
+    let mut mdns = MdnsService::new(service_type, properties);
+    mdns.use_interfaces(interfaces);
     mdns.browse_services();
+    println!("Checking whether the name is free...");
+    let label = mdns.probe_label(&name::instance_label(display_name), PROBE_TIME);
+    mdns.register_service(&label, addresses, port);
+    println!("{}", format!("Registered as {} ({})", display_name, label).green());
     mdns
 }
 
+// Departed peers are gone from the peer table already, so sends stop
+// reaching them with the next packet
+fn print_peer_events(events: Receiver<PeerEvent>) {
+    std::thread::spawn(move || {
+        for event in events {
+            match event {
+                PeerEvent::Joined(peer) => println!("{}", format!("{} joined", peer.display_name).green()),
+                PeerEvent::Left(peer) => println!("{}", format!("{} left", peer.display_name).yellow()),
+            }
+        }
+    });
+}
+
 fn event_loop (
-    sample_rate: f32,
-    channels: u16,
-    buffer_size: usize,
-    ip: IpAddr,
+    settings: &ApplicationSettings,
+    input_map: &InputMap,
     port: u16,
-    user_table: Arc<Mutex<HashMap<String, String>>>,
+    mdns: &MdnsService,
+    directory: Directory,
+    transport: Transport,
 ) -> Result<(), Box<dyn Error>> {
+    let mut active_session: Option<Session> = None;
+    let mut transmit_mode = TransmitMode::default();
+    let talk_switch = TalkSwitch::new();
     loop {
         let input = get_user_input();
-        let opus_channels = if channels == 1 {
-            opus::Channels::Mono
-        } else {
-            opus::Channels::Stereo
-        };
-
-        match input.as_str() {
-            "send" => start_sending(
-                sample_rate,
-                channels,
-                buffer_size,
-                ip.clone(),
-                port,
-                opus_channels,
-                user_table.clone(),
-            ),
-            "exit" => return Ok(()),
+        let (command, argument) = input.split_once(' ').unwrap_or((input.as_str(), ""));
+
+        match command {
+            // In push-to-talk mode a bare Enter works the talk button
+            "" | "ptt" if transmit_mode == TransmitMode::PushToTalk => {
+                if talk_switch.toggle() {
+                    println!("{}", "TALKING".green().bold());
+                } else {
+                    println!("{}", "Listening".yellow());
+                }
+            }
+            "mode" => match argument.parse::<TransmitMode>() {
+                Ok(mode) => {
+                    transmit_mode = mode;
+                    talk_switch.release();
+                    println!("Transmit mode set to {}, takes effect on the next send", mode);
+                }
+                Err(e) => println!("{}", e.red()),
+            },
+            "join" | "leave" | "talk" => {
+                let group = match argument.parse::<TalkGroup>() {
+                    Ok(group) => group,
+                    Err(e) => {
+                        println!("{}", e.red());
+                        continue;
+                    }
+                };
+                let mut membership = directory.membership.lock().unwrap();
+                match command {
+                    "join" => if !membership.join(group.clone()) {
+                        println!("Already in {}", group);
+                    },
+                    "leave" => if let Err(e) = membership.leave(&group) {
+                        println!("{}", e.red());
+                    },
+                    _ => {
+                        membership.talk_on(group.clone());
+                        println!("Talking on {}", group.to_string().green());
+                    }
+                }
+                mdns.set_property(group::GROUPS_PROPERTY, &group::format_groups(membership.joined()));
+            }
+            "peers" => {
+                for peer in mdns.peers() {
+                    println!("{}, seen {}s ago", peer, peer.last_seen.elapsed().as_secs());
+                }
+            }
+            "groups" => {
+                let membership = directory.membership.lock().unwrap();
+                println!("Joined: {}, talking on {}", group::format_groups(membership.joined()), membership.talk_group());
+            }
+            "send" => {
+                let source = match argument.parse::<AudioSource>() {
+                    Ok(source) => source,
+                    Err(e) => {
+                        println!("{}", e.red());
+                        continue;
+                    }
+                };
+                // Only one transmission at a time, and it needs the port
+                if let Some(mut session) = active_session.take() {
+                    session.stop();
+                }
+                let gate = TalkGate::new(transmit_mode, talk_switch.clone());
+                // Dual-stack, so IPv4 and IPv6 servers are both in reach
+                let local = network::any_address(port);
+                match send::start_sending(settings, &source, input_map.clone(), gate, local, directory.clone(), transport.clone()) {
+                    Ok(session) => active_session = Some(session),
+                    Err(e) => println!("{}", format!("Failed to start {} source: {}", source, e).red()),
+                }
+            }
+            "stop" => match active_session.take() {
+                Some(mut session) => {
+                    session.stop();
+                    println!("{}", "Stopped sending".yellow());
+                }
+                None => println!("{}", "Not sending".red()),
+            },
+            "exit" => {
+                if let Some(mut session) = active_session.take() {
+                    session.stop();
+                }
+                return Ok(());
+            }
             _ => println!("{}", "Not a permitted command".red()),
         }
     }
@@ -104,117 +242,3 @@ fn get_user_input() -> String {
     std::io::stdin().read_line(&mut buffer).unwrap();
     buffer.trim().to_string()
 }
-fn start_sending(
-    sample_rate: f32,
-    channels: u16,
-    buffer_size: usize,
-    ip: IpAddr,
-    port: u16,
-    opus_channels: opus::Channels,
-    user_table: Arc<Mutex<HashMap<String, String>>>,
-) {
-    let (output_sine, input_encoder) = channel();
-    let (output_encoder, input_buffer) = channel();
-
-    Sine::new(440.0, 1.0, sample_rate as u32, channels as usize, output_sine, buffer_size);
-
-    std::thread::spawn(move || encode_opus(input_encoder, output_encoder, sample_rate, opus_channels, buffer_size));
-
-    std::thread::spawn(move || batch_and_send_udp(ip, port, input_buffer, user_table));
-}
-fn encode_opus(
-    input_encoder: Receiver<Vec<f32>>,
-    output_encoder: Sender<Vec<u8>>,
-    sample_rate: f32,
-    opus_channels: opus::Channels,
-    buffer_size: usize,
-) {
-    let mut opus_encoder = Encoder::new(sample_rate as u32, opus_channels, Application::Audio).unwrap();
-    opus_encoder.set_bitrate(opus::Bitrate::Bits(64000)).unwrap();
-    opus_encoder.set_vbr(false).unwrap();
-
-    while let Ok(block) = input_encoder.recv() {
-        let mut encoded_block = vec![0; buffer_size];
-        if let Ok(len) = opus_encoder.encode_float(&block, &mut encoded_block) {
-            output_encoder.send(encoded_block[..len].to_vec()).expect("Failed to send encoded data");
-        }
-    }
-}
-
-fn batch_and_send_udp(
-    ip: IpAddr,
-    port: u16,
-    input_buffer: Receiver<Vec<u8>>,
-    user_table: Arc<Mutex<HashMap<String, String>>>,
-) {
-    let ip_port = format!("{}:{}", ip, port);
-    let socket = UdpSocket::bind(&ip_port).expect("UDP: Failed to bind to socket");
-
-    let packet_amount = 20;
-    let mut batch_buffer = Vec::new(); // Start with an empty vector
-    let mut sequence_number = 0;
-
-    loop {
-        if let Ok(block) = input_buffer.recv() {
-            let frame_length = block.len() as u16; // Assuming frame length fits in u16
-
-            // Include the length of the frame before the frame data
-            batch_buffer.extend_from_slice(&frame_length.to_be_bytes());
-            batch_buffer.extend_from_slice(&block);
-
-            if batch_buffer.len() >= packet_amount * (frame_length as usize + 2) {
-                for (_user, address) in user_table.lock().unwrap().clone() {
-                    send_packet(&socket, &address, &batch_buffer, sequence_number);
-                    sequence_number += 1;
-                }
-                batch_buffer.clear();
-            }
-        }
-    }
-}
-fn send_packet(socket: &UdpSocket, address: &str, batch_buffer: &[u8], sequence_number: u32) {
-    let port = format!("{}:18521", address);
-    let packet = create_packet(batch_buffer, sequence_number);
-    socket.send_to(&packet, &port).expect("Failed to send data");
-}
-fn current_time_in_ms() -> Vec<u8> {
-    let start = SystemTime::now();
-    let since_epoch = start.duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    let ms = since_epoch.as_millis();
-    let mut bytes = ms.to_be_bytes().to_vec();
-    let mut timestamp = vec![0xAA, 0xBB];
-    timestamp.append(&mut bytes);
-    timestamp.extend_from_slice(&[0xBB, 0xAA]);
-    timestamp
-}
-
-fn sequencer(sequence_number: u32) -> Vec<u8> {
-    let mut bytes = sequence_number.to_be_bytes().to_vec();
-    let mut sequence = vec![0xCC, 0xDD];
-    sequence.append(&mut bytes);
-    sequence.extend_from_slice(&[0xDD, 0xCC]);
-    sequence
-}
-
-fn create_packet(batch_buffer: &[u8], sequence_number: u32) -> Vec<u8> {
-    let data_len = batch_buffer.len() as u32;
-    let time_in_ms = current_time_in_ms();
-    let sequence_num  = sequencer(sequence_number);
-
-    let sequence_num_len = sequence_num.len() as u8;
-    let time_in_ms_len = time_in_ms.len() as u8;
-
-    let mut packet = Vec::with_capacity(
-        4 + sequence_num_len as usize + time_in_ms_len as usize + batch_buffer.len(),
-    );
-
-    packet.write_u32::<BigEndian>(data_len).unwrap();
-    packet.extend_from_slice(&sequence_num);
-    packet.extend_from_slice(&time_in_ms);
-    packet.extend_from_slice(batch_buffer);
-    packet
-
-}
-
-
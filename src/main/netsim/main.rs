@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use colored::*;
+use selflib::netsim::{Impairment, Proxy};
+use selflib::settings::{self, DEFAULT_SERVER_PORT};
+
+// A UDP proxy that makes the network between a client and a server as bad
+// as asked for:
+//
+//     server --port 18531 --advertise-port 18521
+//     netsim --forward 127.0.0.1:18531 --preset wifi --loss 5 --seed 7
+//
+// The server advertises the usual port, which netsim listens on, so clients
+// send through the proxy.
+// See Impairment::from_args for the impairment flags.
+fn main() {
+    env_logger::init();
+    let listen = match address_arg("--listen") {
+        Ok(listen) => listen.unwrap_or(SocketAddr::from(([0, 0, 0, 0], DEFAULT_SERVER_PORT))),
+        Err(e) => {
+            eprintln!("NETSIM: {}", e);
+            return;
+        }
+    };
+    let forward = match address_arg("--forward") {
+        Ok(Some(forward)) => forward,
+        Ok(None) => {
+            eprintln!("NETSIM: Missing --forward <address:port> of the server");
+            return;
+        }
+        Err(e) => {
+            eprintln!("NETSIM: {}", e);
+            return;
+        }
+    };
+    let impairment = match Impairment::from_args() {
+        Ok(impairment) => impairment,
+        Err(e) => {
+            eprintln!("NETSIM: {}", e);
+            return;
+        }
+    };
+
+    let mut proxy = match Proxy::start(listen, forward, impairment) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            eprintln!("NETSIM: Failed to listen on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("NETSIM: Forwarding {} to {}", proxy.local_addr(), forward);
+    println!("NETSIM: {}", impairment);
+
+    // "stats" prints what happened so far, "exit" stops. Without a
+    // terminal it just keeps forwarding.
+    for line in std::io::stdin().lines() {
+        match line.as_deref().map(str::trim) {
+            Ok("stats") => print_stats(&proxy),
+            Ok("exit") => {
+                proxy.stop();
+                print_stats(&proxy);
+                return;
+            }
+            Ok(_) => println!("{}", "Not a permitted command".red()),
+            Err(_) => break,
+        }
+    }
+    proxy.join();
+}
+
+fn address_arg(flag: &str) -> Result<Option<SocketAddr>, String> {
+    match settings::arg_value(flag) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid {} '{}', expected address:port", flag, value)),
+        None => Ok(None),
+    }
+}
+
+fn print_stats(proxy: &Proxy) {
+    let (upstream, downstream) = proxy.stats();
+    println!("NETSIM: To server: {}", upstream);
+    println!("NETSIM: To client: {}", downstream);
+}
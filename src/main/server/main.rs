@@ -1,398 +1,216 @@
-use cpal::{
-    Device,
-    StreamConfig,
-    traits::{DeviceTrait, StreamTrait},
-};
 #[allow(unused_imports)]
 use byteorder::{BigEndian, ReadBytesExt, ByteOrder};
-use selflib::mdns_service::MdnsService;
+use selflib::mdns_service::{name, peer, MdnsService, PROBE_TIME};
+use selflib::protocol::PROTOCOL_VERSION;
 #[allow(unused_imports)]
 use log::{debug, info, warn, error};
-use selflib::settings::{Settings, ApplicationSettings};
+use selflib::settings::{ApplicationSettings, SettingsBuilder, DEFAULT_SERVER_PORT};
+use selflib::group::{self, TalkGroup};
+use selflib::session::{Session, POLL_INTERVAL};
+use selflib::rtp::Framing;
+use selflib::pipeline::receive::{self, StreamFormat};
 #[allow(unused_imports)]
 use std::{
-    collections::{VecDeque, BTreeMap},
+    collections::{VecDeque, BTreeMap, BTreeSet, HashMap},
     io::{Cursor, Read},
-    net::{UdpSocket, IpAddr},
+    net::{UdpSocket, IpAddr, SocketAddr},
     sync::{
         Arc,
         Mutex,
         mpsc::{channel, Sender, Receiver}
     },
-    time::Instant,
+    time::{Duration, Instant},
     error::Error,
     thread::JoinHandle,
 };
 #[allow(unused_imports)]
 use colored::*;
-use opus::Decoder;
-use cpal::SampleFormat;
-
-#[allow(dead_code)]
-#[derive(Debug)]
-struct PacketData {
-    sequence_number: u32,
-    payload: Vec<u8>,
-}
+use selflib::devices;
+use selflib::network::{self, InterfaceSelection};
 
-const DATA_LEN_SIZE: usize = 4;
-const SEQUENCE_NUM_SIZE: usize = 8;
-const TIMESTAMP_SIZE: usize = 20;
-const HEADER_SIZE: usize = DATA_LEN_SIZE + SEQUENCE_NUM_SIZE + TIMESTAMP_SIZE;
-const PAYLOAD_SIZE: usize = 160 * 20;
-const MAX_PACKET_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE;
+// Shown to clients; every server gets its own label from it, udp-server,
+// udp-server-2 and so on
+const SERVER_NAME: &str = "udp server";
 
 fn main (){
     env_logger::init();
-    let settings: ApplicationSettings = Settings::get_default_settings();
-    let stream_config = settings.create_stream_config();
-    let (channels, sample_rate, buffer_size, sample_format) = get_audio_config(&settings);
-
-    let (_, output_device) = settings.get_devices();
-    let output_device = Arc::new(Mutex::new(output_device));
-
-    let ip =  local_ip_address::local_ip().unwrap();
-    let port: u16 = 18521;
-    let ip_port = format!("{}:{}", ip, port);
-
-    let _mdns = setup_mdns(ip, port);
-    println!("SERVER: Binding to UDP socket on {}", ip_port);
-    let socket = UdpSocket::bind(ip_port).expect("UDP: Failed to bind socket");
-    println!("SERVER: UDP socket bound successfully");
-
-    let (sender_udp, receiver_audio) = channel();
-    let (sender_decoder, receiver_dac) = channel();
-
-    let delay_buffer_size = buffer_size * 100;
-    let delay_buffer = Arc::new(
-        Mutex::new(
-            VecDeque::<f32>::with_capacity(delay_buffer_size)
-        )
-    );
-    let delay_buffer_producer = Arc::clone(&delay_buffer);
-    let playback_buffer = Arc::clone(&delay_buffer);
-
-    // UDP Thread
-    let udp_thread = start_udp_thread(socket, sender_udp, buffer_size * 20);
-
-    // Decoder Thread
-    let decoder_thread = start_decoder_thread(
-        receiver_audio,
-        sender_decoder,
+    if devices::list_requested() {
+        devices::print_devices();
+        return;
+    }
+    if network::list_requested() {
+        network::print_interfaces();
+        return;
+    }
+    let settings = match SettingsBuilder::new().port(DEFAULT_SERVER_PORT).load().and_then(SettingsBuilder::build) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("SERVER: {}", e);
+            return;
+        }
+    };
+    let (channels, sample_rate, buffer_size) = get_audio_config(&settings);
+    let backend = settings.get_backend();
+
+    let interfaces = settings.get_interfaces().clone();
+    let addresses = interfaces.addresses();
+    let port = settings.get_port();
+    let output_map = match settings.get_output_map() {
+        Ok(output_map) => output_map,
+        Err(e) => {
+            eprintln!("SERVER: {}", e);
+            return;
+        }
+    };
+    println!("SERVER: Playing on {} of {} output channels", output_map, channels);
+    let format = StreamFormat {
         sample_rate,
-        channels,
-        buffer_size
-    );
-
-    // Producer Thread
-    let producer_thread = start_producer_thread(
-        receiver_dac,
-        delay_buffer_producer,
-        buffer_size
-    );
-
-    // DAC Thread
-    let dac_thread = start_dac_thread(
-        output_device,
-        playback_buffer,
-        stream_config,
-        sample_format,
-        buffer_size
-    );
-
-    let _ = udp_thread.join();
-    let _ = decoder_thread.join();
-    let _ = dac_thread.unwrap().join();
-    let _ = producer_thread.join();
-}
-
-fn get_audio_config(settings: &ApplicationSettings) -> (u16, f32, usize, SampleFormat) {
-    (
-        settings.get_channels(),
-        settings.get_sample_rate(),
-        settings.get_buffer_size(),
-        settings.get_config_files().1.sample_format(),
-    )
-}
-fn setup_mdns(ip: IpAddr, port: u16) -> MdnsService {
-    let service_type = "_udp_voice._udp.local.";
-    let properties = vec![
-        ("service name", "udp voice"),
-        ("service type", service_type),
-        ("version", "0.0.0"),
-        ("interface", "server"),
-    ];
-    let mdns = MdnsService::new(service_type, properties);
-    mdns.register_service("udp_server", ip, port);
-    mdns.browse_services();
-    mdns
-}
-fn start_udp_thread(socket: UdpSocket, sender_udp: Sender<Vec<u8>>, min_buffer_fill: usize) -> JoinHandle<()> {
-    // [Data Length (4 bytes)] + [Sequence Number (8 bytes)] + [Timestamp (20 bytes)] + [Payload (variable length)]
-    let jitter_buffer: Arc<Mutex<BTreeMap<u32, PacketData>>> = Arc::new(Mutex::new(BTreeMap::new()));
-    std::thread::spawn(move || {
-        let mut prev_packet_time: Option<Instant> = None;
-        loop {
-            let mut buf = [0u8; MAX_PACKET_SIZE];
-            while let Ok((amount, _src)) = socket.recv_from(&mut buf) {
-                let current_packet_time = Instant::now();
-                if let Some(prev_time) = prev_packet_time {
-                println!(
-                    "Time since last packet: {:.3} ms",
-                    current_packet_time.duration_since(prev_time).as_secs_f64() * 1000.0);
-                }
-                prev_packet_time = Some(current_packet_time);
-                let mut cursor = Cursor::new(&buf[0..amount]);
-                let packet_len = match cursor.read_u32::<BigEndian>() {
-                    Ok(len) => len,
-                    Err(e) => {
-                        eprintln!("Error reading packet length: {:?}", e);
-                        continue;
-                    }
-                };
-                let mut sequence_num_buf = [0u8; 8];
-                if let Err(e) = cursor.read_exact(&mut sequence_num_buf) {
-                    eprintln!("Error reading sequence number: {:?}", e);
-                    continue;
-                }
-                if sequence_num_buf[0..2] != [0xCC, 0xDD] || sequence_num_buf[6..8] != [0xDD, 0xCC] {
-                    eprintln!("Invalid sequence number header");
-                    continue;
-                }
-                let sequence_number = BigEndian::read_u32(&sequence_num_buf[2..6]);
-
-                let mut time_in_ms_buf = [0u8; 20];
-                if let Err(e) = cursor.read_exact(&mut time_in_ms_buf) {
-                    eprintln!("Error reading sequence number: {:?}", e);
-                    continue;
-                }
-                if time_in_ms_buf[0..2] != [0xAA, 0xBB] || time_in_ms_buf[18..20] != [0xBB, 0xAA] {
-                    eprintln!("Invalid timestamp header");
-                    continue;
-                }
-                let timestamp = BigEndian::read_u128(&time_in_ms_buf[2..18]);
-
-                println!("data_len: {packet_len}, sequence_num: {sequence_number}, timestamp: {timestamp}");
-
-                let payload_start = cursor.position() as usize;
-                let payload = &buf[payload_start..amount];
-                // let payload = pad_data(payload, data_len, amount);
-                let packet = PacketData {
-                    sequence_number,
-                    payload: payload.to_vec(),
-                };
-
-                {
-                    let mut buffer = jitter_buffer.lock()
-                        .expect("Unable to acquire jitter buffer lock");
-                    buffer.insert(sequence_number, packet);
-            }
-
-                handle_jitter_buffer(jitter_buffer.clone(), sender_udp.clone(), min_buffer_fill);
-            }
+        channels: output_map.stream_channels(channels as usize) as u16,
+        buffer_size,
+        framing: settings.get_framing(),
+    };
+
+    let key = settings.get_key();
+    match (&key, format.framing) {
+        (Some(_), Framing::Rtp) => {
+            eprintln!("SERVER: Encryption is not supported in RTP mode, drop --rtp or the crew key");
+            return;
         }
-    })
-}
-
-fn _pad_data(mut data: Vec<u8>, expected_len: u32, received_len: usize) -> Vec<u8> {
-    if received_len < expected_len as usize {
-        data.extend(vec![0; expected_len as usize - received_len]);
+        (Some(_), Framing::Native) => println!("SERVER: Encryption on, only accepting packets sealed with the crew key"),
+        (None, _) => println!("SERVER: {}", "No crew key set, audio is unencrypted".yellow()),
     }
-    data
-}
-
-fn handle_jitter_buffer(
-    jitter_buffer: Arc<Mutex<BTreeMap<u32, PacketData>>>,
-    sender_udp: Sender<Vec<u8>>,
-    min_buffer_fill: usize,
-) {
-    let mut buffer = jitter_buffer.lock().expect("Unable to get lock for jitter buffer");
-    // if the buffer is filled
-    if buffer.len() >= min_buffer_fill {
-        println!("Original buffer keys: {:?}", buffer.keys());
-        let keys: Vec<_> = buffer.keys().cloned().collect();
-        let min_seq = *keys.first().unwrap();
-        let max_seq = *keys.last().unwrap();
-
-        for expected_seq in min_seq..=max_seq {
-            if !buffer.contains_key(&expected_seq) {
-                let reference_payload = match buffer.range(..expected_seq).next_back() {
-                    Some((_seq, packet)) => &packet.payload,
-                    None => {
-                        continue;
-                    }
-                };
-                let interpolated_data = interpolate_placeholder(reference_payload);
-                buffer.insert(expected_seq, PacketData {
-                    sequence_number: expected_seq,
-                    payload: interpolated_data,
-                });
+    let groups = settings.get_groups();
+    println!("SERVER: Listening to groups {}", group::format_groups(&groups));
+    let capabilities = peer::capabilities(format.framing, key.is_some());
+    println!("SERVER: Using interfaces {}", interfaces);
+    let advertise_port = settings.get_advertise_port();
+    if advertise_port != port {
+        println!("SERVER: Listening on port {}, advertising port {}", port, advertise_port);
+    }
+    let mdns = setup_mdns(settings.get_service_type(), &interfaces, &addresses, advertise_port, &groups, &capabilities);
+
+    let start = || {
+        let mut session = Session::new("server");
+        let sockets = bind_sockets(&interfaces, port);
+        receive::start_receiving(&mut session, sockets, backend.clone(), output_map.clone(), groups.clone(), key.clone(), format);
+        session
+    };
+    let mut session = start();
+
+    // Runs until told to exit; without a terminal it just keeps serving
+    for event in server_events(&interfaces) {
+        match event {
+            ServerEvent::Command(command) if command == "exit" => {
+                println!("SERVER: Shutting down");
+                session.stop();
+                return;
             }
-        }
-        println!("Final Buffer Keys: {:?}", buffer.keys());
-        for (_, packet) in buffer.iter() {
-            if let Err(e) = sender_udp.send(packet.payload.clone()) {
-                eprintln!("SERVER: Failed to send data to audio thread: {:?}", e);
+            ServerEvent::Command(_) => println!("{}", "Not a permitted command".red()),
+            ServerEvent::AddressesChanged(addresses) => {
+                // Sockets on the unspecified address pick up new addresses
+                // by themselves, ones bound to a single address have to go
+                if matches!(interfaces, InterfaceSelection::Named(_)) {
+                    println!("SERVER: Addresses changed, rebinding");
+                    session.stop();
+                    session = start();
+                }
+                mdns.update_addresses(&addresses);
             }
         }
-        buffer.clear();
     }
+    session.join();
 }
-fn interpolate_placeholder(prev_payload: &[u8]) -> Vec<u8> {
-    prev_payload.to_vec()
+
+enum ServerEvent {
+    // A line typed on the terminal
+    Command(String),
+    // The chosen interfaces now hold these
+    AddressesChanged(Vec<IpAddr>),
 }
 
-fn start_decoder_thread(
-    receiver_audio: Receiver<Vec<u8>>,
-    sender_decoder: Sender<Vec<f32>>,
-    sample_rate: f32,
-    channels: u16,
-    target_fill_rate: usize,
-) -> JoinHandle<()> {
+// Terminal commands and address changes as they come. Ends once stdin
+// closes, if the address watcher ever stops.
+fn server_events(interfaces: &InterfaceSelection) -> Receiver<ServerEvent> {
+    let (sender, receiver) = channel();
+    let commands = sender.clone();
     std::thread::spawn(move || {
-        let opus_channels = if channels == 1 {
-            opus::Channels::Mono
-        } else {
-            opus::Channels::Stereo
-        };
-        let mut opus_decoder = Decoder::new(sample_rate as u32, opus_channels).unwrap();
-        let mut accumulated_samples = Vec::with_capacity(1920);
-
-        while let Ok(packet) = receiver_audio.recv() {
-            let mut offset = 0;
-            while offset + 2 <= packet.len() {
-                let frame_length = BigEndian::read_u16(&packet[offset..offset + 2]) as usize;
-                offset += 2;
-
-                if offset + frame_length > packet.len() {
-                    eprintln!("Incomplete frame detected");
-                    break;
-                }
-
-                let frame = &packet[offset..offset + frame_length];
-                offset += frame_length;
-
-                let decoded_samples = decode_packet(frame.to_vec(), &mut opus_decoder, channels);
-                if decoded_samples.is_empty() {
-                    let interpolated_samples = repeat_previous_frame(&accumulated_samples, target_fill_rate);
-                    accumulated_samples.extend_from_slice(&interpolated_samples);
-                } else {
-                    accumulated_samples.extend(decoded_samples);
-                }
-
-                if accumulated_samples.len() >= target_fill_rate {
-                    sender_decoder
-                        .send(accumulated_samples.drain(..target_fill_rate).collect())
-                        .expect("Failed to send decoded data");
-                }
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if commands.send(ServerEvent::Command(line.trim().to_string())).is_err() {
+                break;
             }
         }
-    })
-}
-
-fn decode_packet(
-    packet: Vec<u8>,
-    decoder: &mut Decoder,
-    channels: u16,
-) -> Vec<f32> {
-    let max_frame_size = 5760 * channels as usize;
-    let mut decoded = vec![0.0; max_frame_size];
-    match decoder.decode_float(&packet, &mut decoded, false) {
-        Ok(len) => {
-            decoded.truncate(len * channels as usize);
-            decoded
-        },
-        Err(e) => {
-            eprintln!("Decoding failed: {:?}", e);
-            vec![] // Return empty vec if decoding fails
-        }
-    }
-}
-
-fn repeat_previous_frame(
-    prev_samples: &[f32],
-    target_fill_rate: usize
-) -> Vec<f32> {
-    if prev_samples.is_empty() {
-        vec![0.0; target_fill_rate] // Fill with silence if no previous data
-    } else {
-        prev_samples.iter().cycle().take(target_fill_rate).cloned().collect()
-    }
-}
-
-fn start_producer_thread(
-    receiver_dac: Receiver<Vec<f32>>,
-    delay_buffer: Arc<Mutex<VecDeque<f32>>>,
-    buffer_size: usize,
-) -> JoinHandle<()> {
+    });
+    let changes = network::watch_addresses(interfaces.clone());
     std::thread::spawn(move || {
-        while let Ok(block) = receiver_dac.recv() {
-            let mut buffer = delay_buffer.lock().expect("Failed to lock delay buffer for producer");
-            buffer.extend(block);
-            while buffer.len() > buffer_size * 100 {
-                buffer.drain(..buffer_size);
+        for addresses in changes {
+            if sender.send(ServerEvent::AddressesChanged(addresses)).is_err() {
+                break;
             }
         }
-    })
-}
-
-fn start_dac_thread(
-    device: Arc<Mutex<Device>>,
-    delay_buffer: Arc<Mutex<VecDeque<f32>>>,
-    stream_config: StreamConfig,
-    sample_format: SampleFormat,
-    buffer_size: usize,
-) -> Result<JoinHandle<()>, Box<dyn Error>> {
-    let delay_buffer_clone = Arc::clone(&delay_buffer);
-    let dac_thread = std::thread::spawn(move || {
-        wait_for_buffer_fill(&delay_buffer_clone, stream_config.channels as usize * buffer_size);
-        play_stream(device, delay_buffer_clone, stream_config, sample_format);
     });
-
-    Ok(dac_thread)
+    receiver
 }
 
-fn wait_for_buffer_fill(buffer: &Arc<Mutex<VecDeque<f32>>>, target_size: usize) {
-    loop {
-        let buffer_len = buffer.lock().expect("Failed to acquire playback buffer lock").len();
-        if buffer_len >= target_size * 4 / 5 {
-            break;
-        }
-        std::thread::sleep(std::time::Duration::from_millis(10));
+// One socket per address to receive on. An address that fails to bind is
+// skipped, the others still work.
+fn bind_sockets(interfaces: &InterfaceSelection, port: u16) -> Vec<UdpSocket> {
+    let locals = interfaces.bind_addresses(port);
+    if locals.is_empty() {
+        println!("SERVER: {}", format!("No addresses on {} yet, waiting for one", interfaces).yellow());
     }
+    locals
+        .into_iter()
+        .filter_map(|local| {
+            println!("SERVER: Binding to UDP socket on {}", local);
+            let socket = match network::bind_udp(local) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    eprintln!("SERVER: Failed to bind {}: {}", local, e);
+                    return None;
+                }
+            };
+            socket.set_read_timeout(Some(POLL_INTERVAL)).expect("UDP: Failed to set read timeout");
+            Some(socket)
+        })
+        .collect()
 }
 
-fn play_stream(
-    device: Arc<Mutex<cpal::Device>>,
-    buffer: Arc<Mutex<VecDeque<f32>>>,
-    stream_config: cpal::StreamConfig,
-    sample_format: SampleFormat,
-) {
-    let stream = match sample_format {
-        SampleFormat::F32 => {
-            device.lock().unwrap().build_output_stream(
-                &stream_config,
-                move |data: &mut [f32], _| fill_audio_data(data, &buffer),
-                |err| eprintln!("DAC: Stream error: {}", err),
-                None,
-            )
-        }
-        SampleFormat::I16 => todo!(),
-        SampleFormat::U16 => todo!(),
-        _ => panic!("DAC: Unsupported sample format '{:?}'", sample_format),
-    }
-    .unwrap();
-
-    stream.play().expect("Failed to play stream");
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
+fn get_audio_config(settings: &ApplicationSettings) -> (u16, f32, usize) {
+    (
+        settings.get_channels(),
+        settings.get_sample_rate(),
+        settings.get_buffer_size(),
+    )
 }
-
-fn fill_audio_data(data: &mut [f32], buffer: &Arc<Mutex<VecDeque<f32>>>) {
-    let mut buffer = buffer.lock().expect("Failed to lock buffer for playback");
-    for sample in data.iter_mut() {
-        *sample = buffer.pop_front().unwrap_or(0.0);
-    }
+fn setup_mdns(
+    service_type: &str,
+    interfaces: &InterfaceSelection,
+    addresses: &[IpAddr],
+    port: u16,
+    groups: &BTreeSet<TalkGroup>,
+    capabilities: &BTreeSet<String>,
+) -> MdnsService {
+    let groups = group::format_groups(groups);
+    let protocol_version = PROTOCOL_VERSION.to_string();
+    let capabilities = peer::format_capabilities(capabilities);
+    let properties = vec![
+        ("service name", "udp voice"),
+        ("service type", service_type),
+        ("version", "0.0.0"),
+        (peer::ROLE_PROPERTY, "server"),
+        (peer::PROTOCOL_PROPERTY, protocol_version.as_str()),
+        (peer::CAPABILITIES_PROPERTY, capabilities.as_str()),
+        (group::GROUPS_PROPERTY, groups.as_str()),
+        (peer::DISPLAY_NAME_PROPERTY, SERVER_NAME),
+    ];
+    let mut mdns = MdnsService::new(service_type, properties);
+    mdns.use_interfaces(interfaces);
+    mdns.browse_services();
+    let label = mdns.probe_label(&name::instance_label(SERVER_NAME), PROBE_TIME);
+    mdns.register_service(&label, addresses, port);
+    println!("SERVER: Registered as {}", label);
+    mdns
 }
-
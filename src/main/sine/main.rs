@@ -1,43 +1,40 @@
-use cpal::SampleFormat;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use selflib::settings::{Settings, ApplicationSettings};
-use std::env;
+use selflib::settings::SettingsBuilder;
+use selflib::devices;
 use std::f32::consts::PI;
 use ringbuf::{
-    traits::{Consumer, Producer, Split, Observer}, 
+    traits::{Consumer, Producer, Split, Observer},
     HeapRb,
 };
 
-
 pub fn main() {
 
-    let settings: ApplicationSettings = Settings::get_default_settings();
+    if devices::list_requested() {
+        devices::print_devices();
+        return;
+    }
+    let settings = match SettingsBuilder::new().load().and_then(SettingsBuilder::build) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("SINE: {}", e);
+            return;
+        }
+    };
     let buffer_size = settings.get_buffer_size();
-    let amplitude = settings.get_amplitude();
-    // Command Line Arguments
-    let args: Vec<String> = env::args().collect();
-    let frequency = &args[1];
-    let frequency: f32 = frequency.parse().unwrap();
-    let duration = &args[2];
-    let duration: u64 = duration.parse().unwrap();
+    let tone = settings.get_test_tone();
+    let amplitude = tone.get_amplitude();
+    let frequency = tone.get_frequency();
+    let duration = settings.get_duration();
 
-
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("no output device available");
-    
-    // Default audio config
-    let config = device.default_output_config().unwrap();
-    let sample_format = config.sample_format();
-    let sample_rate = config.sample_rate().0;
-    let channels = config.channels();
+    let backend = settings.get_backend();
+    let format = backend.output_format();
+    let sample_rate = format.sample_rate;
+    let channels = format.channels;
 
     let ring = HeapRb::<f32>::new(buffer_size * channels as usize);
     let (mut producer, mut consumer) = ring.split();
 
-    let _buffer_duration: u64 = (1000 / sample_rate as u64) * buffer_size as u64;
-
     let _producer_thread = std::thread::spawn( move || {
-        let mut phase = 0.0 as f32;
+        let mut phase: f32 = 0.0;
         let phase_increment = 2.0 * PI * frequency / sample_rate as f32;
         // Sine Equation:
         // sine = sin(phase * 2.0 * PI * Frecuencia / Sample Rate)
@@ -49,8 +46,8 @@ pub fn main() {
                     if phase > 2.0 * PI {
                         phase -= 2.0 * PI;
                     }
-                    std::iter::repeat(sample).take(channels as usize)
-                    
+                    std::iter::repeat_n(sample, channels as usize)
+
                 })
             .collect();
 
@@ -63,39 +60,16 @@ pub fn main() {
         }
     });
 
-    // We can send the consumer through a channel mpsc and use 
+    // We can send the consumer through a channel mpsc and use
     // to send it as input for the network Client
 
     std::thread::sleep(std::time::Duration::from_millis(1000));
-    let config = config.into();
-
-    let stream = match sample_format {
-        SampleFormat::F32 => device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                for sample in data {
-                    *sample = consumer.try_pop().unwrap_or(0.0);
-                    }
-                },
-            move |err| {
-                // react to errors here.
-                eprintln!("Failed to output samples into stream: {}", err);
-            },
-            None //None=blocking, Some(Duration)=timeout
-        ),
-        SampleFormat::I16 => {
-            println!("Not yet implemented(I16)");
-            todo!();
-        },
-        SampleFormat::U16 => {
-            println!("Not yet implemented (U16)");
-            todo!();
+    let _stream = backend.start_output(Box::new(move |data: &mut [f32]| {
+        for sample in data {
+            *sample = consumer.try_pop().unwrap_or(0.0);
         }
-        sample_format => panic!("Unsupported sample format '{sample_format}'")
-    }.unwrap();
-    
-    stream.play().expect("Failed to play stream");
+    })).expect("Failed to start output");
 
-    std::thread::sleep(std::time::Duration::from_millis(duration));
+    std::thread::sleep(duration);
 
 }
@@ -1,22 +1,28 @@
 use selflib::sine::Sine;
-use selflib::settings::Settings;
+use selflib::session::StopFlag;
+use selflib::settings::{Settings, ApplicationSettings};
 use std::sync::mpsc::channel;
 
 
 fn main () {
 
-    let settings = Settings::get_default_settings();
+    let settings: ApplicationSettings = Settings::get_default_settings();
     let channels = settings.get_channels();
     let buffer_size = settings.get_buffer_size();
     let sample_rate = settings.get_sample_rate();
-    let (_input_device, output_device) = settings.get_devices();
-    let (_input_config, output_config) = settings.get_config_files();
+    let backend = settings.get_backend();
 
     let (sender, receiver) = channel();
-    let sine = Sine::new(220.0, 1.0, sample_rate as u32, channels as usize, sender, buffer_size );
-    sine.play(receiver, buffer_size, output_device, output_config);
+    let tone = settings.get_test_tone();
+    let sine = Sine::new(tone.get_frequency(), tone.get_amplitude(), sample_rate as u32, channels as usize, sender, buffer_size );
 
-    std::thread::sleep(std::time::Duration::from_millis(3000));
-
-    std::process::exit(0);
+    // Plays for --duration, 3 s by default
+    let stop = StopFlag::new();
+    let timer = stop.clone();
+    let duration = settings.get_duration();
+    std::thread::spawn(move || {
+        std::thread::sleep(duration);
+        timer.stop();
+    });
+    sine.play(receiver, buffer_size, &*backend, &stop);
 }
@@ -1,24 +1,86 @@
-use mdns_sd::{ServiceDaemon, ServiceInfo, ServiceEvent};
+pub mod name;
+pub mod peer;
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo, ServiceEvent};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use hostname;
-use log::debug;
+use log::{debug, info, warn};
+use crate::group;
+use crate::network::InterfaceSelection;
 
-pub struct MdnsService {
+pub use peer::{Peer, PeerTable, Role};
+
+// How long to browse before picking an instance label, long enough for the
+// services already on the network to resolve
+pub const PROBE_TIME: Duration = Duration::from_secs(2);
+
+// A peer appearing on or leaving the network
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Joined(Peer),
+    Left(Peer),
+}
+
+// The instance part of a full service name, "alice" in
+// "alice._udp_voice._udp.local."
+pub fn instance_name(fullname: &str) -> &str {
+    fullname.split('.').next().unwrap_or(fullname)
+}
+
+// What this host announces, shared with the thread following address
+// changes
+struct Announcement {
     daemon: ServiceDaemon,
     service_type: String,
     host_name: String,
-    properties: Vec<(&'static str, &'static str)>,
-    user_table: Arc<Mutex<HashMap<String, String>>>,
+    properties: Mutex<Vec<(String, String)>>,
+    registration: Mutex<Option<(String, Vec<IpAddr>, u16)>>,
+}
+
+impl Announcement {
+    fn register(&self, instance_name: &str, addresses: &[IpAddr], port: u16) -> Result<(), mdns_sd::Error> {
+        let service_info = ServiceInfo::new(
+            &self.service_type,
+            instance_name,
+            &self.host_name,
+            addresses,
+            port,
+            &self.properties.lock().unwrap()[..],
+            )?;
+        self.daemon.register(service_info)?;
+        *self.registration.lock().unwrap() = Some((instance_name.to_string(), addresses.to_vec(), port));
+        debug!("mDNS: Service registered: {} at {:?}", instance_name, addresses);
+        Ok(())
+    }
+    // Registers again as it is, picking up changed properties or addresses
+    fn reregister(&self, addresses: Option<&[IpAddr]>) {
+        let registration = self.registration.lock().unwrap().clone();
+        let Some((instance_name, registered, port)) = registration else {
+            return;
+        };
+        if let Err(e) = self.register(&instance_name, addresses.unwrap_or(&registered), port) {
+            warn!("mDNS: Failed to update {}: {}", instance_name, e);
+        }
+    }
+}
+
+pub struct MdnsService {
+    announcement: Arc<Announcement>,
+    // The interfaces we announce and browse on
+    interfaces: InterfaceSelection,
+    peers: PeerTable,
+    subscribers: Arc<Mutex<Vec<Sender<PeerEvent>>>>,
 }
 
 impl MdnsService {
     pub fn new(
         service_type: &str, 
-        properties: Vec<(&'static str, &'static str)>) 
+        properties: Vec<(&str, &str)>) 
         -> Self {
             let daemon = ServiceDaemon::new().expect("mDNS: Failed to create Daemon");
             let host_name = hostname::get()
@@ -26,30 +88,95 @@ impl MdnsService {
                 .to_str()
                 .expect("mDNS: Unable to convert host name to string")
                 .to_owned() + ".local.";
-            MdnsService {
+            let announcement = Announcement {
                 daemon,
                 service_type: service_type.to_string(),
                 host_name,
-                properties,
-                user_table: Arc::new(Mutex::new(HashMap::new())),
+                properties: Mutex::new(properties
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect()),
+                registration: Mutex::new(None),
+            };
+            MdnsService {
+                announcement: Arc::new(announcement),
+                interfaces: InterfaceSelection::All,
+                peers: Arc::new(Mutex::new(HashMap::new())),
+                subscribers: Arc::new(Mutex::new(Vec::new())),
             }
         
     }
-    pub fn register_service(&self, instance_name: &str, ip: IpAddr, port: u16) {
-        let service_info = ServiceInfo::new(
-            &self.service_type,
-            instance_name,
-            &self.host_name,
-            ip,
-            port,
-            &self.properties[..],
-            ).unwrap();
-        self.daemon.register(service_info).expect("mDNS: Failed to register service");
-        debug!("mDNS: Service registered: {}", instance_name);
+    // Announces and browses on the chosen interfaces only. Call before
+    // browse_services.
+    pub fn use_interfaces(&mut self, selection: &InterfaceSelection) {
+        if let InterfaceSelection::Named(names) = selection {
+            let daemon = &self.announcement.daemon;
+            let enabled: Vec<IfKind> = names.iter().map(IfKind::from).collect();
+            if let Err(e) = daemon.disable_interface(IfKind::All).and_then(|_| daemon.enable_interface(enabled)) {
+                warn!("mDNS: Failed to limit the daemon to {}: {}", selection, e);
+            }
+        }
+        self.interfaces = selection.clone();
+    }
+    // Advertises every one of `addresses`, IPv4 and IPv6, peers pick the
+    // ones they can reach
+    pub fn register_service(&self, instance_name: &str, addresses: &[IpAddr], port: u16) {
+        self.announcement
+            .register(instance_name, addresses, port)
+            .expect("mDNS: Failed to register service");
+    }
+    // `label`, or the first free one with a suffix, going by the services
+    // browsing turned up within `wait`. Browse before calling. Two peers
+    // probing at the same moment can still pick the same label.
+    pub fn probe_label(&self, label: &str, wait: Duration) -> String {
+        thread::sleep(wait);
+        let taken: Vec<String> = self.peers
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|fullname| instance_name(fullname).to_string())
+            .collect();
+        let unique = name::unique_label(label, &taken);
+        if unique != label {
+            info!("mDNS: {} is taken, registering as {}", label, unique);
+        }
+        unique
+    }
+    // Registers again under the same name with new addresses, as when an
+    // interface got a new one
+    pub fn update_addresses(&self, addresses: &[IpAddr]) {
+        self.announcement.reregister(Some(addresses));
+    }
+    // Updates the addresses from every change `changes` reports, see
+    // network::watch_addresses
+    pub fn follow_addresses(&self, changes: Receiver<Vec<IpAddr>>) {
+        let announcement = Arc::clone(&self.announcement);
+        thread::spawn(move || {
+            for addresses in changes {
+                info!("mDNS: Re-registering at {:?}", addresses);
+                announcement.reregister(Some(&addresses));
+            }
+        });
+    }
+    // Changes a TXT property, re-announcing the service if it is registered
+    pub fn set_property(&self, key: &str, value: &str) {
+        {
+            let mut properties = self.announcement.properties.lock().unwrap();
+            match properties.iter_mut().find(|(k, _)| k == key) {
+                Some(property) => property.1 = value.to_string(),
+                None => properties.push((key.to_string(), value.to_string())),
+            }
+        }
+        self.announcement.reregister(None);
     }
+    // Keeps the peer table in step with the network. A peer is dropped when
+    // it says goodbye, or when the daemon lets its records expire because
+    // nobody refreshed them; either way the daemon reports it removed.
     pub fn browse_services(&self) {
-        let receiver = self.daemon.browse(&self.service_type).expect("Failed to browse");
-        let user_table = self.user_table.clone();
+        let receiver = self.announcement.daemon.browse(&self.announcement.service_type).expect("Failed to browse");
+        let peers = self.peers.clone();
+        let subscribers = self.subscribers.clone();
+        let scope_id = self.interfaces.link_local_scope();
 
         thread::spawn( move || {
             loop {
@@ -58,23 +185,29 @@ impl MdnsService {
                     match event {
                         ServiceEvent::ServiceResolved(info) => {
                             debug!("mDNS: Service Resolved: {:?}", info);
-                            let addresses = info.get_addresses_v4();
-                            debug!("mDNS: Addresses found: {:?}", addresses);
-                            for address in addresses {
-                                debug!("mDNS: Found User in IP Address: {:?}", address);
-                                user_table.lock().unwrap().insert(info.get_fullname().to_string(), address.to_string());
-                                debug!("mDNS: Inserted New User into User Table: {:?}", info.get_fullname());
-                                let mut username = String::new();
-                                for char in info.get_fullname().chars() {
-                                    if char != '.' {
-                                        username.push(char);
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                debug!("{} just connected", username);
+                            // A peer resolved again, because it changed or
+                            // came back, replaces the old record and so
+                            // counts as seen now
+                            let peer = Peer::from_service_info(&info, scope_id);
+                            debug!("mDNS: {} is in groups {}", peer.fullname, group::format_groups(&peer.groups));
+                            let previous = peers.lock().unwrap().insert(peer.fullname.clone(), peer.clone());
+                            if previous.is_none() {
+                                info!("mDNS: {} joined", peer);
+                                publish(&subscribers, PeerEvent::Joined(peer));
                             }
                         },
+                        ServiceEvent::ServiceFound(_, fullname) => {
+                            if let Some(peer) = peers.lock().unwrap().get_mut(&fullname) {
+                                peer.last_seen = Instant::now();
+                            }
+                        },
+                        ServiceEvent::ServiceRemoved(_, fullname) => {
+                            let Some(peer) = peers.lock().unwrap().remove(&fullname) else {
+                                continue;
+                            };
+                            info!("mDNS: {} left", peer.display_name);
+                            publish(&subscribers, PeerEvent::Left(peer));
+                        },
                         _ => {}
                     }
                 }
@@ -82,7 +215,46 @@ impl MdnsService {
             }
         });
     }
-    pub fn get_user_table(&self) -> Arc<Mutex<HashMap<String, String>>> {
-        Arc::clone(&self.user_table)
+    // Every peer joining or leaving from now on. Peers already in the table
+    // are not announced again.
+    pub fn subscribe(&self) -> Receiver<PeerEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+    // The peers on the network now, by name
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.peers.lock().unwrap().values().cloned().collect();
+        peers.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        peers
+    }
+    // Says goodbye on the network so peers drop us right away instead of
+    // waiting for the record to expire
+    pub fn unregister_service(&self) {
+        let Some((instance_name, _, _)) = self.announcement.registration.lock().unwrap().take() else {
+            return;
+        };
+        let fullname = format!("{}.{}", instance_name, self.announcement.service_type);
+        match self.announcement.daemon.unregister(&fullname) {
+            Ok(status) => {
+                let _ = status.recv_timeout(Duration::from_secs(1));
+                debug!("mDNS: Service unregistered: {}", instance_name);
+            }
+            Err(e) => debug!("mDNS: Failed to unregister {}: {}", instance_name, e),
+        }
+    }
+    pub fn get_peer_table(&self) -> PeerTable {
+        Arc::clone(&self.peers)
+    }
+}
+// Subscribers that hung up are dropped
+fn publish(subscribers: &Mutex<Vec<Sender<PeerEvent>>>, event: PeerEvent) {
+    subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
+impl Drop for MdnsService {
+    fn drop(&mut self) {
+        self.unregister_service();
+        let _ = self.announcement.daemon.shutdown();
     }
 }
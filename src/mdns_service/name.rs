@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::fmt;
+
+// A user picks a display name, which travels in the TXT record under
+// peer::DISPLAY_NAME_PROPERTY. The instance label in the service name is
+// derived from it and kept DNS-safe: lower case letters, digits and hyphens.
+// "Boom Box" registers as boom-box._udp_voice._udp.local., a second one as
+// boom-box-2.
+
+pub const MAX_NAME_LENGTH: usize = 32;
+// Longest DNS label, suffix included
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    TooLong(usize),
+    InvalidCharacter(char),
+    // Only spaces, hyphens and underscores, nothing to build a label from
+    NoLetters,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "The name is empty"),
+            NameError::TooLong(length) => {
+                write!(f, "The name is {} characters long, at most {} are allowed", length, MAX_NAME_LENGTH)
+            }
+            NameError::InvalidCharacter(c) => {
+                write!(f, "'{}' is not allowed, use letters, digits, spaces, hyphens and underscores", c)
+            }
+            NameError::NoLetters => write!(f, "The name needs at least one letter or digit"),
+        }
+    }
+}
+
+impl Error for NameError {}
+
+// The name as typed, trimmed, if it is fit to show to others
+pub fn validate_display_name(name: &str) -> Result<String, NameError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    let length = name.chars().count();
+    if length > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong(length));
+    }
+    if let Some(c) = name.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_'))) {
+        return Err(NameError::InvalidCharacter(c));
+    }
+    if !name.chars().any(|c| c.is_ascii_alphanumeric()) {
+        return Err(NameError::NoLetters);
+    }
+    Ok(name.to_string())
+}
+
+// The DNS-safe label for a display name. Runs of anything but letters and
+// digits become one hyphen, none at either end, and the label is cut to
+// MAX_LABEL_LENGTH.
+pub fn instance_label(display_name: &str) -> String {
+    let mut label = String::with_capacity(display_name.len());
+    for c in display_name.chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c.to_ascii_lowercase());
+        } else if !label.is_empty() && !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    fit(&label, MAX_LABEL_LENGTH).to_string()
+}
+
+// `label` cut to at most `length` bytes, without a trailing hyphen. Labels
+// are ASCII, so any byte is a character boundary.
+fn fit(label: &str, length: usize) -> &str {
+    label[..label.len().min(length)].trim_end_matches('-')
+}
+
+// `label` if nobody has it, otherwise the first of label-2, label-3 and so
+// on that is free. Labels compare without regard to case, like DNS names.
+pub fn unique_label(label: &str, taken: &[String]) -> String {
+    let is_taken = |candidate: &str| taken.iter().any(|name| name.eq_ignore_ascii_case(candidate));
+    if !is_taken(label) {
+        return label.to_string();
+    }
+    (2..)
+        .map(|suffix: u32| {
+            let suffix = format!("-{}", suffix);
+            format!("{}{}", fit(label, MAX_LABEL_LENGTH - suffix.len()), suffix)
+        })
+        .find(|candidate| !is_taken(candidate))
+        .expect("Ran out of suffixes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_names_are_rejected() {
+        assert_eq!(validate_display_name(""), Err(NameError::Empty));
+        assert_eq!(validate_display_name("  \t "), Err(NameError::Empty));
+        assert_eq!(validate_display_name("  Boom Box \n"), Ok("Boom Box".to_string()));
+    }
+
+    #[test]
+    fn long_names_are_rejected() {
+        let longest = "a".repeat(MAX_NAME_LENGTH);
+        assert_eq!(validate_display_name(&longest), Ok(longest.clone()));
+        assert_eq!(validate_display_name(&format!("{}b", longest)), Err(NameError::TooLong(MAX_NAME_LENGTH + 1)));
+    }
+
+    #[test]
+    fn disallowed_characters_are_rejected() {
+        assert_eq!(validate_display_name("boom.box"), Err(NameError::InvalidCharacter('.')));
+        assert_eq!(validate_display_name("bööm"), Err(NameError::InvalidCharacter('ö')));
+        assert_eq!(validate_display_name("a/b"), Err(NameError::InvalidCharacter('/')));
+        assert_eq!(validate_display_name("- _ -"), Err(NameError::NoLetters));
+    }
+
+    #[test]
+    fn labels_are_dns_safe() {
+        assert_eq!(instance_label("Boom Box"), "boom-box");
+        assert_eq!(instance_label("boom  __ -box"), "boom-box");
+        assert_eq!(instance_label("-_ Boom _-"), "boom");
+        assert_eq!(instance_label("Cam 2"), "cam-2");
+        assert_eq!(instance_label("___"), "");
+    }
+
+    #[test]
+    fn labels_fit_in_a_dns_label() {
+        let label = instance_label(&"Ab".repeat(50));
+        assert_eq!(label.len(), MAX_LABEL_LENGTH);
+        // A cut landing on a separator leaves no trailing hyphen
+        let label = instance_label(&format!("{} tail", "a".repeat(MAX_LABEL_LENGTH - 1)));
+        assert_eq!(label, "a".repeat(MAX_LABEL_LENGTH - 1));
+
+        let full = "a".repeat(MAX_LABEL_LENGTH);
+        let unique = unique_label(&full, std::slice::from_ref(&full));
+        assert_eq!(unique.len(), MAX_LABEL_LENGTH);
+        assert!(unique.ends_with("a-2"), "{}", unique);
+    }
+
+    #[test]
+    fn collisions_get_suffixes_whatever_the_case() {
+        assert_eq!(unique_label("udp-server", &[]), "udp-server");
+        let taken = vec!["UDP-Server".to_string()];
+        assert_eq!(unique_label("udp-server", &taken), "udp-server-2");
+        let taken = vec!["udp-server".to_string(), "udp-server-2".to_string(), "Udp-Server-3".to_string()];
+        assert_eq!(unique_label("udp-server", &taken), "udp-server-4");
+        // A gap is reused
+        let taken = vec!["udp-server".to_string(), "udp-server-3".to_string()];
+        assert_eq!(unique_label("udp-server", &taken), "udp-server-2");
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use mdns_sd::ServiceInfo;
+use crate::group::{self, TalkGroup};
+use crate::mdns_service::instance_name;
+use crate::network::{self, InterfaceSelection};
+use crate::rtp::Framing;
+
+// TXT keys a peer describes itself with, next to group::GROUPS_PROPERTY
+pub const ROLE_PROPERTY: &str = "interface";
+pub const PROTOCOL_PROPERTY: &str = "protocol";
+pub const CAPABILITIES_PROPERTY: &str = "capabilities";
+pub const DISPLAY_NAME_PROPERTY: &str = "name";
+// Besides the framing, "native" or "rtp"
+pub const ENCRYPTED_CAPABILITY: &str = "encrypted";
+
+// Peers by full service name
+pub type PeerTable = Arc<Mutex<HashMap<String, Peer>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+    // Advertised something else, or nothing
+    Unknown,
+}
+
+impl Role {
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("client") => Role::Client,
+            Some("server") => Role::Server,
+            _ => Role::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Client => write!(f, "client"),
+            Role::Server => write!(f, "server"),
+            Role::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// Everything a peer advertises over mDNS
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub fullname: String,
+    pub display_name: String,
+    // Every address it resolved to on its port, best first (see
+    // network::preference). Link-local IPv6 ones carry a scope id, and are
+    // left out when there is no interface to scope them to.
+    pub addresses: Vec<SocketAddr>,
+    pub port: u16,
+    pub role: Role,
+    // The wire protocol it speaks, None if it doesn't say
+    pub protocol_version: Option<u8>,
+    // Such as "native", "rtp" or "encrypted"
+    pub capabilities: BTreeSet<String>,
+    pub groups: BTreeSet<TalkGroup>,
+    // When mDNS last resolved or updated it, or traffic last came from it.
+    // Records that are merely refreshed unchanged don't count, the daemon
+    // doesn't report those.
+    pub last_seen: Instant,
+}
+
+impl Peer {
+    // A peer known without mDNS, in the `all` group and claiming nothing
+    pub fn new(fullname: &str, role: Role, addresses: Vec<IpAddr>, port: u16) -> Self {
+        Self {
+            fullname: fullname.to_string(),
+            display_name: instance_name(fullname).to_string(),
+            addresses: socket_addresses(addresses, port, InterfaceSelection::All.link_local_scope()),
+            port,
+            role,
+            protocol_version: None,
+            capabilities: BTreeSet::new(),
+            groups: BTreeSet::from([TalkGroup::all()]),
+            last_seen: Instant::now(),
+        }
+    }
+
+    // Link-local IPv6 addresses get `scope_id`, the interface they are
+    // taken to be on
+    pub fn from_service_info(info: &ServiceInfo, scope_id: Option<u32>) -> Self {
+        let addresses = socket_addresses(info.get_addresses().iter().copied().collect(), info.get_port(), scope_id);
+        let display_name = match info.get_property_val_str(DISPLAY_NAME_PROPERTY) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => instance_name(info.get_fullname()).to_string(),
+        };
+        Self {
+            fullname: info.get_fullname().to_string(),
+            display_name,
+            addresses,
+            port: info.get_port(),
+            role: Role::parse(info.get_property_val_str(ROLE_PROPERTY)),
+            protocol_version: info.get_property_val_str(PROTOCOL_PROPERTY).and_then(|value| value.parse().ok()),
+            capabilities: parse_capabilities(info.get_property_val_str(CAPABILITIES_PROPERTY)),
+            groups: group::parse_groups(info.get_property_val_str(group::GROUPS_PROPERTY)),
+            last_seen: Instant::now(),
+        }
+    }
+
+    // Whether traffic from `source` came from this peer, going by address
+    // alone since the port it sends from isn't the one it advertises
+    pub fn sent(&self, source: SocketAddr) -> bool {
+        let source = source.ip().to_canonical();
+        self.addresses.iter().any(|address| address.ip().to_canonical() == source)
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    // The best of its addresses a socket bound on `local` can send to
+    pub fn destination(&self, local: SocketAddr) -> Option<SocketAddr> {
+        self.addresses.iter().find_map(|address| network::reachable(local, *address))
+    }
+}
+
+fn socket_addresses(addresses: Vec<IpAddr>, port: u16, scope_id: Option<u32>) -> Vec<SocketAddr> {
+    let mut addresses: Vec<SocketAddr> = addresses
+        .into_iter()
+        .filter_map(|address| network::scoped(address, port, scope_id))
+        .collect();
+    addresses.sort_by_key(|address| (network::preference(address), *address));
+    addresses
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addresses: Vec<String> = self.addresses.iter().map(SocketAddr::to_string).collect();
+        write!(f, "{} ({}) at {}", self.display_name, self.role, addresses.join(", "))?;
+        if let Some(version) = self.protocol_version {
+            write!(f, ", protocol {}", version)?;
+        }
+        if !self.capabilities.is_empty() {
+            write!(f, ", {}", format_capabilities(&self.capabilities))?;
+        }
+        Ok(())
+    }
+}
+
+// Marks the peers `source` belongs to as seen now
+pub fn touch(peers: &PeerTable, source: SocketAddr) {
+    let now = Instant::now();
+    for peer in peers.lock().unwrap().values_mut().filter(|peer| peer.sent(source)) {
+        peer.last_seen = now;
+    }
+}
+
+// What a peer using `framing`, with or without a crew key, advertises
+pub fn capabilities(framing: Framing, encrypted: bool) -> BTreeSet<String> {
+    let mut capabilities = BTreeSet::from([framing.to_string()]);
+    if encrypted {
+        capabilities.insert(ENCRYPTED_CAPABILITY.to_string());
+    }
+    capabilities
+}
+
+pub fn parse_capabilities(value: Option<&str>) -> BTreeSet<String> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|capability| !capability.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn format_capabilities(capabilities: &BTreeSet<String>) -> String {
+    capabilities.iter().map(String::as_str).collect::<Vec<&str>>().join(",")
+}
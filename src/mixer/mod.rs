@@ -0,0 +1,53 @@
+// Level above which the soft clipper starts bending the signal
+const CLIP_KNEE: f32 = 0.9;
+
+// Sums the decoded audio of everyone talking at once. Each input is an
+// interleaved block; shorter blocks are treated as ending in silence.
+// The sum is scaled by 1/sqrt(talkers), so two people at once don't double
+// the level, and whatever still exceeds full scale is soft clipped instead
+// of wrapping or hard clipping.
+pub struct Mixer {
+    gain: f32,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self { gain: 1.0 }
+    }
+
+    pub fn mix(&mut self, inputs: &[Vec<f32>]) -> Vec<f32> {
+        let len = inputs.iter().map(Vec::len).max().unwrap_or(0);
+        if len == 0 {
+            return Vec::new();
+        }
+        let target_gain = 1.0 / (inputs.len() as f32).sqrt();
+
+        // Ramp from the previous gain across the block so talkers joining or
+        // leaving don't cause a step in level.
+        let mut mixed = Vec::with_capacity(len);
+        for i in 0..len {
+            let sum: f32 = inputs.iter().filter_map(|input| input.get(i)).sum();
+            let gain = self.gain + (target_gain - self.gain) * (i as f32 / len as f32);
+            mixed.push(soft_clip(sum * gain));
+        }
+        self.gain = target_gain;
+        mixed
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Linear up to the knee, then eases into full scale without ever reaching past it
+pub fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= CLIP_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - CLIP_KNEE;
+    let clipped = CLIP_KNEE + headroom * ((magnitude - CLIP_KNEE) / headroom).tanh();
+    clipped.copysign(sample)
+}
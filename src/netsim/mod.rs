@@ -0,0 +1,366 @@
+pub mod socket;
+pub mod proxy;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::settings;
+
+pub use socket::SimSocket;
+pub use proxy::Proxy;
+
+// Reproducible bad networks. An Impairment describes the conditions, a
+// Simulator decides the fate of each packet under them, and SimSocket and
+// Proxy apply it to real datagrams: SimSocket wraps a socket in process,
+// Proxy sits between a client and a server as the netsim binary.
+//
+// Every random choice comes from a seeded generator, so the same seed and
+// the same packets give the same losses, delays and duplicates.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LossModel {
+    None,
+    // Every packet is lost with the same probability
+    Random { probability: f64 },
+    // Gilbert–Elliott: a two-state chain that moves to the bad state with
+    // probability `to_bad` and back with `to_good` per packet. Losses come
+    // in bursts while it is in the bad state.
+    GilbertElliott { to_bad: f64, to_good: f64, good_loss: f64, bad_loss: f64 },
+}
+
+impl fmt::Display for LossModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LossModel::None => write!(f, "no loss"),
+            LossModel::Random { probability } => write!(f, "{:.1}% random loss", probability * 100.0),
+            LossModel::GilbertElliott { to_bad, to_good, good_loss, bad_loss } => write!(
+                f,
+                "bursty loss ({:.1}% to bad, {:.1}% to good, {:.1}%/{:.1}% lost)",
+                to_bad * 100.0, to_good * 100.0, good_loss * 100.0, bad_loss * 100.0
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairment {
+    pub loss: LossModel,
+    pub delay: Duration,
+    // Each packet's delay varies uniformly by up to this much either way.
+    // Enough of it reorders packets by itself.
+    pub jitter: Duration,
+    // Chance of a packet arriving twice
+    pub duplicate: f64,
+    // Chance of a packet skipping the delay, overtaking those queued ahead
+    // of it. Has no effect without delay.
+    pub reorder: f64,
+    pub seed: u64,
+}
+
+impl Default for Impairment {
+    fn default() -> Self {
+        Self {
+            loss: LossModel::None,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            duplicate: 0.0,
+            reorder: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} ms ± {} ms delay, {:.1}% duplicated, {:.1}% reordered, seed {}",
+            self.loss,
+            self.delay.as_millis(),
+            self.jitter.as_millis(),
+            self.duplicate * 100.0,
+            self.reorder * 100.0,
+            self.seed
+        )
+    }
+}
+
+impl Impairment {
+    // Named conditions to tune against:
+    // - lan: a quiet wired network
+    // - wifi: a shared access point, short loss bursts and a few ms of jitter
+    // - congested: a busy set network, long bursts, heavy jitter, reordering
+    pub fn preset(name: &str) -> Result<Self, String> {
+        let ms = Duration::from_millis;
+        match name {
+            "lan" => Ok(Self { delay: ms(1), ..Self::default() }),
+            "wifi" => Ok(Self {
+                loss: LossModel::GilbertElliott { to_bad: 0.01, to_good: 0.3, good_loss: 0.0, bad_loss: 0.5 },
+                delay: ms(5),
+                jitter: ms(10),
+                ..Self::default()
+            }),
+            "congested" => Ok(Self {
+                loss: LossModel::GilbertElliott { to_bad: 0.03, to_good: 0.2, good_loss: 0.01, bad_loss: 0.8 },
+                delay: ms(40),
+                jitter: ms(30),
+                duplicate: 0.01,
+                reorder: 0.02,
+                ..Self::default()
+            }),
+            other => Err(format!("Unknown network preset '{}', expected lan, wifi or congested", other)),
+        }
+    }
+
+    // Starts from `--preset <name>` (UDP_VOICE_NETSIM_PRESET) or a perfect
+    // network, then applies any of these on top:
+    //     --loss <percent>                       random loss
+    //     --burst-loss <to bad>,<to good>[,<good loss>,<bad loss>]
+    //                                            Gilbert–Elliott, in percent;
+    //                                            everything is lost while bad
+    //                                            unless the losses are given
+    //     --delay <ms>  --jitter <ms>
+    //     --duplicate <percent>  --reorder <percent>
+    //     --seed <n>
+    // each also as UDP_VOICE_NETSIM_<FLAG>, e.g. UDP_VOICE_NETSIM_BURST_LOSS.
+    pub fn from_args() -> Result<Self, String> {
+        let mut impairment = match arg_value("--preset") {
+            Some(name) => Self::preset(&name)?,
+            None => Self::default(),
+        };
+        if let Some(value) = arg_value("--loss") {
+            impairment.loss = LossModel::Random { probability: parse_percent("--loss", &value)? };
+        }
+        if let Some(value) = arg_value("--burst-loss") {
+            impairment.loss = parse_burst_loss(&value)?;
+        }
+        if let Some(value) = arg_value("--delay") {
+            impairment.delay = parse_millis("--delay", &value)?;
+        }
+        if let Some(value) = arg_value("--jitter") {
+            impairment.jitter = parse_millis("--jitter", &value)?;
+        }
+        if let Some(value) = arg_value("--duplicate") {
+            impairment.duplicate = parse_percent("--duplicate", &value)?;
+        }
+        if let Some(value) = arg_value("--reorder") {
+            impairment.reorder = parse_percent("--reorder", &value)?;
+        }
+        if let Some(value) = arg_value("--seed") {
+            impairment.seed = value.parse().map_err(|_| format!("Invalid --seed '{}'", value))?;
+        }
+        Ok(impairment)
+    }
+}
+
+fn parse_percent(flag: &str, value: &str) -> Result<f64, String> {
+    match value.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent / 100.0),
+        _ => Err(format!("Invalid {} '{}', expected a percentage from 0 to 100", flag, value)),
+    }
+}
+
+fn parse_millis(flag: &str, value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("Invalid {} '{}', expected milliseconds", flag, value))
+}
+
+fn parse_burst_loss(value: &str) -> Result<LossModel, String> {
+    let parts = value
+        .split(',')
+        .map(|part| parse_percent("--burst-loss", part.trim()))
+        .collect::<Result<Vec<f64>, String>>()?;
+    match parts[..] {
+        [to_bad, to_good] => Ok(LossModel::GilbertElliott { to_bad, to_good, good_loss: 0.0, bad_loss: 1.0 }),
+        [to_bad, to_good, good_loss, bad_loss] => Ok(LossModel::GilbertElliott { to_bad, to_good, good_loss, bad_loss }),
+        _ => Err(format!("Invalid --burst-loss '{}', expected 2 or 4 comma separated percentages", value)),
+    }
+}
+
+fn arg_value(flag: &str) -> Option<String> {
+    let variable = format!("UDP_VOICE_NETSIM_{}", flag.trim_start_matches('-').replace('-', "_").to_uppercase());
+    settings::flag_or_env(flag, &variable)
+}
+
+// What happened to the packets that went through a simulator
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SimStats {
+    pub packets: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+impl fmt::Display for SimStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packets, {} lost, {} duplicated, {} reordered",
+            self.packets, self.lost, self.duplicated, self.reordered
+        )
+    }
+}
+
+pub struct Simulator {
+    impairment: Impairment,
+    rng: StdRng,
+    // Gilbert–Elliott state
+    bad: bool,
+    stats: SimStats,
+}
+
+impl Simulator {
+    pub fn new(impairment: Impairment) -> Self {
+        Self {
+            impairment,
+            rng: StdRng::seed_from_u64(impairment.seed),
+            bad: false,
+            stats: SimStats::default(),
+        }
+    }
+
+    // When the copies of a packet sent at `now` arrive: none if it is lost,
+    // two if it is duplicated
+    pub fn schedule(&mut self, now: Instant) -> Vec<Instant> {
+        self.stats.packets += 1;
+        if self.lose() {
+            self.stats.lost += 1;
+            return Vec::new();
+        }
+        let mut arrivals = vec![now + self.delay()];
+        if self.chance(self.impairment.duplicate) {
+            self.stats.duplicated += 1;
+            arrivals.push(now + self.delay());
+        }
+        arrivals
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.stats
+    }
+
+    fn lose(&mut self) -> bool {
+        match self.impairment.loss {
+            LossModel::None => false,
+            LossModel::Random { probability } => self.chance(probability),
+            LossModel::GilbertElliott { to_bad, to_good, good_loss, bad_loss } => {
+                let switch = if self.bad { to_good } else { to_bad };
+                if self.chance(switch) {
+                    self.bad = !self.bad;
+                }
+                self.chance(if self.bad { bad_loss } else { good_loss })
+            }
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        if self.chance(self.impairment.reorder) {
+            self.stats.reordered += 1;
+            return Duration::ZERO;
+        }
+        let jitter = self.impairment.jitter.as_secs_f64();
+        let offset = if jitter > 0.0 { self.rng.gen_range(-jitter..=jitter) } else { 0.0 };
+        Duration::from_secs_f64((self.impairment.delay.as_secs_f64() + offset).max(0.0))
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability.min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKETS: usize = 20_000;
+
+    // Runs `impairment` over PACKETS packets all sent at `now`
+    fn run(impairment: Impairment, now: Instant) -> (Vec<Vec<Instant>>, SimStats) {
+        let mut simulator = Simulator::new(impairment);
+        let schedule = (0..PACKETS).map(|_| simulator.schedule(now)).collect();
+        (schedule, simulator.stats())
+    }
+
+    fn loss_rate(loss: LossModel) -> f64 {
+        let (_, stats) = run(Impairment { loss, seed: 3, ..Impairment::default() }, Instant::now());
+        stats.lost as f64 / stats.packets as f64
+    }
+
+    #[test]
+    fn same_seed_same_schedule() {
+        let impairment = Impairment { seed: 11, ..Impairment::preset("congested").unwrap() };
+        let now = Instant::now();
+        let (first, first_stats) = run(impairment, now);
+        let (second, second_stats) = run(impairment, now);
+        assert_eq!(first, second);
+        assert_eq!(first_stats, second_stats);
+
+        let (other, _) = run(Impairment { seed: 12, ..impairment }, now);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn random_loss_hits_its_rate() {
+        let rate = loss_rate(LossModel::Random { probability: 0.1 });
+        assert!((rate - 0.1).abs() < 0.01, "Lost {:.3}", rate);
+        assert_eq!(loss_rate(LossModel::None), 0.0);
+    }
+
+    #[test]
+    fn gilbert_elliott_loss_hits_its_rate_in_bursts() {
+        let (to_bad, to_good, good_loss, bad_loss) = (0.05, 0.25, 0.01, 0.9);
+        let loss = LossModel::GilbertElliott { to_bad, to_good, good_loss, bad_loss };
+        // The chain spends to_bad / (to_bad + to_good) of the time bad
+        let bad = to_bad / (to_bad + to_good);
+        let expected = bad * bad_loss + (1.0 - bad) * good_loss;
+        let rate = loss_rate(loss);
+        assert!((rate - expected).abs() < 0.03, "Lost {:.3}, expected {:.3}", rate, expected);
+
+        // Losses cluster: far longer runs than random loss at the same rate
+        let (schedule, _) = run(Impairment { loss, seed: 3, ..Impairment::default() }, Instant::now());
+        let lost: Vec<bool> = schedule.iter().map(Vec::is_empty).collect();
+        let runs = lost.windows(2).filter(|pair| pair[1] && !pair[0]).count().max(1);
+        let mean_run = lost.iter().filter(|lost| **lost).count() as f64 / runs as f64;
+        assert!(mean_run > 2.0, "Mean loss run {:.2}", mean_run);
+    }
+
+    #[test]
+    fn duplicates_and_reorders_are_counted() {
+        let delay = Duration::from_millis(50);
+        let impairment = Impairment { delay, duplicate: 0.1, reorder: 0.05, seed: 5, ..Impairment::default() };
+        let now = Instant::now();
+        let (schedule, stats) = run(impairment, now);
+
+        let duplicated = schedule.iter().filter(|arrivals| arrivals.len() == 2).count() as u64;
+        // Reordered copies skip the delay, everything else takes exactly it
+        let reordered = schedule.iter().flatten().filter(|arrival| **arrival == now).count() as u64;
+        let copies = schedule.iter().flatten().count() as u64;
+        assert_eq!(stats.packets, PACKETS as u64);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.duplicated, duplicated);
+        assert_eq!(stats.reordered, reordered);
+        assert_eq!(copies, PACKETS as u64 + duplicated);
+        assert!((duplicated as f64 / PACKETS as f64 - 0.1).abs() < 0.01, "Duplicated {}", duplicated);
+        assert!((reordered as f64 / copies as f64 - 0.05).abs() < 0.01, "Reordered {}", reordered);
+    }
+
+    #[test]
+    fn bad_flags_are_rejected() {
+        assert_eq!(parse_percent("--loss", "5"), Ok(0.05));
+        assert_eq!(parse_percent("--loss", "5%"), Ok(0.05));
+        for bad in ["", "abc", "-1", "100.5", "NaN"] {
+            assert!(parse_percent("--loss", bad).is_err(), "Accepted '{}'", bad);
+        }
+
+        assert_eq!(
+            parse_burst_loss("1,30"),
+            Ok(LossModel::GilbertElliott { to_bad: 0.01, to_good: 0.3, good_loss: 0.0, bad_loss: 1.0 })
+        );
+        for bad in ["", "1", "1,2,3", "1,2,3,4,5", "1,x", "1,200"] {
+            assert!(parse_burst_loss(bad).is_err(), "Accepted '{}'", bad);
+        }
+    }
+}
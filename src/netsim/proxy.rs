@@ -0,0 +1,79 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use log::{debug, info};
+use crate::netsim::{Impairment, SimSocket, SimStats};
+use crate::session::{recv_until_stopped, Session, POLL_INTERVAL};
+
+// Large enough for any UDP payload, whatever protocol is being proxied
+const MAX_DATAGRAM: usize = 65536;
+
+// Forwards datagrams between a client and an upstream server, each
+// direction through its own simulated network. Whatever the upstream sends
+// back goes to the client heard from last, so RTCP reports find their way
+// too. Several clients can share a proxy, but the server then sees them all
+// as one talker.
+pub struct Proxy {
+    local: SocketAddr,
+    upstream_path: Arc<SimSocket>,
+    downstream_path: Arc<SimSocket>,
+    session: Session,
+}
+
+impl Proxy {
+    // The downstream direction gets the next seed, so the two don't lose
+    // the same packets
+    pub fn start(listen: SocketAddr, upstream: SocketAddr, impairment: Impairment) -> io::Result<Self> {
+        let socket = UdpSocket::bind(listen)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local = socket.local_addr()?;
+        let upstream_path = Arc::new(SimSocket::new(socket.try_clone()?, impairment)?);
+        let downstream = Impairment { seed: impairment.seed.wrapping_add(1), ..impairment };
+        let downstream_path = Arc::new(SimSocket::new(socket.try_clone()?, downstream)?);
+        info!("NETSIM: Proxying {} to {} with {}", local, upstream, impairment);
+
+        let mut session = Session::new("netsim");
+        let (to_upstream, to_client) = (Arc::clone(&upstream_path), Arc::clone(&downstream_path));
+        session.spawn("proxy", move |stop| {
+            let mut client: Option<SocketAddr> = None;
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            while let Some((amount, src)) = recv_until_stopped(&socket, &mut buf, &stop) {
+                let result = if src == upstream {
+                    match client {
+                        Some(client) => to_client.send_to(&buf[..amount], client),
+                        None => continue,
+                    }
+                } else {
+                    if client != Some(src) {
+                        info!("NETSIM: Forwarding for {}", src);
+                        client = Some(src);
+                    }
+                    to_upstream.send_to(&buf[..amount], upstream)
+                };
+                if let Err(e) = result {
+                    debug!("NETSIM: Failed to forward from {}: {}", src, e);
+                }
+            }
+        });
+        Ok(Self { local, upstream_path, downstream_path, session })
+    }
+
+    // Where clients should send to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    // Towards the upstream and back towards the client
+    pub fn stats(&self) -> (SimStats, SimStats) {
+        (self.upstream_path.stats(), self.downstream_path.stats())
+    }
+
+    pub fn stop(&mut self) {
+        self.session.stop();
+    }
+
+    // Forwards until stopped from elsewhere, which for the binary is never
+    pub fn join(&mut self) {
+        self.session.join();
+    }
+}
@@ -0,0 +1,140 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use log::warn;
+use crate::netsim::{Impairment, SimStats, Simulator};
+
+// How long the delay line sleeps when nothing is queued
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+// A datagram waiting for its arrival time. Ties keep the order they were
+// sent in.
+struct Delayed {
+    due: Instant,
+    order: u64,
+    destination: SocketAddr,
+    payload: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Delayed {}
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.order).cmp(&(other.due, other.order))
+    }
+}
+
+// A UDP socket whose outgoing datagrams cross a simulated network. Sending
+// never blocks: lost datagrams are dropped on the spot, the rest wait on a
+// delay line thread until they are due. Receiving is untouched.
+//
+// Dropping the socket sends whatever is still queued, on time, before the
+// delay line stops.
+pub struct SimSocket {
+    socket: UdpSocket,
+    simulator: Mutex<Simulator>,
+    queue: Option<Sender<Delayed>>,
+    order: AtomicU64,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SimSocket {
+    pub fn new(socket: UdpSocket, impairment: Impairment) -> io::Result<Self> {
+        let sender = socket.try_clone()?;
+        let (queue, delayed) = channel::<Delayed>();
+        let thread = std::thread::Builder::new()
+            .name("netsim delay line".to_string())
+            .spawn(move || {
+                let mut pending: BinaryHeap<Reverse<Delayed>> = BinaryHeap::new();
+                loop {
+                    let wait = match pending.peek() {
+                        Some(Reverse(next)) => next.due.saturating_duration_since(Instant::now()),
+                        None => IDLE_WAIT,
+                    };
+                    match delayed.recv_timeout(wait) {
+                        Ok(datagram) => pending.push(Reverse(datagram)),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        // Let the rest arrive as planned, then stop
+                        Err(RecvTimeoutError::Disconnected) if pending.is_empty() => break,
+                        Err(RecvTimeoutError::Disconnected) => std::thread::sleep(wait),
+                    }
+                    let now = Instant::now();
+                    while pending.peek().is_some_and(|Reverse(next)| next.due <= now) {
+                        let Reverse(datagram) = pending.pop().unwrap();
+                        if let Err(e) = sender.send_to(&datagram.payload, datagram.destination) {
+                            warn!("NETSIM: Failed to send to {}: {}", datagram.destination, e);
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            socket,
+            simulator: Mutex::new(Simulator::new(impairment)),
+            queue: Some(queue),
+            order: AtomicU64::new(0),
+            thread: Some(thread),
+        })
+    }
+
+    // Like UdpSocket::send_to. A lost datagram still counts as sent, the
+    // sender can't tell on a real network either.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let destination = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to"))?;
+        let arrivals = self.simulator.lock().unwrap().schedule(Instant::now());
+        let queue = self.queue.as_ref().expect("Delay line already stopped");
+        for due in arrivals {
+            let order = self.order.fetch_add(1, AtomicOrdering::Relaxed);
+            let datagram = Delayed { due, order, destination, payload: buf.to_vec() };
+            queue.send(datagram).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Delay line stopped"))?;
+        }
+        Ok(buf.len())
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    // The socket underneath, for reading and for anything not simulated
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.simulator.lock().unwrap().stats()
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
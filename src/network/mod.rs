@@ -0,0 +1,247 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+// Addressing shared by discovery and transport. Sockets bind dual-stack
+// where the host allows it, so one socket reaches peers over IPv4 and IPv6
+// alike; IPv4 peers then show up as IPv4-mapped IPv6 addresses
+// (::ffff:192.168.1.20).
+//
+// Link-local IPv6 addresses (fe80::/10) only mean something together with
+// the interface they were found on, the scope id. mDNS doesn't say which one
+// that was, so the first chosen interface holding a link-local address is
+// assumed.
+
+// How often watch_addresses looks for addresses that came or went
+pub const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Binds a UDP socket on `local`. An unspecified IPv6 address binds
+// dual-stack, falling back to IPv4 only on hosts without IPv6.
+pub fn bind_udp(local: SocketAddr) -> io::Result<UdpSocket> {
+    match local {
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => match bind_dual_stack(local) {
+            Ok(socket) => Ok(socket),
+            Err(e) => {
+                warn!("NETWORK: No IPv6 on this host ({}), binding IPv4 only", e);
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, local.port()))
+            }
+        },
+        _ => UdpSocket::bind(local),
+    }
+}
+
+fn bind_dual_stack(local: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&local.into())?;
+    Ok(socket.into())
+}
+
+// Every address on `port`, IPv6 and IPv4
+pub fn any_address(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+}
+
+// A network interface and the addresses it holds right now
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub index: Option<u32>,
+    pub addresses: Vec<IpAddr>,
+    pub is_loopback: bool,
+}
+
+// Every interface with an address, in the order the system lists them
+pub fn interfaces() -> Vec<Interface> {
+    let listed = match if_addrs::get_if_addrs() {
+        Ok(listed) => listed,
+        Err(e) => {
+            warn!("NETWORK: Unable to list interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut interfaces: Vec<Interface> = Vec::new();
+    for address in listed {
+        match interfaces.iter_mut().find(|interface| interface.name == address.name) {
+            Some(interface) => interface.addresses.push(address.ip()),
+            None => interfaces.push(Interface {
+                name: address.name.clone(),
+                index: address.index,
+                addresses: vec![address.ip()],
+                is_loopback: address.is_loopback(),
+            }),
+        }
+    }
+    interfaces
+}
+
+pub fn list_requested() -> bool {
+    std::env::args().any(|arg| arg == "--list-interfaces")
+}
+
+// Prints every interface with its addresses
+pub fn print_interfaces() {
+    let interfaces = interfaces();
+    if interfaces.is_empty() {
+        println!("No interfaces");
+    }
+    for interface in interfaces {
+        let marker = if interface.is_loopback { " (loopback)" } else { "" };
+        println!("{}{}", interface.name, marker);
+        for address in &interface.addresses {
+            println!("  {}", address);
+        }
+    }
+}
+
+// The interfaces a binary advertises itself on and receives from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum InterfaceSelection {
+    // Everything but loopback, receiving on the unspecified address
+    #[default]
+    All,
+    // These by name, receiving on their addresses only
+    Named(Vec<String>),
+}
+
+impl InterfaceSelection {
+    // "all", or interface names separated by commas
+    pub fn parse(value: &str) -> Self {
+        let names: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        if names.is_empty() || names.iter().any(|name| name == "all") {
+            return InterfaceSelection::All;
+        }
+        InterfaceSelection::Named(names)
+    }
+
+    pub fn includes(&self, interface: &Interface) -> bool {
+        match self {
+            InterfaceSelection::All => !interface.is_loopback,
+            InterfaceSelection::Named(names) => names.contains(&interface.name),
+        }
+    }
+
+    // The chosen interfaces present now. A named one that is down or gone
+    // is simply missing.
+    pub fn interfaces(&self) -> Vec<Interface> {
+        interfaces().into_iter().filter(|interface| self.includes(interface)).collect()
+    }
+
+    // The addresses worth advertising
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.interfaces().into_iter().flat_map(|interface| interface.addresses).collect()
+    }
+
+    // Where to receive on `port`: one socket on the unspecified address, or
+    // one per address of the chosen interfaces
+    pub fn bind_addresses(&self, port: u16) -> Vec<SocketAddr> {
+        match self {
+            InterfaceSelection::All => vec![any_address(port)],
+            InterfaceSelection::Named(_) => self
+                .interfaces()
+                .into_iter()
+                .flat_map(|interface| {
+                    let index = interface.index;
+                    interface.addresses.into_iter().filter_map(move |address| scoped(address, port, index))
+                })
+                .collect(),
+        }
+    }
+
+    // The interface link-local IPv6 peers are assumed to be on
+    pub fn link_local_scope(&self) -> Option<u32> {
+        self.interfaces()
+            .into_iter()
+            .filter(|interface| !interface.is_loopback)
+            .find(|interface| {
+                interface.addresses.iter().any(|address| matches!(address, IpAddr::V6(v6) if is_unicast_link_local(v6)))
+            })
+            .and_then(|interface| interface.index)
+    }
+}
+
+impl fmt::Display for InterfaceSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterfaceSelection::All => write!(f, "all"),
+            InterfaceSelection::Named(names) => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+// Reports the addresses of `selection` whenever they change, say after a
+// DHCP renew or a VPN coming up. Checks every ADDRESS_CHECK_INTERVAL and
+// stops once the receiver is dropped and the next change finds nobody
+// listening.
+pub fn watch_addresses(selection: InterfaceSelection) -> Receiver<Vec<IpAddr>> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        let mut current = selection.addresses();
+        loop {
+            std::thread::sleep(ADDRESS_CHECK_INTERVAL);
+            let addresses = selection.addresses();
+            if addresses == current {
+                continue;
+            }
+            info!("NETWORK: Addresses on {} changed to {:?}", selection, addresses);
+            if sender.send(addresses.clone()).is_err() {
+                return;
+            }
+            current = addresses;
+        }
+    });
+    receiver
+}
+
+pub fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+// `ip` on `port`, link-local IPv6 addresses carrying `scope_id`. Without a
+// scope a link-local address can't be used at all, so there is none.
+pub fn scoped(ip: IpAddr, port: u16, scope_id: Option<u32>) -> Option<SocketAddr> {
+    match ip {
+        IpAddr::V6(v6) if is_unicast_link_local(&v6) => {
+            scope_id.map(|scope_id| SocketAddr::V6(SocketAddrV6::new(v6, port, 0, scope_id)))
+        }
+        _ => Some(SocketAddr::new(ip, port)),
+    }
+}
+
+// How a socket bound on `local` addresses `destination`: IPv4 through an
+// IPv6 socket goes IPv4-mapped, IPv6 can't go through an IPv4 socket at all
+pub fn reachable(local: SocketAddr, destination: SocketAddr) -> Option<SocketAddr> {
+    match (local, destination) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            Some(SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()))
+        }
+        (SocketAddr::V4(_), SocketAddr::V6(v6)) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => Some(SocketAddr::new(IpAddr::V4(v4), v6.port())),
+            None => {
+                debug!("NETWORK: {} is out of reach of IPv4 socket {}", destination, local);
+                None
+            }
+        },
+        _ => Some(destination),
+    }
+}
+
+// Lower sorts first: routable IPv4, routable IPv6, then link-local IPv6 and
+// finally link-local IPv4, which only turns up when DHCP failed
+pub fn preference(address: &SocketAddr) -> u8 {
+    match address.ip() {
+        IpAddr::V4(v4) if v4.is_link_local() => 3,
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(v6) if is_unicast_link_local(&v6) => 2,
+        IpAddr::V6(_) => 1,
+    }
+}
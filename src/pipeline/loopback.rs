@@ -0,0 +1,224 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::info;
+use crate::backend::{AudioFormat, MemoryBackend};
+use crate::channel_map::{InputMap, OutputMap};
+use crate::group::{Membership, TalkGroup};
+use crate::mdns_service::{Peer, Role};
+use crate::netsim::{Impairment, Proxy, SimStats};
+use crate::pipeline::receive::{self, StreamFormat};
+use crate::pipeline::send::{self, Directory, Transport};
+use crate::rtp::Framing;
+use crate::session::{Session, POLL_INTERVAL};
+use crate::settings::{SettingsBuilder, DEFAULT_FRAMES_PER_PACKET};
+use crate::source::AudioSource;
+use crate::talk::{TalkGate, TalkSwitch, TransmitMode};
+
+// Both memory backends run at the codec rate so nothing is resampled
+const SAMPLE_RATE: u32 = 48000;
+// The SNR is measured over windows this long
+const SNR_WINDOW: Duration = Duration::from_millis(20);
+// How far a window may slide to line up with the sent tone. Longer than a
+// period of the tone, so playout gaps of any length are absorbed too.
+const SNR_SEARCH: Duration = Duration::from_millis(5);
+// Left out of the SNR at both ends of the tone, while the codec settles
+const SNR_GUARD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoopbackConfig {
+    // Above 200 Hz, see SNR_SEARCH
+    pub frequency: f32,
+    pub amplitude: f32,
+    // Silence before the tone, so its onset can be found on the far end
+    pub lead_in: Duration,
+    pub tone: Duration,
+    // How long to keep listening after the tone, at least the latency
+    pub tail: Duration,
+    pub framing: Framing,
+    // Milliseconds of audio per Opus frame
+    pub frame_duration: f32,
+    pub frames_per_packet: usize,
+    // Sends through a netsim proxy with these conditions instead of straight
+    // to the server
+    pub impairment: Option<Impairment>,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> Self {
+        Self {
+            frequency: 440.0,
+            amplitude: 0.5,
+            lead_in: Duration::from_millis(500),
+            tone: Duration::from_secs(2),
+            tail: Duration::from_secs(2),
+            framing: Framing::Native,
+            frame_duration: 20.0,
+            frames_per_packet: DEFAULT_FRAMES_PER_PACKET,
+            impairment: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopbackReport {
+    pub sent: Vec<f32>,
+    pub received: Vec<f32>,
+    // Audio carried by one packet, the unit the pipeline's latency comes in
+    pub packet_duration: Duration,
+    // From the tone starting at the client's input to it starting at the
+    // server's output. None if it never came out.
+    pub latency: Option<Duration>,
+    // Median SNR over the windows of the tone, each lined up on its own
+    pub snr_db: Option<f32>,
+    // What the proxy did to the packets, towards the server and back
+    pub network: Option<(SimStats, SimStats)>,
+}
+
+// Sends a tone burst from a client's send pipeline to a server's receive
+// pipeline over 127.0.0.1, with memory backends standing in for the sound
+// cards, and measures what the server plays. Runs in real time, for as
+// long as the burst plus the tail.
+pub fn run(config: &LoopbackConfig) -> Result<LoopbackReport, Box<dyn Error>> {
+    let format = AudioFormat { sample_rate: SAMPLE_RATE, channels: 1 };
+    let sent = tone_burst(config);
+    let client_backend = Arc::new(MemoryBackend::new(format, sent.clone()));
+    let server_backend = Arc::new(MemoryBackend::new(format, Vec::new()));
+    let settings = SettingsBuilder::new()
+        .audio_backend(client_backend.clone())
+        .frame_duration(config.frame_duration)
+        .frames_per_packet(config.frames_per_packet)
+        .build()?;
+
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    let server_port = socket.local_addr()?.port();
+    let stream_format = StreamFormat {
+        sample_rate: SAMPLE_RATE as f32,
+        channels: 1,
+        buffer_size: settings.get_buffer_size(),
+        framing: config.framing,
+    };
+    let mut server = Session::new("loopback server");
+    receive::start_receiving(
+        &mut server,
+        vec![socket],
+        server_backend.clone(),
+        OutputMap::Spread,
+        BTreeSet::from([TalkGroup::all()]),
+        None,
+        stream_format,
+    );
+
+    let mut proxy = match config.impairment {
+        Some(impairment) => {
+            let upstream = SocketAddr::from((Ipv4Addr::LOCALHOST, server_port));
+            Some(Proxy::start(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), upstream, impairment)?)
+        }
+        None => None,
+    };
+    let send_port = proxy.as_ref().map_or(server_port, |proxy| proxy.local_addr().port());
+
+    // A directory with only the server in it, instead of what mDNS finds
+    let directory = Directory {
+        peers: Arc::new(Mutex::new(HashMap::from([(
+            "loopback".to_string(),
+            Peer::new("loopback", Role::Server, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], send_port),
+        )]))),
+        membership: Arc::new(Mutex::new(Membership::new())),
+    };
+    let transport = Transport {
+        framing: config.framing,
+        frames_per_packet: settings.get_frames_per_packet(),
+        key: None,
+    };
+    let gate = TalkGate::new(TransmitMode::Continuous, TalkSwitch::new());
+    let local = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    info!("LOOPBACK: Sending {:?} of audio to port {}", duration(sent.len()), send_port);
+    let mut client = send::start_sending(&settings, &AudioSource::Microphone, InputMap::downmix(1), gate, local, directory, transport)?;
+
+    std::thread::sleep(duration(sent.len()) + config.tail);
+    client.stop();
+    if let Some(proxy) = proxy.as_mut() {
+        proxy.stop();
+    }
+    server.stop();
+
+    let played = server_backend.played();
+    let lead_in = samples(config.lead_in);
+    let onset = played.samples.iter().position(|sample| sample.abs() > config.amplitude / 2.0);
+    let latency = match (client_backend.capture_started(), played.started, onset) {
+        (Some(captured), Some(playing), Some(onset)) => {
+            let sent_at = captured + duration(lead_in);
+            let heard_at = playing + duration(onset);
+            Some(heard_at.saturating_duration_since(sent_at))
+        }
+        _ => None,
+    };
+    let snr_db = onset.and_then(|onset| segmental_snr(&sent[lead_in..], &played.samples[onset..]));
+    info!("LOOPBACK: Latency {:?}, SNR {:?} dB", latency, snr_db);
+
+    Ok(LoopbackReport {
+        sent,
+        received: played.samples,
+        packet_duration: stream_format.tick() * settings.get_frames_per_packet() as u32,
+        latency,
+        snr_db,
+        network: proxy.map(|proxy| proxy.stats()),
+    })
+}
+
+// Silence for the lead in, then the tone
+fn tone_burst(config: &LoopbackConfig) -> Vec<f32> {
+    let step = 2.0 * std::f32::consts::PI * config.frequency / SAMPLE_RATE as f32;
+    std::iter::repeat_n(0.0, samples(config.lead_in))
+        .chain((0..samples(config.tone)).map(|n| config.amplitude * (step * n as f32).sin()))
+        .collect()
+}
+
+// Median over SNR_WINDOW windows of `sent`, each compared with the stretch
+// of `received` within SNR_SEARCH of it that matches best
+fn segmental_snr(sent: &[f32], received: &[f32]) -> Option<f32> {
+    let window = samples(SNR_WINDOW);
+    let search = samples(SNR_SEARCH) as isize;
+    let guard = samples(SNR_GUARD);
+    let end = sent.len().saturating_sub(guard + window);
+    let mut snrs: Vec<f32> = (guard..end)
+        .step_by(window)
+        .filter_map(|start| {
+            let reference = &sent[start..start + window];
+            (-search..=search)
+                .filter_map(|lag| {
+                    let from = usize::try_from(start as isize + lag).ok()?;
+                    received.get(from..from + window)
+                })
+                .map(|candidate| window_snr(reference, candidate))
+                .reduce(f32::max)
+        })
+        .collect();
+    if snrs.is_empty() {
+        return None;
+    }
+    snrs.sort_by(f32::total_cmp);
+    Some(snrs[snrs.len() / 2])
+}
+
+// SNR of `received` against `reference`, once scaled to the same level
+fn window_snr(reference: &[f32], received: &[f32]) -> f32 {
+    let correlation: f32 = reference.iter().zip(received).map(|(r, x)| r * x).sum();
+    let energy: f32 = received.iter().map(|x| x * x).sum();
+    let gain = if energy > 0.0 { correlation / energy } else { 0.0 };
+    let signal: f32 = reference.iter().map(|r| r * r).sum();
+    let noise: f32 = reference.iter().zip(received).map(|(r, x)| (r - gain * x).powi(2)).sum();
+    10.0 * (signal / noise.max(f32::MIN_POSITIVE)).log10()
+}
+
+fn samples(duration: Duration) -> usize {
+    (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize
+}
+
+fn duration(samples: usize) -> Duration {
+    Duration::from_secs_f64(samples as f64 / SAMPLE_RATE as f64)
+}
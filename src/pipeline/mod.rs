@@ -0,0 +1,6 @@
+// The audio paths between a client and a server. `send` captures, encodes
+// and packetizes on the client; `receive` buffers, decodes, mixes and plays
+// on the server. `loopback` joins the two over 127.0.0.1 to measure them.
+pub mod send;
+pub mod receive;
+pub mod loopback;
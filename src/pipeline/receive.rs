@@ -0,0 +1,567 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use crate::backend::AudioBackend;
+use crate::channel_map::OutputMap;
+use crate::codec::StreamDecoder;
+use crate::crypto::{CrewKey, ReplayGuard};
+use crate::group::TalkGroup;
+use crate::jitter_buffer::{JitterBuffer, JitterStats, Playout};
+use crate::mixer::Mixer;
+use crate::protocol::{self, PacketKind, VoicePacket, MAX_PACKET_SIZE};
+use crate::resample::Resampler;
+use crate::rtp::{self, rtcp, Framing, ReceptionStats, OPUS_CLOCK_RATE};
+use crate::session::{recv_until_stopped, Session, StopFlag};
+use crate::talk::{self, SQUELCH_TAIL};
+
+// How often RTCP receiver reports go out in RTP mode
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+// A talker we haven't heard from for this long is forgotten
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Identifies one talker: by SSRC in RTP mode, by sender address otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKey {
+    Address(SocketAddr),
+    Ssrc(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamFormat {
+    pub sample_rate: f32,
+    // Decoded channels; the output map spreads them over the device
+    pub channels: u16,
+    // Samples per channel the mixer produces per tick. Talkers may packetize
+    // differently, their audio is cut to fit.
+    pub buffer_size: usize,
+    pub framing: Framing,
+}
+
+impl StreamFormat {
+    pub fn tick(&self) -> Duration {
+        Duration::from_secs_f32(self.buffer_size as f32 / self.sample_rate)
+    }
+    // One tick in samples at the Opus clock rate, how packets count them
+    fn tick_at_opus_rate(&self) -> usize {
+        (self.buffer_size as f32 * OPUS_CLOCK_RATE as f32 / self.sample_rate) as usize
+    }
+}
+
+// How a talker cuts its audio into packets, as its packets tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packetization {
+    frames: usize,
+    // Samples per channel at 48 kHz
+    frame_size: usize,
+}
+
+impl Packetization {
+    // Native packets say so in their header; markers carry no frames, so a
+    // stream that starts with one assumes a single frame until audio arrives
+    fn of(packet: &VoicePacket, format: &StreamFormat) -> Self {
+        Self {
+            frames: (packet.frames as usize).max(1),
+            frame_size: match packet.frame_size {
+                0 => format.tick_at_opus_rate(),
+                frame_size => frame_size as usize,
+            },
+        }
+    }
+    // RTP packets carry one Opus frame, whose length is in its TOC byte
+    fn of_rtp(payload: &[u8], format: &StreamFormat) -> Self {
+        Self {
+            frames: 1,
+            frame_size: opus::packet::get_nb_samples(payload, OPUS_CLOCK_RATE).unwrap_or(format.tick_at_opus_rate()),
+        }
+    }
+    fn duration(&self) -> Duration {
+        Duration::from_secs_f64((self.frames * self.frame_size) as f64 / OPUS_CLOCK_RATE as f64)
+    }
+    fn frame_size_at(&self, sample_rate: f32) -> usize {
+        (self.frame_size as f32 * sample_rate / OPUS_CLOCK_RATE as f32) as usize
+    }
+}
+
+// Everything needed to play one talker: each has its own jitter buffer and
+// decoder so simultaneous talkers don't corrupt each other's state.
+pub struct TalkerStream {
+    address: SocketAddr,
+    packetization: Packetization,
+    jitter_buffer: JitterBuffer<VoicePacket>,
+    decoder: StreamDecoder,
+    // Decoded audio the mixer hasn't taken yet, packets rarely line up with
+    // its ticks
+    pending: VecDeque<f32>,
+    rtp_stats: Option<ReceptionStats>,
+    // The native send this stream belongs to
+    sender_id: Option<u64>,
+    last_heard: Instant,
+}
+
+impl TalkerStream {
+    fn new(address: SocketAddr, packetization: Packetization, format: &StreamFormat) -> Self {
+        info!("SERVER: New talker from {}, {} frame(s) of {} samples per packet",
+            address, packetization.frames, packetization.frame_size);
+        Self {
+            address,
+            packetization,
+            jitter_buffer: jitter_buffer(packetization, format),
+            decoder: StreamDecoder::new(format.sample_rate as u32, format.channels, packetization.frame_size_at(format.sample_rate))
+                .expect("Failed to create Opus decoder"),
+            pending: VecDeque::new(),
+            rtp_stats: None,
+            sender_id: None,
+            last_heard: Instant::now(),
+        }
+    }
+
+    // A talker that changes its packetization, usually between two sends,
+    // gets a jitter buffer sized for the new packets
+    fn adopt(&mut self, packetization: Packetization, format: &StreamFormat) {
+        if packetization == self.packetization {
+            return;
+        }
+        info!("SERVER: {} now sends {} frame(s) of {} samples per packet",
+            self.address, packetization.frames, packetization.frame_size);
+        self.packetization = packetization;
+        self.jitter_buffer = jitter_buffer(packetization, format);
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.jitter_buffer.stats()
+    }
+}
+
+// Never aims below one mixer tick, since each tick takes a tick's worth of
+// packets out at once
+fn jitter_buffer(packetization: Packetization, format: &StreamFormat) -> JitterBuffer<VoicePacket> {
+    let packet_duration = packetization.duration();
+    let min_delay = packet_duration.max(format.tick());
+    JitterBuffer::new(packet_duration).with_delay_bounds(min_delay, min_delay * 25)
+}
+
+pub type StreamTable = Arc<Mutex<HashMap<StreamKey, TalkerStream>>>;
+// Shared by every socket so a packet can't be replayed to another address
+pub type ReplayWindows = Arc<Mutex<ReplayGuard>>;
+
+// Receives talkers on `socket` and plays their mix on `backend`, until the
+// session stops. The socket needs POLL_INTERVAL as its read timeout.
+pub fn start_receiving(
+    session: &mut Session,
+    sockets: Vec<UdpSocket>,
+    backend: Arc<dyn AudioBackend>,
+    output_map: OutputMap,
+    groups: BTreeSet<TalkGroup>,
+    key: Option<CrewKey>,
+    format: StreamFormat,
+) {
+    let device_channels = backend.output_format().channels as usize;
+    let (sender_mixer, receiver_dac) = channel();
+
+    let delay_buffer_size = format.buffer_size * 100;
+    let delay_buffer = Arc::new(
+        Mutex::new(
+            VecDeque::<f32>::with_capacity(delay_buffer_size)
+        )
+    );
+    let delay_buffer_producer = Arc::clone(&delay_buffer);
+    let playback_buffer = Arc::clone(&delay_buffer);
+
+    let streams: StreamTable = Arc::new(Mutex::new(HashMap::new()));
+
+    // UDP Threads, one per socket
+    match format.framing {
+        Framing::Native => {
+            let replay_windows: ReplayWindows = Arc::new(Mutex::new(ReplayGuard::default()));
+            for socket in sockets {
+                let replay_windows = Arc::clone(&replay_windows);
+                start_udp_thread(session, socket, Arc::clone(&streams), replay_windows, groups.clone(), key.clone(), format);
+            }
+        }
+        Framing::Rtp => {
+            // Reports go out through the first socket, whichever one the
+            // talker reached us on
+            if let Some(socket) = sockets.first() {
+                let report_socket = socket.try_clone().expect("UDP: Failed to clone socket");
+                start_rtcp_thread(session, report_socket, Arc::clone(&streams));
+            }
+            for socket in sockets {
+                start_rtp_thread(session, socket, Arc::clone(&streams), format);
+            }
+        }
+    }
+
+    // Mixer Thread
+    start_mixer_thread(session, streams, sender_mixer, format);
+
+    // Producer Thread
+    let resampler = Resampler::new(format.sample_rate as u32, backend.output_format().sample_rate, format.channels as usize);
+    start_producer_thread(
+        session,
+        receiver_dac,
+        delay_buffer_producer,
+        resampler,
+        output_map,
+        device_channels,
+        format,
+    );
+
+    // DAC Thread
+    start_dac_thread(
+        session,
+        backend,
+        playback_buffer,
+        device_channels,
+        format.buffer_size
+    );
+}
+
+pub fn start_udp_thread(
+    session: &mut Session,
+    socket: UdpSocket,
+    streams: StreamTable,
+    replay_windows: ReplayWindows,
+    groups: BTreeSet<TalkGroup>,
+    key: Option<CrewKey>,
+    format: StreamFormat,
+) {
+    let group_ids: Vec<u32> = groups.iter().map(TalkGroup::id).collect();
+    session.spawn("udp", move |stop| {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        while let Some((amount, src)) = recv_until_stopped(&socket, &mut buf, &stop) {
+            let voice_packet = match protocol::open(&buf[0..amount], key.as_ref()) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    warn!("SERVER: Dropping packet from {}: {}", src, e);
+                    continue;
+                }
+            };
+            if key.is_some() && !replay_windows
+                .lock()
+                .unwrap()
+                .accept(voice_packet.sender_id, voice_packet.sequence_number, voice_packet.timestamp)
+            {
+                warn!("SERVER: Dropping stale or replayed packet {} from {}", voice_packet.sequence_number, src);
+                continue;
+            }
+            if !group_ids.contains(&voice_packet.group) {
+                debug!("SERVER: Dropping packet from {} for group {:08x}, not joined", src, voice_packet.group);
+                continue;
+            }
+            let mut streams = streams.lock().expect("Unable to acquire stream table lock");
+            file_packet(&mut streams, src, voice_packet, &format);
+        }
+    });
+}
+
+// Queues a native packet on its talker's stream. Every send starts its count
+// over under a new sender id, so a new id from a known address starts the
+// stream afresh; the old jitter buffer would drop the new packets as late.
+pub fn file_packet(streams: &mut HashMap<StreamKey, TalkerStream>, src: SocketAddr, voice_packet: VoicePacket, format: &StreamFormat) {
+    let sequence_number = voice_packet.sequence_number;
+    let timestamp = voice_packet.timestamp;
+    let packetization = Packetization::of(&voice_packet, format);
+
+    let stream = streams
+        .entry(StreamKey::Address(src))
+        .or_insert_with(|| TalkerStream::new(src, packetization, format));
+    if stream.sender_id.is_some_and(|sender_id| sender_id != voice_packet.sender_id) {
+        info!("SERVER: {} started a new send", src);
+        *stream = TalkerStream::new(src, packetization, format);
+    }
+    stream.sender_id = Some(voice_packet.sender_id);
+    if voice_packet.kind == PacketKind::Audio {
+        stream.adopt(packetization, format);
+    }
+    stream.last_heard = Instant::now();
+    stream.jitter_buffer.push(sequence_number, voice_packet);
+    debug!(
+        "SERVER: {src} sequence_num: {sequence_number}, timestamp: {}, jitter: {:.3} ms, target delay: {:.3} ms",
+        timestamp,
+        stream.jitter_buffer.jitter().as_secs_f64() * 1000.0,
+        stream.jitter_buffer.target_delay().as_secs_f64() * 1000.0,
+    );
+}
+
+fn start_rtp_thread(session: &mut Session, socket: UdpSocket, streams: StreamTable, format: StreamFormat) {
+    session.spawn("rtp", move |stop| {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        while let Some((amount, src)) = recv_until_stopped(&socket, &mut buf, &stop) {
+            let bytes = &buf[0..amount];
+            if rtcp::is_rtcp(bytes) {
+                if let Ok(rtcp::RtcpPacket::SenderReport(report)) = rtcp::decode(bytes) {
+                    let mut streams = streams.lock().expect("Unable to acquire stream table lock");
+                    if let Some(stats) = streams
+                        .get_mut(&StreamKey::Ssrc(report.ssrc))
+                        .and_then(|stream| stream.rtp_stats.as_mut())
+                    {
+                        stats.on_sender_report(&report);
+                    }
+                }
+                continue;
+            }
+            let frame = match rtp::depacketize(bytes) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("SERVER: Dropping RTP packet from {}: {}", src, e);
+                    continue;
+                }
+            };
+
+            let packetization = Packetization::of_rtp(&frame.payload, &format);
+            let mut streams = streams.lock().expect("Unable to acquire stream table lock");
+            let stream = streams
+                .entry(StreamKey::Ssrc(frame.ssrc))
+                .or_insert_with(|| TalkerStream::new(src, packetization, &format));
+            stream.adopt(packetization, &format);
+            stream.address = src;
+            stream.last_heard = Instant::now();
+            let sequence_number = stream.rtp_stats
+                .get_or_insert_with(|| ReceptionStats::new(&frame))
+                .update(&frame);
+            stream.jitter_buffer.push(sequence_number, VoicePacket::new(sequence_number, frame.payload));
+        }
+    });
+}
+
+fn start_rtcp_thread(session: &mut Session, socket: UdpSocket, streams: StreamTable) {
+    session.spawn("rtcp", move |stop| {
+        let ssrc: u32 = rand::random();
+        while !stop.sleep(RTCP_INTERVAL) {
+            let mut streams = streams.lock().expect("Unable to acquire stream table lock");
+            for stream in streams.values_mut() {
+                let Some(stats) = stream.rtp_stats.as_mut() else {
+                    continue;
+                };
+                let report = rtcp::ReceiverReport {
+                    ssrc,
+                    reports: vec![stats.report_block()],
+                };
+                if let Err(e) = socket.send_to(&report.encode(), stream.address) {
+                    warn!("SERVER: Failed to send RTCP receiver report to {}: {}", stream.address, e);
+                }
+            }
+        }
+    });
+}
+
+// Once per packet duration, pulls the next packet out of every talker's
+// jitter buffer, decodes it and mixes the result into one block.
+fn start_mixer_thread(
+    session: &mut Session,
+    streams: StreamTable,
+    sender_mixer: Sender<Vec<f32>>,
+    format: StreamFormat,
+) {
+    session.spawn("mixer", move |stop| {
+        let mut mixer = Mixer::new();
+        let mut next_tick = Instant::now();
+        while !stop.is_stopped() {
+            let blocks: Vec<Vec<f32>> = {
+                let mut streams = streams.lock().expect("Unable to acquire stream table lock");
+                streams.retain(|_, stream| {
+                    let active = stream.last_heard.elapsed() < STREAM_TIMEOUT || !stream.jitter_buffer.is_empty();
+                    if !active {
+                        info!("SERVER: Talker {} went quiet, dropping stream", stream.address);
+                    }
+                    active
+                });
+                streams.values_mut()
+                    .filter_map(|stream| next_block(stream, &format))
+                    .collect()
+            };
+
+            let mixed = mixer.mix(&blocks);
+            if !mixed.is_empty() {
+                if let Err(e) = sender_mixer.send(mixed) {
+                    error!("SERVER: Failed to send data to audio thread: {:?}", e);
+                }
+            }
+
+            // Tick against an absolute deadline so playout doesn't drift
+            next_tick += format.tick();
+            let now = Instant::now();
+            if next_tick > now {
+                stop.sleep(next_tick - now);
+            } else {
+                next_tick = now;
+            }
+        }
+    });
+}
+
+// One tick of audio for one talker, or nothing while it is silent. Packets
+// come out of the jitter buffer until they cover the tick; a talkspurt that
+// ends part way through is padded with silence.
+pub fn next_block(stream: &mut TalkerStream, format: &StreamFormat) -> Option<Vec<f32>> {
+    let wanted = format.buffer_size * format.channels as usize;
+    while stream.pending.len() < wanted {
+        match handle_jitter_buffer(stream, format) {
+            Some(samples) => stream.pending.extend(samples),
+            None => break,
+        }
+    }
+    if stream.pending.is_empty() {
+        return None;
+    }
+    let available = wanted.min(stream.pending.len());
+    let mut block: Vec<f32> = stream.pending.drain(..available).collect();
+    block.resize(wanted, 0.0);
+    Some(block)
+}
+
+// Plays out the next packet for one talker, or nothing while the jitter
+// buffer has nothing to give
+fn handle_jitter_buffer(stream: &mut TalkerStream, format: &StreamFormat) -> Option<Vec<f32>> {
+    let frames = stream.packetization.frames;
+    match stream.jitter_buffer.pop() {
+        Playout::Frame(packet) => match packet.kind {
+            PacketKind::Audio => Some(decode_packet(&packet.payload, &mut stream.decoder, format, frames)),
+            PacketKind::TalkStart => {
+                debug!("SERVER: Talk start from {}", stream.address);
+                Some(Vec::new())
+            }
+            PacketKind::TalkStop => {
+                debug!("SERVER: Talk stop from {}", stream.address);
+                stream.jitter_buffer.end_talkspurt();
+                Some(talk::squelch_tail(format.sample_rate, format.channels as usize, SQUELCH_TAIL))
+            }
+        },
+        Playout::Lost { sequence_number, next } => {
+            debug!("SERVER: Packet {} from {} lost, concealing", sequence_number, stream.address);
+            let next = next
+                .filter(|packet| packet.kind == PacketKind::Audio)
+                .map(|packet| packet.payload);
+            Some(conceal_packet(next.as_deref(), &mut stream.decoder, format, frames))
+        }
+        Playout::Underrun => {
+            let stats = stream.jitter_buffer.stats();
+            warn!("SERVER: Jitter buffer underrun for {} ({} underruns, {} overruns, {} late)",
+                stream.address, stats.underruns, stats.overruns, stats.late);
+            None
+        }
+        Playout::Buffering => None,
+    }
+}
+
+// RTP packets carry a single bare Opus frame, native packets a batch of
+// length-prefixed frames.
+fn packet_frames(packet: &[u8], framing: Framing) -> Result<Vec<&[u8]>, protocol::ProtocolError> {
+    match framing {
+        Framing::Native => protocol::unpack_frames(packet),
+        Framing::Rtp => Ok(vec![packet]),
+    }
+}
+
+fn decode_packet(packet: &[u8], decoder: &mut StreamDecoder, format: &StreamFormat, frame_count: usize) -> Vec<f32> {
+    let frames = match packet_frames(packet, format.framing) {
+        Ok(frames) => frames,
+        Err(e) => {
+            warn!("SERVER: Incomplete frame detected: {}", e);
+            return conceal_packet(None, decoder, format, frame_count);
+        }
+    };
+    let mut decoded = Vec::new();
+    for frame in frames {
+        match decoder.decode(frame) {
+            Ok(samples) => decoded.extend(samples),
+            Err(e) => {
+                warn!("SERVER: Decoding failed: {:?}", e);
+                decoded.extend(decoder.conceal(None).unwrap_or_default());
+            }
+        }
+    }
+    decoded
+}
+
+// All but the last frame of a lost packet are concealed by the decoder, the
+// last one can be rebuilt from the FEC data in the first frame of the packet
+// that followed it.
+fn conceal_packet(next_packet: Option<&[u8]>, decoder: &mut StreamDecoder, format: &StreamFormat, frame_count: usize) -> Vec<f32> {
+    let next_frame = next_packet
+        .and_then(|packet| packet_frames(packet, format.framing).ok())
+        .and_then(|frames| frames.first().map(|frame| frame.to_vec()));
+
+    let mut concealed = Vec::new();
+    for index in 0..frame_count {
+        let fec_frame = if index == frame_count - 1 { next_frame.as_deref() } else { None };
+        match decoder.conceal(fec_frame) {
+            Ok(samples) => concealed.extend(samples),
+            Err(e) => warn!("SERVER: Concealment failed: {:?}", e),
+        }
+    }
+    concealed
+}
+
+fn start_producer_thread(
+    session: &mut Session,
+    receiver_dac: Receiver<Vec<f32>>,
+    delay_buffer: Arc<Mutex<VecDeque<f32>>>,
+    mut resampler: Resampler,
+    output_map: OutputMap,
+    device_channels: usize,
+    format: StreamFormat,
+) {
+    let buffer_size = format.buffer_size;
+    // Ends once the mixer thread hangs up. The mix is at the codec rate in
+    // the stream's layout, the delay buffer at the device rate and layout.
+    session.spawn("producer", move |_| {
+        while let Ok(block) = receiver_dac.recv() {
+            let block = resampler.process(&block);
+            let block = output_map.apply(&block, format.channels as usize, device_channels);
+            let mut buffer = delay_buffer.lock().expect("Failed to lock delay buffer for producer");
+            buffer.extend(block);
+            // Trim whole frames so the channels stay interleaved
+            while buffer.len() > buffer_size * 100 {
+                buffer.drain(..buffer_size * device_channels);
+            }
+        }
+    });
+}
+
+// The output stream is started and dropped on this thread, cpal streams
+// can't move between threads on every platform.
+fn start_dac_thread(
+    session: &mut Session,
+    backend: Arc<dyn AudioBackend>,
+    delay_buffer: Arc<Mutex<VecDeque<f32>>>,
+    channels: usize,
+    buffer_size: usize,
+) {
+    let delay_buffer_clone = Arc::clone(&delay_buffer);
+    session.spawn("dac", move |stop| {
+        if wait_for_buffer_fill(&delay_buffer_clone, channels * buffer_size, &stop) {
+            play_stream(&*backend, delay_buffer_clone, &stop);
+        }
+    });
+}
+
+// Returns false if the session stopped before the buffer filled
+fn wait_for_buffer_fill(buffer: &Arc<Mutex<VecDeque<f32>>>, target_size: usize, stop: &StopFlag) -> bool {
+    loop {
+        let buffer_len = buffer.lock().expect("Failed to acquire playback buffer lock").len();
+        if buffer_len >= target_size * 4 / 5 {
+            return true;
+        }
+        if stop.sleep(Duration::from_millis(10)) {
+            return false;
+        }
+    }
+}
+
+fn play_stream(backend: &dyn AudioBackend, buffer: Arc<Mutex<VecDeque<f32>>>, stop: &StopFlag) {
+    let _stream = backend
+        .start_output(Box::new(move |data: &mut [f32]| fill_audio_data(data, &buffer)))
+        .unwrap_or_else(|e| panic!("DAC: Unable to start {} output: {}", backend.name(), e));
+    stop.wait();
+}
+
+fn fill_audio_data(data: &mut [f32], buffer: &Arc<Mutex<VecDeque<f32>>>) {
+    let mut buffer = buffer.lock().expect("Failed to lock buffer for playback");
+    for sample in data.iter_mut() {
+        *sample = buffer.pop_front().unwrap_or(0.0);
+    }
+}
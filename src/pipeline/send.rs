@@ -0,0 +1,378 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use opus::{Application, Encoder};
+use crate::channel_map::InputMap;
+use crate::codec;
+use crate::crypto::CrewKey;
+use crate::group::{Membership, TalkGroup};
+use crate::mdns_service::{peer, PeerTable, Role};
+use crate::network;
+use crate::protocol::{self, PacketKind, VoicePacket, MAX_FRAME_BYTES};
+use crate::rtp::{rtcp, Framing, RtpSender, OPUS_CLOCK_RATE};
+use crate::session::{recv_until_stopped, Session, StopFlag, POLL_INTERVAL};
+use crate::settings::ApplicationSettings;
+use crate::source::{self, AudioSource};
+use crate::talk::{GateDecision, Outgoing, TalkGate};
+
+// Packet loss the encoder plans its FEC redundancy for
+const EXPECTED_PACKET_LOSS_PERC: i32 = 10;
+// How often RTCP sender reports go out in RTP mode
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+// A destination that keeps failing is logged no more often than this
+const SEND_ERROR_INTERVAL: Duration = Duration::from_secs(5);
+
+// Who to send to: the peers mDNS found and the group this client is
+// talking on
+#[derive(Clone)]
+pub struct Directory {
+    pub peers: PeerTable,
+    pub membership: Arc<Mutex<Membership>>,
+}
+
+impl Directory {
+    // The talk group and the address of every server listening to it, on
+    // the port it advertises, as a socket bound on `local` reaches it.
+    // Servers speaking another protocol version would only drop the packets.
+    fn recipients(&self, local: SocketAddr) -> (TalkGroup, Vec<SocketAddr>) {
+        let talk_group = self.membership.lock().unwrap().talk_group().clone();
+        let destinations = self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.role == Role::Server && peer.groups.contains(&talk_group))
+            .filter(|peer| peer.protocol_version.is_none_or(|version| version == protocol::PROTOCOL_VERSION))
+            .filter_map(|peer| peer.destination(local))
+            .collect();
+        (talk_group, destinations)
+    }
+}
+
+// How packets are put on the wire
+#[derive(Clone)]
+pub struct Transport {
+    pub framing: Framing,
+    // Opus frames per native packet, RTP always carries one
+    pub frames_per_packet: usize,
+    // Seals native packets with the crew key when set
+    pub key: Option<CrewKey>,
+}
+
+impl Transport {
+    // What this client sends, as advertised over mDNS
+    pub fn capabilities(&self) -> BTreeSet<String> {
+        peer::capabilities(self.framing, self.key.is_some())
+    }
+}
+
+// Stamps outgoing packets with this send's sender id and seals them
+pub struct Sealer {
+    sender_id: u64,
+    key: Option<CrewKey>,
+}
+
+impl Sealer {
+    pub fn new(sender_id: u64, key: Option<CrewKey>) -> Self {
+        Self { sender_id, key }
+    }
+    pub fn seal(&self, packet: VoicePacket) -> Vec<u8> {
+        protocol::seal(&packet.with_sender(self.sender_id), self.key.as_ref())
+    }
+}
+
+// Starts capturing `source` and sending it from `local` to whoever the
+// directory lists. Stopping the returned session ends the send.
+pub fn start_sending(
+    settings: &ApplicationSettings,
+    source: &AudioSource,
+    input_map: InputMap,
+    gate: TalkGate,
+    local: SocketAddr,
+    directory: Directory,
+    transport: Transport,
+) -> Result<Session, Box<dyn Error>> {
+    let sample_rate = settings.get_sample_rate();
+    let channels = settings.get_input_channels();
+    let buffer_size = settings.get_buffer_size();
+    let (output_source, input_encoder) = channel();
+    let (output_encoder, input_buffer) = channel();
+    let socket = network::bind_udp(local)?;
+
+    // Stopping the session silences the source first; the encoder and the
+    // packetizer then see their input close, send the talk stop and finish.
+    let mut session = Session::new("send");
+    source::start_source(source, settings, output_source, buffer_size, &mut session)?;
+
+    let opus_encoder = create_encoder(sample_rate, &input_map, settings.get_bitrate())?;
+    session.spawn("encoder", move |_| {
+        encode_opus(input_encoder, output_encoder, gate, input_map, channels as usize, opus_encoder)
+    });
+
+    // Frame length at the Opus clock rate, which is how both framings count it
+    let frame_ticks = (buffer_size as u64 * OPUS_CLOCK_RATE as u64 / sample_rate as u64) as u32;
+    match transport.framing {
+        Framing::Native => {
+            let batch = Batch::new(transport.frames_per_packet, frame_ticks as usize);
+            session.spawn("sender", move |_| batch_and_send_udp(socket, input_buffer, directory, batch, transport.key));
+        }
+        Framing::Rtp => {
+            let report_socket = socket.try_clone()?;
+            report_socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            let peers = Arc::clone(&directory.peers);
+            session.spawn("rtcp", move |stop| receive_rtcp(report_socket, peers, stop));
+            session.spawn("sender", move |_| send_rtp(socket, input_buffer, directory, frame_ticks));
+        }
+    }
+    Ok(session)
+}
+
+pub fn create_encoder(sample_rate: f32, input_map: &InputMap, bitrate: i32) -> Result<Encoder, opus::Error> {
+    let opus_channels = codec::opus_channels(input_map.stream_channels() as u16);
+    // In-band FEC is only produced by the SILK/hybrid modes, which the Voip
+    // application favours for speech.
+    let mut opus_encoder = Encoder::new(sample_rate as u32, opus_channels, Application::Voip)?;
+    opus_encoder.set_bitrate(opus::Bitrate::Bits(bitrate))?;
+    opus_encoder.set_vbr(false)?;
+    opus_encoder.set_inband_fec(true)?;
+    opus_encoder.set_packet_loss_perc(EXPECTED_PACKET_LOSS_PERC)?;
+    Ok(opus_encoder)
+}
+
+fn encode_opus(
+    input_encoder: Receiver<Vec<f32>>,
+    output_encoder: Sender<Outgoing>,
+    mut gate: TalkGate,
+    input_map: InputMap,
+    device_channels: usize,
+    mut opus_encoder: Encoder,
+) {
+    let mut transmitting = false;
+    while let Ok(block) = input_encoder.recv() {
+        let block = input_map.apply(&block, device_channels);
+        match gate.process(&block) {
+            GateDecision::Start => {
+                // Fresh encoder state, so the first frame doesn't carry the
+                // tail of the previous talkspurt
+                opus_encoder.reset_state().unwrap();
+                output_encoder.send(Outgoing::TalkStart).expect("Failed to send talk start");
+                transmitting = true;
+            }
+            GateDecision::Open => {}
+            GateDecision::Stop => {
+                output_encoder.send(Outgoing::TalkStop).expect("Failed to send talk stop");
+                transmitting = false;
+                continue;
+            }
+            GateDecision::Closed => continue,
+        }
+        let mut encoded_block = vec![0; MAX_FRAME_BYTES];
+        if let Ok(len) = opus_encoder.encode_float(&block, &mut encoded_block) {
+            output_encoder.send(Outgoing::Frame(encoded_block[..len].to_vec())).expect("Failed to send encoded data");
+        }
+    }
+    if transmitting {
+        let _ = output_encoder.send(Outgoing::TalkStop);
+    }
+}
+
+// Opus frames collected for the next native packet
+pub struct Batch {
+    payload: Vec<u8>,
+    frames: usize,
+    frames_per_packet: usize,
+    // Samples per channel in each frame at the Opus clock rate
+    frame_size: usize,
+}
+
+impl Batch {
+    pub fn new(frames_per_packet: usize, frame_size: usize) -> Self {
+        Self { payload: Vec::new(), frames: 0, frames_per_packet, frame_size }
+    }
+    // Adds a frame, its length going before it. True once the batch is full.
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        protocol::pack_frame(&mut self.payload, frame);
+        self.frames += 1;
+        self.frames >= self.frames_per_packet
+    }
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+    pub fn clear(&mut self) {
+        self.payload.clear();
+        self.frames = 0;
+    }
+}
+
+fn batch_and_send_udp(
+    socket: UdpSocket,
+    input_buffer: Receiver<Outgoing>,
+    directory: Directory,
+    mut batch: Batch,
+    key: Option<CrewKey>,
+) {
+    // One count for the whole stream: every recipient gets the same packet
+    // under the same number, so each sees a contiguous sequence however many
+    // others are listening. It starts over with every send, so does the
+    // sender id.
+    let mut sequence_number: u32 = 0;
+    let sealer = Sealer::new(rand::random(), key);
+    let local = socket.local_addr().expect("Failed to read the local address");
+    let mut fanout = Fanout::new(socket);
+
+    while let Ok(outgoing) = input_buffer.recv() {
+        match outgoing {
+            Outgoing::TalkStart => {
+                let (talk_group, destinations) = directory.recipients(local);
+                let packet = create_marker(&sealer, &talk_group, &batch, PacketKind::TalkStart, sequence_number);
+                fanout.send_to_all(&destinations, &packet);
+                sequence_number = sequence_number.wrapping_add(1);
+            }
+            Outgoing::Frame(block) => {
+                if batch.push(&block) {
+                    let (talk_group, destinations) = directory.recipients(local);
+                    let packet = create_packet(&sealer, &talk_group, &batch, sequence_number);
+                    fanout.send_to_all(&destinations, &packet);
+                    sequence_number = sequence_number.wrapping_add(1);
+                    batch.clear();
+                }
+            }
+            Outgoing::TalkStop => {
+                // Flush whatever is left of the talkspurt before the marker
+                let (talk_group, destinations) = directory.recipients(local);
+                if !batch.is_empty() {
+                    let packet = create_packet(&sealer, &talk_group, &batch, sequence_number);
+                    fanout.send_to_all(&destinations, &packet);
+                    sequence_number = sequence_number.wrapping_add(1);
+                    batch.clear();
+                }
+                let packet = create_marker(&sealer, &talk_group, &batch, PacketKind::TalkStop, sequence_number);
+                fanout.send_to_all(&destinations, &packet);
+                sequence_number = sequence_number.wrapping_add(1);
+            }
+        }
+    }
+}
+
+fn send_rtp(
+    socket: UdpSocket,
+    input_buffer: Receiver<Outgoing>,
+    directory: Directory,
+    frame_ticks: u32,
+) {
+    let mut rtp_sender = RtpSender::new();
+    let local = socket.local_addr().expect("Failed to read the local address");
+    let mut fanout = Fanout::new(socket);
+    info!("RTP: Sending with SSRC {:08x}", rtp_sender.ssrc());
+    let mut first_frame = true;
+    let mut last_report = Instant::now();
+
+    while let Ok(outgoing) = input_buffer.recv() {
+        // RTP has no talk stop marker; receivers notice the stream pausing.
+        // The marker bit flags the first packet of a talkspurt instead.
+        let block = match outgoing {
+            Outgoing::TalkStart => {
+                first_frame = true;
+                continue;
+            }
+            Outgoing::TalkStop => continue,
+            Outgoing::Frame(block) => block,
+        };
+        let packet = rtp_sender.packetize(&block, frame_ticks, first_frame);
+        first_frame = false;
+        let sender_report = if last_report.elapsed() >= RTCP_INTERVAL {
+            last_report = Instant::now();
+            Some(rtp_sender.sender_report().encode())
+        } else {
+            None
+        };
+        // RTP carries no group id, so groups are only enforced by choosing
+        // who to send to
+        let (_talk_group, destinations) = directory.recipients(local);
+        fanout.send_to_all(&destinations, &packet);
+        if let Some(report) = &sender_report {
+            fanout.send_to_all(&destinations, report);
+        }
+    }
+}
+
+// Receiver reports from the servers, which also tell they are alive
+fn receive_rtcp(socket: UdpSocket, peers: PeerTable, stop: StopFlag) {
+    let mut buf = [0u8; 1500];
+    while let Some((amount, src)) = recv_until_stopped(&socket, &mut buf, &stop) {
+        if !rtcp::is_rtcp(&buf[..amount]) {
+            continue;
+        }
+        peer::touch(&peers, src);
+        match rtcp::decode(&buf[..amount]) {
+            Ok(rtcp::RtcpPacket::ReceiverReport(report)) => {
+                for block in report.reports {
+                    info!("RTCP: {} reports {}/256 lost ({} total), jitter {} ticks",
+                        src, block.fraction_lost, block.cumulative_lost, block.jitter);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => debug!("RTCP: Ignoring report from {}: {}", src, e),
+        }
+    }
+}
+
+// Sends to every recipient in turn. One that can't be reached doesn't stop
+// the others: with several addresses per peer, a route going away on one of
+// them is routine. Failures are logged once per SEND_ERROR_INTERVAL and
+// destination, with a count of the ones in between.
+struct Fanout {
+    socket: UdpSocket,
+    // When each failing destination was last logged, and the failures since
+    failures: HashMap<SocketAddr, (Option<Instant>, u64)>,
+}
+
+impl Fanout {
+    fn new(socket: UdpSocket) -> Self {
+        Self { socket, failures: HashMap::new() }
+    }
+
+    fn send_to_all(&mut self, destinations: &[SocketAddr], packet: &[u8]) {
+        for destination in destinations {
+            if let Err(e) = self.socket.send_to(packet, destination) {
+                self.failed(*destination, &e);
+            }
+        }
+    }
+
+    fn failed(&mut self, destination: SocketAddr, error: &std::io::Error) {
+        let now = Instant::now();
+        let (last_logged, suppressed) = self.failures.entry(destination).or_insert((None, 0));
+        if last_logged.is_some_and(|logged| now.duration_since(logged) < SEND_ERROR_INTERVAL) {
+            *suppressed += 1;
+            return;
+        }
+        if *suppressed > 0 {
+            warn!("NETWORK: Failed to send to {}: {} ({} more failures since)", destination, error, suppressed);
+        } else {
+            warn!("NETWORK: Failed to send to {}: {}", destination, error);
+        }
+        *last_logged = Some(now);
+        *suppressed = 0;
+    }
+}
+
+// Markers carry the frame size too, so a receiver meeting the talker through
+// its talk start sizes its jitter buffer right away
+pub fn create_marker(sealer: &Sealer, talk_group: &TalkGroup, batch: &Batch, kind: PacketKind, sequence_number: u32) -> Vec<u8> {
+    let packet = VoicePacket::marker(kind, sequence_number)
+        .with_group(talk_group.id())
+        .with_framing(0, batch.frame_size);
+    sealer.seal(packet)
+}
+
+// One batch of length-prefixed Opus frames as it goes on the wire
+pub fn create_packet(sealer: &Sealer, talk_group: &TalkGroup, batch: &Batch, sequence_number: u32) -> Vec<u8> {
+    let packet = VoicePacket::new(sequence_number, batch.payload.clone())
+        .with_group(talk_group.id())
+        .with_framing(batch.frames, batch.frame_size);
+    sealer.seal(packet)
+}
@@ -0,0 +1,446 @@
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt, ByteOrder};
+use crate::crypto::{CrewKey, TAG_SIZE};
+
+// Wire format shared by every binary that sends or receives voice packets:
+// [Version (1 byte)] + [Kind (1 byte)] + [Flags (1 byte)] + [Frames (1 byte)]
+// + [Frame Size (2 bytes)] + [Group (4 bytes)] + [Sender Id (8 bytes)]
+// + [Data Length (4 bytes)] + [Sequence Number (8 bytes)]
+// + [Timestamp (20 bytes)] + [Payload (variable length)]
+pub const PROTOCOL_VERSION: u8 = 5;
+
+pub const VERSION_SIZE: usize = 1;
+pub const KIND_SIZE: usize = 1;
+pub const FLAGS_SIZE: usize = 1;
+pub const FRAMES_SIZE: usize = 1;
+pub const FRAME_SIZE_SIZE: usize = 2;
+pub const GROUP_SIZE: usize = 4;
+pub const SENDER_ID_SIZE: usize = 8;
+pub const DATA_LEN_SIZE: usize = 4;
+pub const SEQUENCE_NUM_SIZE: usize = 8;
+pub const TIMESTAMP_SIZE: usize = 20;
+pub const HEADER_SIZE: usize =
+    VERSION_SIZE + KIND_SIZE + FLAGS_SIZE + FRAMES_SIZE + FRAME_SIZE_SIZE + GROUP_SIZE
+    + SENDER_ID_SIZE + DATA_LEN_SIZE + SEQUENCE_NUM_SIZE + TIMESTAMP_SIZE;
+// Opus frames a client may put in one packet
+pub const MAX_FRAMES_PER_PACKET: usize = 3;
+// Largest Opus frame in bytes (RFC 6716, 3.4)
+pub const MAX_FRAME_BYTES: usize = 1275;
+const FRAME_LENGTH_SIZE: usize = 2;
+pub const PAYLOAD_SIZE: usize = MAX_FRAMES_PER_PACKET * (FRAME_LENGTH_SIZE + MAX_FRAME_BYTES);
+pub const MAX_PACKET_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE + TAG_SIZE;
+
+// The payload is sealed with the crew key, see `seal`
+pub const FLAG_ENCRYPTED: u8 = 0x01;
+
+const SEQUENCE_START: [u8; 2] = [0xCC, 0xDD];
+const SEQUENCE_END: [u8; 2] = [0xDD, 0xCC];
+const TIMESTAMP_START: [u8; 2] = [0xAA, 0xBB];
+const TIMESTAMP_END: [u8; 2] = [0xBB, 0xAA];
+
+// Talk start/stop markers share the sequence space with audio, so receivers
+// see them in order and know when a talkspurt really ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    Audio = 0,
+    TalkStart = 1,
+    TalkStop = 2,
+}
+
+impl TryFrom<u8> for PacketKind {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketKind::Audio),
+            1 => Ok(PacketKind::TalkStart),
+            2 => Ok(PacketKind::TalkStop),
+            other => Err(ProtocolError::InvalidPacketKind(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoicePacket {
+    pub kind: PacketKind,
+    // Talk group id, see `group::group_id`. 0 is the `all` group.
+    pub group: u32,
+    // Random per sequence space, keeps encryption nonces unique
+    pub sender_id: u64,
+    // Whether `payload` is still sealed
+    pub encrypted: bool,
+    // Opus frames in the payload, 0 for markers
+    pub frames: u8,
+    // Samples per channel in each frame at 48 kHz, so receivers learn the
+    // sender's framing from the packet itself. 0 if unknown.
+    pub frame_size: u16,
+    pub sequence_number: u32,
+    // Milliseconds since the UNIX epoch at the time the packet was built
+    pub timestamp: u128,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    Truncated { expected: usize, actual: usize },
+    UnsupportedVersion(u8),
+    InvalidPacketKind(u8),
+    InvalidSequenceMarker,
+    InvalidTimestampMarker,
+    LengthMismatch { declared: usize, actual: usize },
+    InvalidFrame { offset: usize },
+    // Encrypted packet but no crew key configured
+    Encrypted,
+    // Plaintext packet but a crew key is configured
+    NotEncrypted,
+    AuthenticationFailed,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated { expected, actual } => {
+                write!(f, "packet truncated: expected at least {} bytes, got {}", expected, actual)
+            }
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {} (expected {})", version, PROTOCOL_VERSION)
+            }
+            ProtocolError::InvalidPacketKind(kind) => write!(f, "invalid packet kind {}", kind),
+            ProtocolError::InvalidSequenceMarker => write!(f, "invalid sequence number header"),
+            ProtocolError::InvalidTimestampMarker => write!(f, "invalid timestamp header"),
+            ProtocolError::LengthMismatch { declared, actual } => {
+                write!(f, "payload length mismatch: header says {}, got {}", declared, actual)
+            }
+            ProtocolError::InvalidFrame { offset } => {
+                write!(f, "incomplete frame at payload offset {}", offset)
+            }
+            ProtocolError::Encrypted => write!(f, "packet is encrypted but no crew key is set"),
+            ProtocolError::NotEncrypted => write!(f, "unencrypted packet rejected, a crew key is set"),
+            ProtocolError::AuthenticationFailed => write!(f, "packet failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl VoicePacket {
+    pub fn new(sequence_number: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind: PacketKind::Audio,
+            group: 0,
+            sender_id: 0,
+            encrypted: false,
+            frames: 0,
+            frame_size: 0,
+            sequence_number,
+            timestamp: current_time_in_ms(),
+            payload,
+        }
+    }
+
+    // A payload-less talk start or stop marker
+    pub fn marker(kind: PacketKind, sequence_number: u32) -> Self {
+        Self {
+            kind,
+            group: 0,
+            sender_id: 0,
+            encrypted: false,
+            frames: 0,
+            frame_size: 0,
+            sequence_number,
+            timestamp: current_time_in_ms(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn with_group(mut self, group: u32) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn with_sender(mut self, sender_id: u64) -> Self {
+        self.sender_id = sender_id;
+        self
+    }
+
+    pub fn with_framing(mut self, frames: usize, frame_size: usize) -> Self {
+        self.frames = frames as u8;
+        self.frame_size = frame_size as u16;
+        self
+    }
+}
+
+pub fn current_time_in_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+pub fn encode(packet: &VoicePacket) -> Vec<u8> {
+    let mut bytes = encode_header(packet, packet.payload.len());
+    bytes.extend_from_slice(&packet.payload);
+    bytes
+}
+
+fn encode_header(packet: &VoicePacket, data_len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + data_len);
+    bytes.push(PROTOCOL_VERSION);
+    bytes.push(packet.kind as u8);
+    bytes.push(if packet.encrypted { FLAG_ENCRYPTED } else { 0 });
+    bytes.push(packet.frames);
+    bytes.write_u16::<BigEndian>(packet.frame_size).unwrap();
+    bytes.write_u32::<BigEndian>(packet.group).unwrap();
+    bytes.write_u64::<BigEndian>(packet.sender_id).unwrap();
+    bytes.write_u32::<BigEndian>(data_len as u32).unwrap();
+
+    bytes.extend_from_slice(&SEQUENCE_START);
+    bytes.write_u32::<BigEndian>(packet.sequence_number).unwrap();
+    bytes.extend_from_slice(&SEQUENCE_END);
+
+    bytes.extend_from_slice(&TIMESTAMP_START);
+    bytes.write_u128::<BigEndian>(packet.timestamp).unwrap();
+    bytes.extend_from_slice(&TIMESTAMP_END);
+    bytes
+}
+
+// Encodes a plaintext packet, encrypting its payload when a crew key is
+// given. The header travels in the clear but is authenticated.
+pub fn seal(packet: &VoicePacket, key: Option<&CrewKey>) -> Vec<u8> {
+    let Some(key) = key else {
+        return encode(packet);
+    };
+    let mut sealed = packet.clone();
+    sealed.encrypted = true;
+    let mut bytes = encode_header(&sealed, packet.payload.len() + TAG_SIZE);
+    let ciphertext = key.seal(packet.sender_id, packet.sequence_number, &bytes, &packet.payload);
+    bytes.extend_from_slice(&ciphertext);
+    bytes
+}
+
+// The receiving side of `seal`. With a crew key only authentic encrypted
+// packets are accepted; without one only plaintext packets are.
+pub fn open(bytes: &[u8], key: Option<&CrewKey>) -> Result<VoicePacket, ProtocolError> {
+    let mut packet = decode(bytes)?;
+    match (key, packet.encrypted) {
+        (None, false) => Ok(packet),
+        (None, true) => Err(ProtocolError::Encrypted),
+        (Some(_), false) => Err(ProtocolError::NotEncrypted),
+        (Some(key), true) => {
+            packet.payload = key
+                .open(packet.sender_id, packet.sequence_number, &bytes[..HEADER_SIZE], &packet.payload)
+                .map_err(|_| ProtocolError::AuthenticationFailed)?;
+            packet.encrypted = false;
+            Ok(packet)
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<VoicePacket, ProtocolError> {
+    // The version is checked before anything else so that a peer running a
+    // different release gets a clear error instead of a marker mismatch.
+    let version = *bytes.first().ok_or(ProtocolError::Truncated {
+        expected: HEADER_SIZE,
+        actual: 0,
+    })?;
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    if bytes.len() < HEADER_SIZE {
+        return Err(ProtocolError::Truncated {
+            expected: HEADER_SIZE,
+            actual: bytes.len(),
+        });
+    }
+
+    let kind = PacketKind::try_from(bytes[VERSION_SIZE])?;
+    let flags = bytes[VERSION_SIZE + KIND_SIZE];
+    let frames = bytes[VERSION_SIZE + KIND_SIZE + FLAGS_SIZE];
+    let mut cursor = Cursor::new(&bytes[VERSION_SIZE + KIND_SIZE + FLAGS_SIZE + FRAMES_SIZE..]);
+    let frame_size = cursor.read_u16::<BigEndian>().unwrap();
+    let group = cursor.read_u32::<BigEndian>().unwrap();
+    let sender_id = cursor.read_u64::<BigEndian>().unwrap();
+    let data_len = cursor.read_u32::<BigEndian>().unwrap() as usize;
+
+    let mut sequence_num_buf = [0u8; SEQUENCE_NUM_SIZE];
+    cursor.read_exact(&mut sequence_num_buf).unwrap();
+    if sequence_num_buf[0..2] != SEQUENCE_START || sequence_num_buf[6..8] != SEQUENCE_END {
+        return Err(ProtocolError::InvalidSequenceMarker);
+    }
+    let sequence_number = BigEndian::read_u32(&sequence_num_buf[2..6]);
+
+    let mut time_in_ms_buf = [0u8; TIMESTAMP_SIZE];
+    cursor.read_exact(&mut time_in_ms_buf).unwrap();
+    if time_in_ms_buf[0..2] != TIMESTAMP_START || time_in_ms_buf[18..20] != TIMESTAMP_END {
+        return Err(ProtocolError::InvalidTimestampMarker);
+    }
+    let timestamp = BigEndian::read_u128(&time_in_ms_buf[2..18]);
+
+    let payload = &bytes[HEADER_SIZE..];
+    if payload.len() != data_len {
+        return Err(ProtocolError::LengthMismatch {
+            declared: data_len,
+            actual: payload.len(),
+        });
+    }
+
+    Ok(VoicePacket {
+        kind,
+        group,
+        sender_id,
+        encrypted: flags & FLAG_ENCRYPTED != 0,
+        frames,
+        frame_size,
+        sequence_number,
+        timestamp,
+        payload: payload.to_vec(),
+    })
+}
+
+// A payload carries one or more Opus frames, each prefixed with its length:
+// [Frame Length (2 bytes)] + [Frame (variable length)] ...
+pub fn pack_frame(payload: &mut Vec<u8>, frame: &[u8]) {
+    payload.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+    payload.extend_from_slice(frame);
+}
+
+pub fn unpack_frames(payload: &[u8]) -> Result<Vec<&[u8]>, ProtocolError> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+        if offset + 2 > payload.len() {
+            return Err(ProtocolError::InvalidFrame { offset });
+        }
+        let frame_length = BigEndian::read_u16(&payload[offset..offset + 2]) as usize;
+        if offset + 2 + frame_length > payload.len() {
+            return Err(ProtocolError::InvalidFrame { offset });
+        }
+        frames.push(&payload[offset + 2..offset + 2 + frame_length]);
+        offset += 2 + frame_length;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offsets into the header, see the layout at the top
+    const GROUP_OFFSET: usize = VERSION_SIZE + KIND_SIZE + FLAGS_SIZE + FRAMES_SIZE + FRAME_SIZE_SIZE;
+    const SEQUENCE_OFFSET: usize = GROUP_OFFSET + GROUP_SIZE + SENDER_ID_SIZE + DATA_LEN_SIZE;
+    const TIMESTAMP_OFFSET: usize = SEQUENCE_OFFSET + SEQUENCE_NUM_SIZE;
+
+    fn audio_packet() -> VoicePacket {
+        let mut payload = Vec::new();
+        pack_frame(&mut payload, &[1, 2, 3]);
+        pack_frame(&mut payload, &[4, 5]);
+        VoicePacket::new(u32::MAX, payload)
+            .with_group(0xdead_beef)
+            .with_sender(42)
+            .with_framing(2, 960)
+    }
+
+    #[test]
+    fn packets_survive_a_round_trip() {
+        let packet = audio_packet();
+        let bytes = encode(&packet);
+        assert_eq!(bytes.len(), HEADER_SIZE + packet.payload.len());
+        assert_eq!(decode(&bytes), Ok(packet.clone()));
+
+        let frames = unpack_frames(&packet.payload).unwrap();
+        assert_eq!(frames, vec![&[1, 2, 3][..], &[4, 5][..]]);
+
+        let marker = VoicePacket::marker(PacketKind::TalkStop, 7).with_framing(0, 480);
+        assert_eq!(decode(&encode(&marker)), Ok(marker));
+    }
+
+    #[test]
+    fn other_versions_are_rejected_first() {
+        let mut bytes = encode(&audio_packet());
+        bytes[0] = PROTOCOL_VERSION - 1;
+        assert_eq!(decode(&bytes), Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION - 1)));
+        // Even when too short to hold a header
+        assert_eq!(decode(&[PROTOCOL_VERSION + 1]), Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+    }
+
+    #[test]
+    fn short_packets_are_truncated() {
+        let bytes = encode(&audio_packet());
+        assert_eq!(decode(&[]), Err(ProtocolError::Truncated { expected: HEADER_SIZE, actual: 0 }));
+        assert_eq!(
+            decode(&bytes[..HEADER_SIZE - 1]),
+            Err(ProtocolError::Truncated { expected: HEADER_SIZE, actual: HEADER_SIZE - 1 })
+        );
+    }
+
+    #[test]
+    fn payload_must_match_the_declared_length() {
+        let packet = audio_packet();
+        let declared = packet.payload.len();
+        let mut bytes = encode(&packet);
+        bytes.pop();
+        assert_eq!(decode(&bytes), Err(ProtocolError::LengthMismatch { declared, actual: declared - 1 }));
+        bytes.extend_from_slice(&[0, 0]);
+        assert_eq!(decode(&bytes), Err(ProtocolError::LengthMismatch { declared, actual: declared + 1 }));
+    }
+
+    #[test]
+    fn bad_markers_and_kinds_are_rejected() {
+        let bytes = encode(&audio_packet());
+
+        let mut bad = bytes.clone();
+        bad[SEQUENCE_OFFSET] ^= 0xff;
+        assert_eq!(decode(&bad), Err(ProtocolError::InvalidSequenceMarker));
+        let mut bad = bytes.clone();
+        bad[SEQUENCE_OFFSET + SEQUENCE_NUM_SIZE - 1] ^= 0xff;
+        assert_eq!(decode(&bad), Err(ProtocolError::InvalidSequenceMarker));
+
+        let mut bad = bytes.clone();
+        bad[TIMESTAMP_OFFSET] ^= 0xff;
+        assert_eq!(decode(&bad), Err(ProtocolError::InvalidTimestampMarker));
+        let mut bad = bytes.clone();
+        bad[TIMESTAMP_OFFSET + TIMESTAMP_SIZE - 1] ^= 0xff;
+        assert_eq!(decode(&bad), Err(ProtocolError::InvalidTimestampMarker));
+
+        let mut bad = bytes;
+        bad[VERSION_SIZE] = 9;
+        assert_eq!(decode(&bad), Err(ProtocolError::InvalidPacketKind(9)));
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let mut payload = Vec::new();
+        pack_frame(&mut payload, &[1, 2, 3]);
+        pack_frame(&mut payload, &[4, 5, 6]);
+        // The second frame's last byte is missing
+        assert_eq!(unpack_frames(&payload[..payload.len() - 1]), Err(ProtocolError::InvalidFrame { offset: 5 }));
+        // Half a length prefix
+        assert_eq!(unpack_frames(&payload[..6]), Err(ProtocolError::InvalidFrame { offset: 5 }));
+        assert_eq!(unpack_frames(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn sealed_packets_only_open_untouched_and_with_the_right_key() {
+        let key = CrewKey::from_passphrase("crew");
+        let packet = audio_packet();
+        let sealed = seal(&packet, Some(&key));
+        assert_ne!(&sealed[HEADER_SIZE..HEADER_SIZE + packet.payload.len()], &packet.payload[..]);
+        assert_eq!(open(&sealed, Some(&key)), Ok(packet.clone()));
+
+        let mut tampered = sealed.clone();
+        tampered[GROUP_OFFSET] ^= 0x01;
+        assert_eq!(open(&tampered, Some(&key)), Err(ProtocolError::AuthenticationFailed));
+
+        let mut tampered = sealed.clone();
+        tampered[HEADER_SIZE] ^= 0x01;
+        assert_eq!(open(&tampered, Some(&key)), Err(ProtocolError::AuthenticationFailed));
+
+        let other = CrewKey::from_passphrase("not the crew");
+        assert_eq!(open(&sealed, Some(&other)), Err(ProtocolError::AuthenticationFailed));
+
+        assert_eq!(open(&sealed, None), Err(ProtocolError::Encrypted));
+        assert_eq!(open(&encode(&packet), Some(&key)), Err(ProtocolError::NotEncrypted));
+    }
+}
@@ -0,0 +1,174 @@
+use std::f64::consts::PI;
+
+// Band-limited resampling between the device rate and the 48 kHz the codec
+// and the network run at (Smith's windowed-sinc interpolation). The kernel
+// is tabulated once and linearly interpolated between table points.
+
+// Half-width of the kernel in zero crossings. More is sharper and slower.
+const ZERO_CROSSINGS: usize = 16;
+// Table points per zero crossing
+const RESOLUTION: usize = 512;
+// Keep the passband a little below Nyquist so the transition band doesn't alias
+const ROLLOFF: f64 = 0.95;
+
+pub struct Resampler {
+    channels: usize,
+    // Input frames per output frame
+    step: f64,
+    // Cutoff as a fraction of the input Nyquist frequency
+    scale: f64,
+    // Kernel reach in input frames, either side of the output position
+    reach: f64,
+    kernel: Vec<f32>,
+    // Input frames that are still needed, interleaved
+    history: Vec<f32>,
+    // Position of the next output frame, in frames into `history`
+    time: f64,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        let step = from_rate as f64 / to_rate as f64;
+        // Downsampling lowers the cutoff to the new Nyquist frequency
+        let scale = (to_rate as f64 / from_rate as f64).min(1.0) * ROLLOFF;
+        let reach = ZERO_CROSSINGS as f64 / scale;
+        let lead_in = reach.ceil() as usize;
+        Self {
+            channels,
+            step,
+            scale,
+            reach,
+            kernel: build_kernel(),
+            // Silence before the first sample, so the first output frame has
+            // the history it needs
+            history: vec![0.0; lead_in * channels],
+            time: lead_in as f64,
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.step == 1.0
+    }
+
+    // Resamples a block of interleaved frames. Output lags the input by the
+    // kernel's reach, a fraction of a millisecond.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.is_passthrough() {
+            return input.to_vec();
+        }
+        self.history.extend_from_slice(input);
+        let frames = self.history.len() / self.channels;
+
+        let mut output = Vec::with_capacity(((input.len() as f64 / self.step) as usize + 1) * self.channels);
+        while self.time + self.reach < frames as f64 {
+            let first = (self.time - self.reach).floor().max(0.0) as usize;
+            let last = ((self.time + self.reach).ceil() as usize).min(frames - 1);
+            for channel in 0..self.channels {
+                let mut sum = 0.0;
+                for frame in first..=last {
+                    let distance = (self.time - frame as f64) * self.scale;
+                    sum += self.history[frame * self.channels + channel] * self.tap(distance);
+                }
+                output.push(sum * self.scale as f32);
+            }
+            self.time += self.step;
+        }
+
+        // Drop the frames no future output can reach
+        let keep_from = ((self.time - self.reach).floor().max(0.0) as usize).min(frames);
+        self.history.drain(..keep_from * self.channels);
+        self.time -= keep_from as f64;
+        output
+    }
+
+    // Kernel value `distance` zero crossings from its centre
+    fn tap(&self, distance: f64) -> f32 {
+        let position = distance.abs() * RESOLUTION as f64;
+        let index = position as usize;
+        if index + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+        let fraction = (position - index as f64) as f32;
+        self.kernel[index] + (self.kernel[index + 1] - self.kernel[index]) * fraction
+    }
+}
+
+// One side of a Blackman-windowed sinc, out to ZERO_CROSSINGS
+fn build_kernel() -> Vec<f32> {
+    let length = ZERO_CROSSINGS * RESOLUTION;
+    (0..=length)
+        .map(|i| {
+            let x = i as f64 / RESOLUTION as f64;
+            let sinc = if i == 0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let w = 0.5 + 0.5 * i as f64 / length as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+            (sinc * window) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `seconds` of a sine at `frequency` and `amplitude`, the same on every channel
+    fn tone(frequency: f64, amplitude: f32, sample_rate: u32, channels: usize, seconds: f64) -> Vec<f32> {
+        let frames = (seconds * sample_rate as f64) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let sample = (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin() as f32 * amplitude;
+                std::iter::repeat_n(sample, channels)
+            })
+            .collect()
+    }
+
+    // Resamples `input` in 10 ms blocks, as the pipelines do
+    fn resample(resampler: &mut Resampler, input: &[f32], from_rate: u32, channels: usize) -> Vec<f32> {
+        let block = from_rate as usize / 100 * channels;
+        input.chunks(block).flat_map(|chunk| resampler.process(chunk)).collect()
+    }
+
+    #[test]
+    fn output_length_follows_the_rate() {
+        for (from_rate, to_rate, channels) in [(44100, 48000, 1), (48000, 44100, 2), (96000, 48000, 1)] {
+            let mut resampler = Resampler::new(from_rate, to_rate, channels);
+            let input = vec![0.0; from_rate as usize * channels];
+            let output = resample(&mut resampler, &input, from_rate, channels);
+            assert_eq!(output.len() % channels, 0);
+            // A second in gives a second out, less the kernel's lag
+            let frames = output.len() / channels;
+            let expected = to_rate as usize;
+            assert!(frames <= expected && expected - frames <= 40, "{} -> {}: {} frames", from_rate, to_rate, frames);
+            // Every later block comes out at the new rate
+            let block = resampler.process(&vec![0.0; from_rate as usize / 100 * channels]);
+            let block_frames = (block.len() / channels) as i64;
+            assert!((block_frames - to_rate as i64 / 100).abs() <= 1, "{} -> {}: {} frames", from_rate, to_rate, block_frames);
+        }
+    }
+
+    #[test]
+    fn equal_rates_pass_through() {
+        let mut resampler = Resampler::new(48000, 48000, 2);
+        assert!(resampler.is_passthrough());
+        let input = tone(440.0, 0.5, 48000, 2, 0.01);
+        assert_eq!(resampler.process(&input), input);
+        assert!(!Resampler::new(44100, 48000, 2).is_passthrough());
+    }
+
+    #[test]
+    fn a_tone_keeps_its_level_and_pitch() {
+        for (from_rate, to_rate) in [(44100, 48000), (48000, 44100)] {
+            let input = tone(1000.0, 0.5, from_rate, 1, 1.0);
+            let mut resampler = Resampler::new(from_rate, to_rate, 1);
+            let output = resample(&mut resampler, &input, from_rate, 1);
+            // Past the kernel's lead-in
+            let steady = &output[to_rate as usize / 100..];
+            let peak = steady.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            assert!((peak - 0.5).abs() < 0.01, "{} -> {}: peak {}", from_rate, to_rate, peak);
+            // Two zero crossings per cycle
+            let crossings = steady.windows(2).filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0)).count();
+            let expected = 2.0 * 1000.0 * steady.len() as f64 / to_rate as f64;
+            assert!((crossings as f64 - expected).abs() <= 3.0, "{} -> {}: {} crossings, expected {}", from_rate, to_rate, crossings, expected);
+        }
+    }
+}
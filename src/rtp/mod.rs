@@ -0,0 +1,236 @@
+pub mod rtcp;
+
+use std::fmt;
+use std::time::Instant;
+use rtp_rs::{RtpPacketBuilder, RtpReader, Seq};
+use crate::rtp::rtcp::{ReportBlock, SenderReport};
+
+// Dynamic payload type conventionally used for Opus
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+// RFC 7587: the RTP clock for Opus always runs at 48 kHz, whatever the coded rate
+pub const OPUS_CLOCK_RATE: u32 = 48000;
+
+// How packets are framed on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    // The crate's own format, see `protocol`
+    Native,
+    // One Opus frame per RTP packet (RFC 7587), RTCP multiplexed on the same port
+    Rtp,
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::Native => write!(f, "native"),
+            Framing::Rtp => write!(f, "rtp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtpError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnsupportedPayloadType(u8),
+    Malformed(String),
+}
+
+impl fmt::Display for RtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtpError::Truncated => write!(f, "packet truncated"),
+            RtpError::UnsupportedVersion(version) => write!(f, "unsupported RTP version {}", version),
+            RtpError::UnsupportedPayloadType(pt) => write!(f, "unsupported payload type {}", pt),
+            RtpError::Malformed(reason) => write!(f, "malformed packet: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RtpError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpFrame {
+    pub ssrc: u32,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub marker: bool,
+    pub payload: Vec<u8>,
+}
+
+// Sending side of one talker's stream.
+pub struct RtpSender {
+    ssrc: u32,
+    sequence: Seq,
+    timestamp: u32,
+    packet_count: u32,
+    octet_count: u32,
+}
+
+impl RtpSender {
+    pub fn new() -> Self {
+        Self {
+            ssrc: rand::random(),
+            sequence: Seq::from(rand::random::<u16>()),
+            timestamp: rand::random(),
+            packet_count: 0,
+            octet_count: 0,
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    // Wraps one Opus frame. `samples` is the frame length in 48 kHz ticks.
+    pub fn packetize(&mut self, frame: &[u8], samples: u32, marker: bool) -> Vec<u8> {
+        let packet = RtpPacketBuilder::new()
+            .payload_type(OPUS_PAYLOAD_TYPE)
+            .ssrc(self.ssrc)
+            .sequence(self.sequence)
+            .timestamp(self.timestamp)
+            .marked(marker)
+            .payload(frame)
+            .build()
+            .expect("RTP: Failed to build packet");
+        self.sequence = self.sequence.next();
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        self.packet_count = self.packet_count.wrapping_add(1);
+        self.octet_count = self.octet_count.wrapping_add(frame.len() as u32);
+        packet
+    }
+
+    pub fn sender_report(&self) -> SenderReport {
+        SenderReport {
+            ssrc: self.ssrc,
+            ntp_timestamp: rtcp::ntp_now(),
+            rtp_timestamp: self.timestamp,
+            packet_count: self.packet_count,
+            octet_count: self.octet_count,
+            reports: Vec::new(),
+        }
+    }
+}
+
+impl Default for RtpSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn depacketize(bytes: &[u8]) -> Result<RtpFrame, RtpError> {
+    let reader = RtpReader::new(bytes).map_err(|e| match e {
+        rtp_rs::RtpReaderError::BufferTooShort(_) => RtpError::Truncated,
+        rtp_rs::RtpReaderError::UnsupportedVersion(version) => RtpError::UnsupportedVersion(version),
+        other => RtpError::Malformed(format!("{:?}", other)),
+    })?;
+    if reader.payload_type() != OPUS_PAYLOAD_TYPE {
+        return Err(RtpError::UnsupportedPayloadType(reader.payload_type()));
+    }
+    Ok(RtpFrame {
+        ssrc: reader.ssrc(),
+        sequence_number: reader.sequence_number().into(),
+        timestamp: reader.timestamp(),
+        marker: reader.mark(),
+        payload: reader.payload().to_vec(),
+    })
+}
+
+// Receiving side bookkeeping for one SSRC, following RFC 3550 appendix A.
+pub struct ReceptionStats {
+    ssrc: u32,
+    base_sequence: u32,
+    max_sequence: u16,
+    cycles: u32,
+    received: u32,
+    expected_prior: u32,
+    received_prior: u32,
+    // Interarrival jitter in RTP timestamp units, scaled by 16
+    jitter: u32,
+    last_transit: Option<i64>,
+    clock_start: Instant,
+    last_sender_report: Option<(u32, Instant)>,
+}
+
+impl ReceptionStats {
+    pub fn new(first: &RtpFrame) -> Self {
+        Self {
+            ssrc: first.ssrc,
+            base_sequence: first.sequence_number as u32,
+            max_sequence: first.sequence_number,
+            cycles: 0,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            jitter: 0,
+            last_transit: None,
+            clock_start: Instant::now(),
+            last_sender_report: None,
+        }
+    }
+
+    // Records an arriving frame and returns its 32-bit extended sequence number.
+    pub fn update(&mut self, frame: &RtpFrame) -> u32 {
+        self.received += 1;
+        let delta = Seq::from(frame.sequence_number) - Seq::from(self.max_sequence);
+        if delta > 0 {
+            if frame.sequence_number < self.max_sequence {
+                self.cycles = self.cycles.wrapping_add(1 << 16);
+            }
+            self.max_sequence = frame.sequence_number;
+        }
+        let cycles = if delta < 0 && frame.sequence_number > self.max_sequence {
+            // A reordered packet from before the last wrap
+            self.cycles.wrapping_sub(1 << 16)
+        } else {
+            self.cycles
+        };
+
+        let arrival = (self.clock_start.elapsed().as_secs_f64() * OPUS_CLOCK_RATE as f64) as i64;
+        let transit = arrival - frame.timestamp as i64;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).unsigned_abs() as u32;
+            self.jitter = self.jitter.wrapping_add(d).wrapping_sub((self.jitter + 8) >> 4);
+        }
+        self.last_transit = Some(transit);
+
+        cycles | frame.sequence_number as u32
+    }
+
+    pub fn on_sender_report(&mut self, report: &SenderReport) {
+        self.last_sender_report = Some((rtcp::ntp_middle(report.ntp_timestamp), Instant::now()));
+    }
+
+    pub fn report_block(&mut self) -> ReportBlock {
+        let extended_max = self.cycles | self.max_sequence as u32;
+        let expected = extended_max.wrapping_sub(self.base_sequence).wrapping_add(1);
+        let lost = expected.saturating_sub(self.received);
+
+        let expected_interval = expected.wrapping_sub(self.expected_prior);
+        let received_interval = self.received.wrapping_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction_lost = if expected_interval == 0 {
+            0
+        } else {
+            (((lost_interval as u64) << 8) / expected_interval as u64) as u8
+        };
+
+        let (last_sender_report, delay_since_last_sender_report) = match self.last_sender_report {
+            // DLSR is expressed in units of 1/65536 seconds
+            Some((lsr, at)) => (lsr, (at.elapsed().as_secs_f64() * 65536.0) as u32),
+            None => (0, 0),
+        };
+
+        ReportBlock {
+            ssrc: self.ssrc,
+            fraction_lost,
+            cumulative_lost: lost,
+            extended_highest_sequence: extended_max,
+            jitter: self.jitter >> 4,
+            last_sender_report,
+            delay_since_last_sender_report,
+        }
+    }
+}
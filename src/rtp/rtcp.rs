@@ -0,0 +1,169 @@
+use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::RtpError;
+
+pub const SENDER_REPORT: u8 = 200;
+pub const RECEIVER_REPORT: u8 = 201;
+
+// Seconds between the NTP epoch (1900) and the UNIX epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    pub fraction_lost: u8,
+    // Only the low 24 bits go on the wire
+    pub cumulative_lost: u32,
+    pub extended_highest_sequence: u32,
+    pub jitter: u32,
+    pub last_sender_report: u32,
+    pub delay_since_last_sender_report: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SenderReport {
+    pub ssrc: u32,
+    pub ntp_timestamp: u64,
+    pub rtp_timestamp: u32,
+    pub packet_count: u32,
+    pub octet_count: u32,
+    pub reports: Vec<ReportBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiverReport {
+    pub ssrc: u32,
+    pub reports: Vec<ReportBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtcpPacket {
+    SenderReport(SenderReport),
+    ReceiverReport(ReceiverReport),
+}
+
+pub fn ntp_now() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let seconds = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (seconds << 32) | fraction
+}
+
+// The middle 32 bits of an NTP timestamp, as used by the LSR/DLSR fields
+pub fn ntp_middle(ntp_timestamp: u64) -> u32 {
+    (ntp_timestamp >> 16) as u32
+}
+
+fn write_header(bytes: &mut Vec<u8>, report_count: usize, packet_type: u8, length_words: usize) {
+    bytes.push(0x80 | (report_count as u8 & 0x1F));
+    bytes.push(packet_type);
+    bytes.write_u16::<BigEndian>(length_words as u16).unwrap();
+}
+
+fn write_block(bytes: &mut Vec<u8>, block: &ReportBlock) {
+    bytes.write_u32::<BigEndian>(block.ssrc).unwrap();
+    bytes.write_u32::<BigEndian>(
+        ((block.fraction_lost as u32) << 24) | (block.cumulative_lost & 0x00FF_FFFF)
+    ).unwrap();
+    bytes.write_u32::<BigEndian>(block.extended_highest_sequence).unwrap();
+    bytes.write_u32::<BigEndian>(block.jitter).unwrap();
+    bytes.write_u32::<BigEndian>(block.last_sender_report).unwrap();
+    bytes.write_u32::<BigEndian>(block.delay_since_last_sender_report).unwrap();
+}
+
+fn read_block(cursor: &mut Cursor<&[u8]>) -> std::io::Result<ReportBlock> {
+    let ssrc = cursor.read_u32::<BigEndian>()?;
+    let loss = cursor.read_u32::<BigEndian>()?;
+    Ok(ReportBlock {
+        ssrc,
+        fraction_lost: (loss >> 24) as u8,
+        cumulative_lost: loss & 0x00FF_FFFF,
+        extended_highest_sequence: cursor.read_u32::<BigEndian>()?,
+        jitter: cursor.read_u32::<BigEndian>()?,
+        last_sender_report: cursor.read_u32::<BigEndian>()?,
+        delay_since_last_sender_report: cursor.read_u32::<BigEndian>()?,
+    })
+}
+
+impl SenderReport {
+    pub fn encode(&self) -> Vec<u8> {
+        // Header (1 word) + sender info (6 words) + 6 words per report block
+        let words = 7 + 6 * self.reports.len();
+        let mut bytes = Vec::with_capacity(words * 4);
+        write_header(&mut bytes, self.reports.len(), SENDER_REPORT, words - 1);
+        bytes.write_u32::<BigEndian>(self.ssrc).unwrap();
+        bytes.write_u64::<BigEndian>(self.ntp_timestamp).unwrap();
+        bytes.write_u32::<BigEndian>(self.rtp_timestamp).unwrap();
+        bytes.write_u32::<BigEndian>(self.packet_count).unwrap();
+        bytes.write_u32::<BigEndian>(self.octet_count).unwrap();
+        for block in &self.reports {
+            write_block(&mut bytes, block);
+        }
+        bytes
+    }
+}
+
+impl ReceiverReport {
+    pub fn encode(&self) -> Vec<u8> {
+        let words = 2 + 6 * self.reports.len();
+        let mut bytes = Vec::with_capacity(words * 4);
+        write_header(&mut bytes, self.reports.len(), RECEIVER_REPORT, words - 1);
+        bytes.write_u32::<BigEndian>(self.ssrc).unwrap();
+        for block in &self.reports {
+            write_block(&mut bytes, block);
+        }
+        bytes
+    }
+}
+
+// RTCP is multiplexed on the RTP port (RFC 5761); the second byte tells them apart.
+pub fn is_rtcp(bytes: &[u8]) -> bool {
+    bytes.len() >= 8 && bytes[0] >> 6 == 2 && (192..=223).contains(&bytes[1])
+}
+
+pub fn decode(bytes: &[u8]) -> Result<RtcpPacket, RtpError> {
+    if bytes.len() < 8 {
+        return Err(RtpError::Truncated);
+    }
+    if bytes[0] >> 6 != 2 {
+        return Err(RtpError::UnsupportedVersion(bytes[0] >> 6));
+    }
+    let report_count = (bytes[0] & 0x1F) as usize;
+    let packet_type = bytes[1];
+    let mut cursor = Cursor::new(&bytes[4..]);
+    let truncated = |_| RtpError::Truncated;
+
+    match packet_type {
+        SENDER_REPORT => {
+            let ssrc = cursor.read_u32::<BigEndian>().map_err(truncated)?;
+            let ntp_timestamp = cursor.read_u64::<BigEndian>().map_err(truncated)?;
+            let rtp_timestamp = cursor.read_u32::<BigEndian>().map_err(truncated)?;
+            let packet_count = cursor.read_u32::<BigEndian>().map_err(truncated)?;
+            let octet_count = cursor.read_u32::<BigEndian>().map_err(truncated)?;
+            let reports = (0..report_count)
+                .map(|_| read_block(&mut cursor))
+                .collect::<std::io::Result<Vec<_>>>()
+                .map_err(truncated)?;
+            Ok(RtcpPacket::SenderReport(SenderReport {
+                ssrc,
+                ntp_timestamp,
+                rtp_timestamp,
+                packet_count,
+                octet_count,
+                reports,
+            }))
+        }
+        RECEIVER_REPORT => {
+            let ssrc = cursor.read_u32::<BigEndian>().map_err(truncated)?;
+            let reports = (0..report_count)
+                .map(|_| read_block(&mut cursor))
+                .collect::<std::io::Result<Vec<_>>>()
+                .map_err(truncated)?;
+            Ok(RtcpPacket::ReceiverReport(ReceiverReport { ssrc, reports }))
+        }
+        other => Err(RtpError::UnsupportedPayloadType(other)),
+    }
+}
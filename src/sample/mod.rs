@@ -0,0 +1,166 @@
+use cpal::traits::DeviceTrait;
+use cpal::{FromSample, Sample, SampleFormat, SizedSample};
+use log::debug;
+
+// Everything inside the application is interleaved f32. These helpers build
+// cpal streams in whatever format the device wants and convert at the edge,
+// so the rest of the code never sees a device sample type.
+//
+// cpal 0.15 has no packed 24-bit format; 24-bit devices show up as I32.
+
+// Triangular (TPDF) dither of one LSB of the target format. Truncating to a
+// narrow integer format without it turns quiet passages into distortion.
+pub struct Dither {
+    lsb: f32,
+    state: u32,
+}
+
+impl Dither {
+    // Float and 32/64-bit formats are left alone, their step is far below
+    // anything audible.
+    pub fn for_format(sample_format: SampleFormat) -> Self {
+        let bits = sample_format.sample_size() * 8;
+        let lsb = if sample_format.is_float() || bits >= 32 {
+            0.0
+        } else {
+            1.0 / (1u32 << (bits - 1)) as f32
+        };
+        Self { lsb, state: 0x9E37_79B9 }
+    }
+
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        if self.lsb == 0.0 {
+            return sample;
+        }
+        // Sum of two uniform values is triangular on (-1, 1)
+        let triangular = self.uniform() - self.uniform();
+        sample + triangular * self.lsb
+    }
+
+    // xorshift32 mapped to [0, 1)
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / (u32::MAX as f32 + 1.0)
+    }
+}
+
+// Converts a block of f32 samples into the device format, dithering first
+pub fn write_samples<T>(output: &mut [T], input: &[f32], dither: &mut Dither)
+where
+    T: Sample + FromSample<f32>,
+{
+    for (out, sample) in output.iter_mut().zip(input) {
+        *out = T::from_sample(dither.apply(*sample).clamp(-1.0, 1.0));
+    }
+}
+
+pub fn read_samples<T>(input: &[T], output: &mut Vec<f32>)
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    output.clear();
+    output.extend(input.iter().map(|sample| f32::from_sample(*sample)));
+}
+
+// Builds an output stream in `sample_format`. `fill` is handed an f32
+// buffer the size of the device's, to be filled with interleaved samples.
+pub fn build_output_stream<F>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sample_format: SampleFormat,
+    fill: F,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    F: FnMut(&mut [f32]) + Send + 'static,
+{
+    debug!("SAMPLE: Building output stream with format {}", sample_format);
+    match sample_format {
+        SampleFormat::I8 => output_stream::<i8, F>(device, config, fill),
+        SampleFormat::I16 => output_stream::<i16, F>(device, config, fill),
+        SampleFormat::I32 => output_stream::<i32, F>(device, config, fill),
+        SampleFormat::I64 => output_stream::<i64, F>(device, config, fill),
+        SampleFormat::U8 => output_stream::<u8, F>(device, config, fill),
+        SampleFormat::U16 => output_stream::<u16, F>(device, config, fill),
+        SampleFormat::U32 => output_stream::<u32, F>(device, config, fill),
+        SampleFormat::U64 => output_stream::<u64, F>(device, config, fill),
+        SampleFormat::F32 => output_stream::<f32, F>(device, config, fill),
+        SampleFormat::F64 => output_stream::<f64, F>(device, config, fill),
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+// Builds an input stream in `sample_format`. `handle` receives every
+// callback's worth of interleaved samples converted to f32.
+pub fn build_input_stream<F>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sample_format: SampleFormat,
+    handle: F,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    F: FnMut(&[f32]) + Send + 'static,
+{
+    debug!("SAMPLE: Building input stream with format {}", sample_format);
+    match sample_format {
+        SampleFormat::I8 => input_stream::<i8, F>(device, config, handle),
+        SampleFormat::I16 => input_stream::<i16, F>(device, config, handle),
+        SampleFormat::I32 => input_stream::<i32, F>(device, config, handle),
+        SampleFormat::I64 => input_stream::<i64, F>(device, config, handle),
+        SampleFormat::U8 => input_stream::<u8, F>(device, config, handle),
+        SampleFormat::U16 => input_stream::<u16, F>(device, config, handle),
+        SampleFormat::U32 => input_stream::<u32, F>(device, config, handle),
+        SampleFormat::U64 => input_stream::<u64, F>(device, config, handle),
+        SampleFormat::F32 => input_stream::<f32, F>(device, config, handle),
+        SampleFormat::F64 => input_stream::<f64, F>(device, config, handle),
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+fn output_stream<T, F>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut fill: F,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+    F: FnMut(&mut [f32]) + Send + 'static,
+{
+    let mut dither = Dither::for_format(T::FORMAT);
+    let mut scratch: Vec<f32> = Vec::new();
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            scratch.clear();
+            scratch.resize(data.len(), 0.0);
+            fill(&mut scratch);
+            write_samples(data, &scratch, &mut dither);
+        },
+        |err| log::error!("SAMPLE: Output stream error: {}", err),
+        None,
+    )
+}
+
+fn input_stream<T, F>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut handle: F,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+    F: FnMut(&[f32]) + Send + 'static,
+{
+    let mut scratch: Vec<f32> = Vec::new();
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            read_samples(data, &mut scratch);
+            handle(&scratch);
+        },
+        |err| log::error!("SAMPLE: Input stream error: {}", err),
+        None,
+    )
+}
@@ -0,0 +1,154 @@
+use std::any::Any;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use log::{debug, warn};
+
+// How long blocking socket reads wait before a thread checks whether its
+// session was stopped
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A read that ran into POLL_INTERVAL rather than failing. Which of the two
+// kinds shows up depends on the platform.
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// An error a UDP socket reports about an earlier send rather than about
+// itself. Windows turns the ICMP port unreachable from a peer that left into
+// a ConnectionReset on the next receive, Linux into ConnectionRefused.
+pub fn is_peer_gone(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused)
+}
+
+// `recv_from` for threads that must notice their session stopping. The
+// socket needs POLL_INTERVAL as its read timeout. Errors are logged and
+// receiving goes on, one socket error must not end a receive thread for
+// good. Returns None only once stopped.
+pub fn recv_until_stopped(socket: &UdpSocket, buf: &mut [u8], stop: &StopFlag) -> Option<(usize, SocketAddr)> {
+    while !stop.is_stopped() {
+        match socket.recv_from(buf) {
+            Ok(received) => return Some(received),
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) if is_peer_gone(&e) => debug!("SESSION: A peer is gone: {}", e),
+            Err(e) => {
+                warn!("SESSION: Receive failed: {}", e);
+                // Don't spin on an error that keeps coming back
+                stop.sleep(POLL_INTERVAL);
+            }
+        }
+    }
+    None
+}
+
+// Cooperative stop signal shared by a session's threads. Sleeping on it
+// wakes up as soon as the session is stopped.
+#[derive(Debug, Clone, Default)]
+pub struct StopFlag(Arc<(Mutex<bool>, Condvar)>);
+
+impl StopFlag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn stop(&self) {
+        let (stopped, condvar) = &*self.0;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+    pub fn is_stopped(&self) -> bool {
+        *self.0.0.lock().unwrap()
+    }
+    // Sleeps for `duration` or until stopped. Returns true if stopped.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (stopped, condvar) = &*self.0;
+        let guard = stopped.lock().unwrap();
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, duration, |stopped| !*stopped)
+            .unwrap();
+        *guard
+    }
+    // Blocks until stopped
+    pub fn wait(&self) {
+        let (stopped, condvar) = &*self.0;
+        let guard = stopped.lock().unwrap();
+        let _guard = condvar.wait_while(guard, |stopped| !*stopped).unwrap();
+    }
+}
+
+// A running pipeline: the threads it spawned and whatever has to stay alive
+// while it runs, like cpal streams or a sine generator. Sockets belong to
+// the threads using them and close when those threads are joined.
+//
+// Stopping, or dropping, the session raises its stop flag, drops the held
+// resources so sources fall silent and their channels close, then joins
+// every thread in the order it was spawned.
+pub struct Session {
+    name: String,
+    stop: StopFlag,
+    threads: Vec<(String, JoinHandle<()>)>,
+    resources: Vec<Box<dyn Any>>,
+}
+
+impl Session {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            stop: StopFlag::new(),
+            threads: Vec::new(),
+            resources: Vec::new(),
+        }
+    }
+
+    pub fn stop_flag(&self) -> StopFlag {
+        self.stop.clone()
+    }
+
+    pub fn spawn<F>(&mut self, name: &str, f: F)
+    where
+        F: FnOnce(StopFlag) + Send + 'static,
+    {
+        let stop = self.stop.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("{} {}", self.name, name))
+            .spawn(move || f(stop))
+            .expect("Failed to spawn session thread");
+        self.threads.push((name.to_string(), handle));
+    }
+
+    // Keeps `resource` alive until the session stops
+    pub fn hold<T: 'static>(&mut self, resource: T) {
+        self.resources.push(Box::new(resource));
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.stop.is_stopped()
+    }
+
+    pub fn stop(&mut self) {
+        if self.threads.is_empty() && self.resources.is_empty() {
+            return;
+        }
+        debug!("SESSION: Stopping {}", self.name);
+        self.stop.stop();
+        self.resources.clear();
+        self.join();
+        debug!("SESSION: {} stopped", self.name);
+    }
+
+    // Waits for every thread to finish on its own
+    pub fn join(&mut self) {
+        for (name, handle) in self.threads.drain(..) {
+            if handle.join().is_err() {
+                warn!("SESSION: {} thread '{}' panicked", self.name, name);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -1,65 +1,629 @@
-use cpal::Device;
-use cpal::traits::{DeviceTrait, HostTrait};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Deserialize;
+use log::{debug, info, warn};
+use crate::devices::{self, DeviceError, DeviceSelector};
+use crate::backend::{AudioBackend, AudioFormat, BackendError, BackendKind, CpalBackend, FileBackend, NullBackend, DEFAULT_FORMAT};
+use crate::channel_map::{InputMap, OutputMap, MAX_STREAM_CHANNELS};
+use crate::crypto::CrewKey;
+use crate::group::{self, TalkGroup};
+use crate::network::{self, InterfaceSelection};
+use crate::protocol::MAX_FRAMES_PER_PACKET;
+use crate::rtp::Framing;
+
+pub const DEFAULT_SERVER_PORT: u16 = 18521;
+pub const DEFAULT_CLIENT_PORT: u16 = 18522;
+pub const DEFAULT_BITRATE: i32 = 64000;
+// One Opus frame per packet keeps a lost packet short and latency low
+pub const DEFAULT_FRAMES_PER_PACKET: usize = 1;
+pub const DEFAULT_SERVICE_TYPE: &str = "_udp_voice._udp.local.";
+// Read from the working directory when no --config is given
+pub const DEFAULT_CONFIG_FILE: &str = "udp_voice.toml";
+// How long the sine binary plays without a duration, in milliseconds
+pub const DEFAULT_DURATION: u64 = 3000;
+
+// Frame sizes Opus accepts at 48 kHz, 2.5 to 60 ms
+const OPUS_FRAME_SIZES: [usize; 6] = [120, 240, 480, 960, 1920, 2880];
+const DEFAULT_BUFFER_SIZE: usize = 960;
 
 pub trait Settings {
     fn get_default_settings() -> Self;
 }
 
-pub struct ApplicationSettings {
-    // System Config
-    #[allow(dead_code)]
-    host: cpal::Host,
-    devices: (cpal::Device, cpal::Device),
-    config_files: (cpal::SupportedStreamConfig, cpal::SupportedStreamConfig),
-    sample_rate: cpal::SampleRate,
-    channels: cpal::ChannelCount,
-    buffer_size: usize,
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    // A value that parsed but makes no sense, e.g. a 1000 sample Opus frame
+    Invalid { key: &'static str, reason: String },
+    Device(DeviceError),
+    Backend(BackendError),
 }
 
-impl Settings for ApplicationSettings {
-    fn get_default_settings() -> Self {
-        let host = cpal::default_host();
-        let (input_device, output_device) = (
-            host.default_input_device().unwrap(),
-            host.default_output_device().unwrap(),
-            );
-
-        let (input_config, output_config) = (
-            input_device.default_input_config()
-            .expect("Failed to get output_config"),
-            output_device.default_output_config()
-            .expect("Failed to get output_config")
-            );
-        let _sample_rate = output_config
-            .sample_rate();
-        let channels = output_config
-            .channels();
-         let default_buffer_size = 960;
-         let buffer_size = match output_config.buffer_size() {
-            cpal::SupportedBufferSize::Range { min, max } => {
-                if default_buffer_size >= *min as usize && default_buffer_size <= *max as usize {
-                    default_buffer_size
-                } else {
-                    *max as usize
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io(path, e) => write!(f, "Can't read {}: {}", path.display(), e),
+            SettingsError::Parse(path, e) => write!(f, "Can't parse {}: {}", path.display(), e),
+            SettingsError::Invalid { key, reason } => write!(f, "Invalid {}: {}", key, reason),
+            SettingsError::Device(e) => write!(f, "{}", e),
+            SettingsError::Backend(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SettingsError {}
+
+impl From<DeviceError> for SettingsError {
+    fn from(e: DeviceError) -> Self {
+        SettingsError::Device(e)
+    }
+}
+
+impl From<BackendError> for SettingsError {
+    fn from(e: BackendError) -> Self {
+        SettingsError::Backend(e)
+    }
+}
+
+// Everything that can be configured. Each source (file, environment,
+// command line) fills in what it knows and later sources win.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Overrides {
+    backend: Option<String>,
+    host: Option<String>,
+    // Device name, or index as listed by --list-devices
+    input_device: Option<String>,
+    output_device: Option<String>,
+    // File backend
+    input_file: Option<PathBuf>,
+    output_file: Option<PathBuf>,
+    speed: Option<f32>,
+    buffer_size: Option<usize>,
+    // Opus frame length in ms, another way to give buffer_size. Wins over
+    // buffer_size when both are set.
+    frame_duration: Option<f32>,
+    frames_per_packet: Option<usize>,
+    port: Option<u16>,
+    // The port the server announces over mDNS, when something in front of
+    // it, such as netsim, takes its traffic
+    advertise_port: Option<u16>,
+    // "all", or interface names separated by commas, as listed by
+    // --list-interfaces
+    interfaces: Option<String>,
+    bitrate: Option<i32>,
+    service_type: Option<String>,
+    // Crew passphrase; without one audio goes out unencrypted
+    key: Option<String>,
+    // RTP framing instead of the native packet format
+    rtp: Option<bool>,
+    // Talk groups the server listens to, separated by commas
+    groups: Option<String>,
+    // Channel maps, channels counted from 1 like on the hardware. An input
+    // channel wins over stream_channels.
+    input_channel: Option<usize>,
+    stream_channels: Option<usize>,
+    // Such as "3,4"
+    output_channels: Option<String>,
+    // How long the sine binary plays, in milliseconds
+    duration: Option<u64>,
+    tone: ToneOverrides,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ToneOverrides {
+    frequency: Option<f32>,
+    amplitude: Option<f32>,
+}
+
+impl Overrides {
+    fn merge(&mut self, other: Overrides) {
+        self.backend = other.backend.or(self.backend.take());
+        self.host = other.host.or(self.host.take());
+        self.input_device = other.input_device.or(self.input_device.take());
+        self.output_device = other.output_device.or(self.output_device.take());
+        self.input_file = other.input_file.or(self.input_file.take());
+        self.output_file = other.output_file.or(self.output_file.take());
+        self.speed = other.speed.or(self.speed);
+        self.buffer_size = other.buffer_size.or(self.buffer_size);
+        self.frame_duration = other.frame_duration.or(self.frame_duration);
+        self.frames_per_packet = other.frames_per_packet.or(self.frames_per_packet);
+        self.port = other.port.or(self.port);
+        self.advertise_port = other.advertise_port.or(self.advertise_port);
+        self.interfaces = other.interfaces.or(self.interfaces.take());
+        self.bitrate = other.bitrate.or(self.bitrate);
+        self.service_type = other.service_type.or(self.service_type.take());
+        self.key = other.key.or(self.key.take());
+        self.rtp = other.rtp.or(self.rtp);
+        self.groups = other.groups.or(self.groups.take());
+        self.input_channel = other.input_channel.or(self.input_channel);
+        self.stream_channels = other.stream_channels.or(self.stream_channels);
+        self.output_channels = other.output_channels.or(self.output_channels.take());
+        self.duration = other.duration.or(self.duration);
+        self.tone.frequency = other.tone.frequency.or(self.tone.frequency);
+        self.tone.amplitude = other.tone.amplitude.or(self.tone.amplitude);
+    }
+
+    // Sets one value from its textual form, as found in the environment or
+    // on the command line
+    fn set(&mut self, key: &'static str, value: String) -> Result<(), SettingsError> {
+        fn parse<T: std::str::FromStr>(key: &'static str, value: &str) -> Result<T, SettingsError> {
+            value.parse().map_err(|_| SettingsError::Invalid { key, reason: format!("'{}' is not a valid value", value) })
+        }
+        match key {
+            "backend" => self.backend = Some(value),
+            "host" => self.host = Some(value),
+            "input_device" => self.input_device = Some(value),
+            "output_device" => self.output_device = Some(value),
+            "input_file" => self.input_file = Some(PathBuf::from(value)),
+            "output_file" => self.output_file = Some(PathBuf::from(value)),
+            "speed" => self.speed = Some(parse(key, &value)?),
+            "buffer_size" => self.buffer_size = Some(parse(key, &value)?),
+            "frame_duration" => self.frame_duration = Some(parse(key, &value)?),
+            "frames_per_packet" => self.frames_per_packet = Some(parse(key, &value)?),
+            "port" => self.port = Some(parse(key, &value)?),
+            "advertise_port" => self.advertise_port = Some(parse(key, &value)?),
+            "interfaces" => self.interfaces = Some(value),
+            "bitrate" => self.bitrate = Some(parse(key, &value)?),
+            "service_type" => self.service_type = Some(value),
+            "key" => self.key = Some(value),
+            "rtp" => self.rtp = Some(parse_switch(key, &value)?),
+            "groups" => self.groups = Some(value),
+            "input_channel" => self.input_channel = Some(parse(key, &value)?),
+            "stream_channels" => self.stream_channels = Some(parse(key, &value)?),
+            "output_channels" => self.output_channels = Some(value),
+            "duration" => self.duration = Some(parse(key, &value)?),
+            "tone.frequency" => self.tone.frequency = Some(parse(key, &value)?),
+            "tone.amplitude" => self.tone.amplitude = Some(parse(key, &value)?),
+            _ => unreachable!("Unknown setting {}", key),
+        }
+        Ok(())
+    }
+}
+
+// Setting, command line flag and environment variable
+const KEYS: [(&str, &str, &str); 23] = [
+    ("backend", "--backend", "UDP_VOICE_BACKEND"),
+    ("host", "--host", "UDP_VOICE_HOST"),
+    ("input_device", "--input-device", "UDP_VOICE_INPUT_DEVICE"),
+    ("output_device", "--output-device", "UDP_VOICE_OUTPUT_DEVICE"),
+    ("input_file", "--input-file", "UDP_VOICE_INPUT_FILE"),
+    ("output_file", "--output-file", "UDP_VOICE_OUTPUT_FILE"),
+    ("speed", "--speed", "UDP_VOICE_SPEED"),
+    ("buffer_size", "--buffer-size", "UDP_VOICE_BUFFER_SIZE"),
+    ("frame_duration", "--frame-duration", "UDP_VOICE_FRAME_DURATION"),
+    ("frames_per_packet", "--frames-per-packet", "UDP_VOICE_FRAMES_PER_PACKET"),
+    ("port", "--port", "UDP_VOICE_PORT"),
+    ("advertise_port", "--advertise-port", "UDP_VOICE_ADVERTISE_PORT"),
+    ("interfaces", "--interfaces", "UDP_VOICE_INTERFACES"),
+    ("bitrate", "--bitrate", "UDP_VOICE_BITRATE"),
+    ("service_type", "--service-type", "UDP_VOICE_SERVICE_TYPE"),
+    ("key", "--key", "UDP_VOICE_KEY"),
+    ("groups", "--groups", "UDP_VOICE_GROUPS"),
+    ("input_channel", "--input-channel", "UDP_VOICE_INPUT_CHANNEL"),
+    ("stream_channels", "--stream-channels", "UDP_VOICE_STREAM_CHANNELS"),
+    ("output_channels", "--output-channels", "UDP_VOICE_OUTPUT_CHANNELS"),
+    ("duration", "--duration", "UDP_VOICE_DURATION"),
+    ("tone.frequency", "--frequency", "UDP_VOICE_TONE_FREQUENCY"),
+    ("tone.amplitude", "--amplitude", "UDP_VOICE_TONE_AMPLITUDE"),
+];
+
+// Settings a bare flag turns on. Their variables take 1 or 0, true or false.
+const SWITCHES: [(&str, &str, &str); 1] = [
+    ("rtp", "--rtp", "UDP_VOICE_RTP"),
+];
+
+// Builds ApplicationSettings from defaults, a TOML file, environment
+// variables and command line flags, in that order of precedence:
+//
+//     let settings = SettingsBuilder::new()
+//         .port(DEFAULT_SERVER_PORT)
+//         .load()?
+//         .build()?;
+//
+// Setters called before `load` act as defaults, after it as overrides.
+#[derive(Clone, Default)]
+pub struct SettingsBuilder {
+    overrides: Overrides,
+    // Replaces whatever backend the overrides would open
+    audio_backend: Option<Arc<dyn AudioBackend>>,
+}
+
+impl SettingsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.overrides.backend = Some(backend.to_string());
+        self
+    }
+    // A backend built in code, such as a MemoryBackend
+    pub fn audio_backend(mut self, backend: Arc<dyn AudioBackend>) -> Self {
+        self.audio_backend = Some(backend);
+        self
+    }
+    // Input and output files for the file backend
+    pub fn files(mut self, input: Option<&Path>, output: Option<&Path>) -> Self {
+        self.overrides.input_file = input.map(Path::to_path_buf);
+        self.overrides.output_file = output.map(Path::to_path_buf);
+        self
+    }
+    pub fn speed(mut self, speed: f32) -> Self {
+        self.overrides.speed = Some(speed);
+        self
+    }
+    pub fn host(mut self, name: &str) -> Self {
+        self.overrides.host = Some(name.to_string());
+        self
+    }
+    pub fn input_device(mut self, name: &str) -> Self {
+        self.overrides.input_device = Some(name.to_string());
+        self
+    }
+    pub fn output_device(mut self, name: &str) -> Self {
+        self.overrides.output_device = Some(name.to_string());
+        self
+    }
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.overrides.buffer_size = Some(buffer_size);
+        self
+    }
+    pub fn frame_duration(mut self, milliseconds: f32) -> Self {
+        self.overrides.frame_duration = Some(milliseconds);
+        self
+    }
+    pub fn frames_per_packet(mut self, frames: usize) -> Self {
+        self.overrides.frames_per_packet = Some(frames);
+        self
+    }
+    pub fn port(mut self, port: u16) -> Self {
+        self.overrides.port = Some(port);
+        self
+    }
+    pub fn interfaces(mut self, selection: &InterfaceSelection) -> Self {
+        self.overrides.interfaces = Some(selection.to_string());
+        self
+    }
+    pub fn bitrate(mut self, bitrate: i32) -> Self {
+        self.overrides.bitrate = Some(bitrate);
+        self
+    }
+    pub fn service_type(mut self, service_type: &str) -> Self {
+        self.overrides.service_type = Some(service_type.to_string());
+        self
+    }
+    // A crew passphrase, or None for unencrypted audio
+    pub fn key(mut self, passphrase: Option<&str>) -> Self {
+        self.overrides.key = passphrase.map(str::to_string);
+        self
+    }
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.overrides.rtp = Some(framing == Framing::Rtp);
+        self
+    }
+    pub fn groups(mut self, groups: &BTreeSet<TalkGroup>) -> Self {
+        self.overrides.groups = Some(group::format_groups(groups));
+        self
+    }
+    pub fn output_channels(mut self, channels: &str) -> Self {
+        self.overrides.output_channels = Some(channels.to_string());
+        self
+    }
+    pub fn duration(mut self, milliseconds: u64) -> Self {
+        self.overrides.duration = Some(milliseconds);
+        self
+    }
+    pub fn tone(mut self, frequency: f32, amplitude: f32) -> Self {
+        self.overrides.tone = ToneOverrides { frequency: Some(frequency), amplitude: Some(amplitude) };
+        self
+    }
+
+    // Reads the file named by `--config` or UDP_VOICE_CONFIG, or
+    // DEFAULT_CONFIG_FILE if it exists, then the environment, then the
+    // command line
+    pub fn load(self) -> Result<Self, SettingsError> {
+        let path = arg_value("--config").or_else(|| std::env::var("UDP_VOICE_CONFIG").ok());
+        let builder = match path {
+            Some(path) => self.file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => self.file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => self,
+        };
+        builder.env()?.args()
+    }
+
+    pub fn file(self, path: &Path) -> Result<Self, SettingsError> {
+        info!("SETTINGS: Reading {}", path.display());
+        let text = std::fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_path_buf(), e))?;
+        self.toml(&text, path)
+    }
+
+    // The settings in `text`, TOML as read from `path`
+    fn toml(mut self, text: &str, path: &Path) -> Result<Self, SettingsError> {
+        let file: Overrides = toml::from_str(text).map_err(|e| SettingsError::Parse(path.to_path_buf(), e))?;
+        self.overrides.merge(file);
+        Ok(self)
+    }
+
+    pub fn env(self) -> Result<Self, SettingsError> {
+        self.variables(|variable| std::env::var(variable).ok())
+    }
+
+    // The environment as `lookup` tells it
+    fn variables(mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<Self, SettingsError> {
+        for (key, _, variable) in KEYS.into_iter().chain(SWITCHES) {
+            if let Some(value) = lookup(variable) {
+                debug!("SETTINGS: {} from {}", key, variable);
+                self.overrides.set(key, value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn args(self) -> Result<Self, SettingsError> {
+        self.flags(&std::env::args().collect::<Vec<_>>())
+    }
+
+    // The command line as given in `args`
+    fn flags(mut self, args: &[String]) -> Result<Self, SettingsError> {
+        for (key, flag, _) in KEYS {
+            if let Some(value) = value_after(args, flag) {
+                debug!("SETTINGS: {} from {}", key, flag);
+                if key == "key" {
+                    warn!("SETTINGS: --key shows the crew passphrase to anyone listing processes, prefer UDP_VOICE_KEY or the config file");
                 }
-             }
-            cpal::SupportedBufferSize::Unknown => default_buffer_size,
+                self.overrides.set(key, value)?;
+            }
+        }
+        for (key, flag, _) in SWITCHES {
+            if args.iter().any(|arg| arg == flag) {
+                debug!("SETTINGS: {} from {}", key, flag);
+                self.overrides.set(key, "true".to_string())?;
+            }
+        }
+        Ok(self)
+    }
+
+    // Validates everything and opens the audio backend
+    pub fn build(self) -> Result<ApplicationSettings, SettingsError> {
+        let overrides = self.overrides;
+        let tone = TestToneSettings {
+            frequency: overrides.tone.frequency.unwrap_or(TestToneSettings::DEFAULT_FREQUENCY),
+            amplitude: overrides.tone.amplitude.unwrap_or(TestToneSettings::DEFAULT_AMPLITUDE),
         };
+        tone.validate()?;
 
+        let port = overrides.port.unwrap_or(DEFAULT_CLIENT_PORT);
+        if port == 0 {
+            return Err(SettingsError::Invalid { key: "port", reason: "must not be 0".to_string() });
+        }
+        let advertise_port = overrides.advertise_port.unwrap_or(port);
+        if advertise_port == 0 {
+            return Err(SettingsError::Invalid { key: "advertise_port", reason: "must not be 0".to_string() });
+        }
+        let interfaces = match overrides.interfaces {
+            Some(value) => InterfaceSelection::parse(&value),
+            None => InterfaceSelection::All,
+        };
+        validate_interfaces(&interfaces)?;
+        let bitrate = overrides.bitrate.unwrap_or(DEFAULT_BITRATE);
+        if !(6000..=510000).contains(&bitrate) {
+            return Err(SettingsError::Invalid {
+                key: "bitrate",
+                reason: format!("{} is outside Opus' 6000 to 510000 bit/s", bitrate),
+            });
+        }
+        let service_type = overrides.service_type.unwrap_or_else(|| DEFAULT_SERVICE_TYPE.to_string());
+        if !service_type.starts_with('_') || !service_type.ends_with("._udp.local.") {
+            return Err(SettingsError::Invalid {
+                key: "service_type",
+                reason: format!("'{}' should look like _name._udp.local.", service_type),
+            });
+        }
+        let buffer_size = match overrides.frame_duration {
+            Some(milliseconds) => frame_size(milliseconds)?,
+            None => overrides.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+        };
+        validate_buffer_size(buffer_size)?;
+        let frames_per_packet = overrides.frames_per_packet.unwrap_or(DEFAULT_FRAMES_PER_PACKET);
+        if !(1..=MAX_FRAMES_PER_PACKET).contains(&frames_per_packet) {
+            return Err(SettingsError::Invalid {
+                key: "frames_per_packet",
+                reason: format!("{} is outside 1 to {}", frames_per_packet, MAX_FRAMES_PER_PACKET),
+            });
+        }
+        if let Some(stream_channels) = overrides.stream_channels {
+            if !(1..=MAX_STREAM_CHANNELS).contains(&stream_channels) {
+                return Err(SettingsError::Invalid {
+                    key: "stream_channels",
+                    reason: format!("{} is not 1 or 2", stream_channels),
+                });
+            }
+        }
+        let key = overrides.key.filter(|passphrase| !passphrase.is_empty()).map(|passphrase| CrewKey::from_passphrase(&passphrase));
+        let framing = if overrides.rtp.unwrap_or(false) { Framing::Rtp } else { Framing::Native };
+        let groups = group::parse_groups(overrides.groups.as_deref());
+        let speed = overrides.speed.unwrap_or(1.0);
+        if !(speed > 0.0 && speed.is_finite()) {
+            return Err(SettingsError::Invalid { key: "speed", reason: format!("{} is not a positive factor", speed) });
+        }
 
-        let settings = Self {
-            host,
-            devices: (input_device, output_device),
-            config_files: (
-                input_config,
-                output_config,
-                ),
+        let backend_kind = match overrides.backend {
+            Some(backend) => backend.parse().map_err(|reason| SettingsError::Invalid { key: "backend", reason })?,
+            None => BackendKind::default(),
+        };
+        let input_selector = parse_selector("input_device", overrides.input_device)?;
+        let output_selector = parse_selector("output_device", overrides.output_device)?;
+        let backend: Arc<dyn AudioBackend> = match (self.audio_backend, backend_kind) {
+            (Some(backend), _) => backend,
+            (None, BackendKind::Cpal) => {
+                let host = devices::select_host(overrides.host.as_deref())?;
+                Arc::new(CpalBackend::open(&host, &input_selector, &output_selector, buffer_size)?)
+            }
+            (None, BackendKind::Null) => Arc::new(NullBackend::new(DEFAULT_FORMAT)),
+            (None, BackendKind::File) => Arc::new(FileBackend::new(
+                overrides.input_file.as_deref(),
+                overrides.output_file.as_deref(),
+                DEFAULT_FORMAT,
+                speed,
+            )?),
+        };
+        info!("SETTINGS: Using the {} audio backend", backend.name());
+
+        let mut settings = ApplicationSettings {
+            backend,
+            host_name: overrides.host,
+            selectors: (input_selector, output_selector),
             sample_rate: cpal::SampleRate(48000),
-            channels,
+            input_sample_rate: cpal::SampleRate(0),
+            output_sample_rate: cpal::SampleRate(0),
+            channels: 0,
+            input_channels: 0,
             buffer_size,
+            frames_per_packet,
+            port,
+            advertise_port,
+            interfaces,
+            bitrate,
+            service_type,
+            key,
+            framing,
+            groups,
+            input_channel: overrides.input_channel,
+            stream_channels: overrides.stream_channels,
+            output_channels: overrides.output_channels,
+            duration: Duration::from_millis(overrides.duration.unwrap_or(DEFAULT_DURATION)),
+            tone,
         };
+        settings.update_formats();
+        // Channels can only be checked against the devices once they are open
+        settings.get_input_map()?;
+        settings.get_output_map()?;
+        Ok(settings)
+    }
+}
+
+// Named interfaces have to exist, though they may have no address yet
+fn validate_interfaces(selection: &InterfaceSelection) -> Result<(), SettingsError> {
+    let InterfaceSelection::Named(names) = selection else {
+        return Ok(());
+    };
+    let present: Vec<String> = network::interfaces().into_iter().map(|interface| interface.name).collect();
+    match names.iter().find(|name| !present.contains(name)) {
+        Some(missing) => Err(SettingsError::Invalid {
+            key: "interfaces",
+            reason: format!("no interface named '{}', there are {}", missing, present.join(", ")),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn validate_buffer_size(buffer_size: usize) -> Result<(), SettingsError> {
+    if OPUS_FRAME_SIZES.contains(&buffer_size) {
+        return Ok(());
+    }
+    Err(SettingsError::Invalid {
+        key: "buffer_size",
+        reason: format!("{} is not an Opus frame size, use one of {:?}", buffer_size, OPUS_FRAME_SIZES),
+    })
+}
+
+// Samples per frame at 48 kHz for a frame length in milliseconds
+fn frame_size(milliseconds: f32) -> Result<usize, SettingsError> {
+    let samples = milliseconds * 48.0;
+    match OPUS_FRAME_SIZES.iter().find(|size| **size as f32 == samples) {
+        Some(size) => Ok(*size),
+        None => Err(SettingsError::Invalid {
+            key: "frame_duration",
+            reason: format!("{} ms is not an Opus frame length, use 2.5, 5, 10, 20, 40 or 60", milliseconds),
+        }),
+    }
+}
+
+fn parse_selector(key: &'static str, value: Option<String>) -> Result<DeviceSelector, SettingsError> {
+    match value {
+        Some(value) => value.parse().map_err(|reason| SettingsError::Invalid { key, reason }),
+        None => Ok(DeviceSelector::Default),
+    }
+}
 
-        settings
+fn parse_switch(key: &'static str, value: &str) -> Result<bool, SettingsError> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" | "" => Ok(false),
+        other => Err(SettingsError::Invalid { key, reason: format!("'{}' is not 1 or 0, true or false", other) }),
+    }
+}
+
+// The value after `flag` on the command line
+pub fn arg_value(flag: &str) -> Option<String> {
+    value_after(&std::env::args().collect::<Vec<_>>(), flag)
+}
+
+fn value_after(args: &[String], flag: &str) -> Option<String> {
+    args.iter().skip_while(|arg| *arg != flag).nth(1).cloned()
+}
+
+// The value after `flag` on the command line, or else `variable`. For
+// binaries with flags of their own, outside the settings.
+pub fn flag_or_env(flag: &str, variable: &str) -> Option<String> {
+    let value = arg_value(flag).or_else(|| std::env::var(variable).ok());
+    debug!("SETTINGS: {} = {:?}", flag, value);
+    value
+}
+
+pub fn flag_present(flag: &str) -> bool {
+    std::env::args().any(|arg| arg == flag)
+}
+
+pub struct ApplicationSettings {
+    // System Config
+    backend: Arc<dyn AudioBackend>,
+    // What the cpal backend was opened with, to reopen it
+    host_name: Option<String>,
+    selectors: (DeviceSelector, DeviceSelector),
+    // Rate of the codec and the network; devices are resampled to and from it
+    sample_rate: cpal::SampleRate,
+    input_sample_rate: cpal::SampleRate,
+    output_sample_rate: cpal::SampleRate,
+    // Channel counts of the output and input devices. The Opus stream has
+    // its own, see channel_map.
+    channels: cpal::ChannelCount,
+    input_channels: cpal::ChannelCount,
+    buffer_size: usize,
+    // Network
+    frames_per_packet: usize,
+    port: u16,
+    advertise_port: u16,
+    interfaces: InterfaceSelection,
+    bitrate: i32,
+    service_type: String,
+    key: Option<CrewKey>,
+    framing: Framing,
+    groups: BTreeSet<TalkGroup>,
+    // Channel map selections, resolved against the devices when asked for
+    input_channel: Option<usize>,
+    stream_channels: Option<usize>,
+    output_channels: Option<String>,
+    // Sine binary
+    duration: Duration,
+    tone: TestToneSettings,
+}
+
+// Everything from the usual sources. Binaries use SettingsBuilder directly
+// so a bad configuration is reported instead of panicking.
+impl Settings for ApplicationSettings {
+    fn get_default_settings() -> Self {
+        SettingsBuilder::new()
+            .load()
+            .and_then(SettingsBuilder::build)
+            .expect("Invalid settings")
     }
 }
 
@@ -69,42 +633,120 @@ impl ApplicationSettings {
     pub fn get_buffer_size(&self) -> usize {
         self.buffer_size
     }
+    // Opus frames the client puts in each native packet
+    pub fn get_frames_per_packet(&self) -> usize {
+        self.frames_per_packet
+    }
     pub fn get_sample_rate(&self)-> f32 {
         self.sample_rate.0 as f32
     }
-    pub fn get_devices(&self) -> (Device, Device) {
-        self.devices.clone()
+    pub fn get_input_sample_rate(&self) -> u32 {
+        self.input_sample_rate.0
+    }
+    pub fn get_output_sample_rate(&self) -> u32 {
+        self.output_sample_rate.0
+    }
+    pub fn get_backend(&self) -> Arc<dyn AudioBackend> {
+        Arc::clone(&self.backend)
     }
     pub fn get_channels(&self) -> cpal::ChannelCount {
         self.channels
     }
-    pub fn get_config_files(&self) -> (
-        cpal::SupportedStreamConfig,
-        cpal::SupportedStreamConfig
-        ) {
-        self.config_files.clone()
+    pub fn get_input_channels(&self) -> cpal::ChannelCount {
+        self.input_channels
     }
-    pub fn create_stream_config(&self) -> cpal::StreamConfig {
-        cpal::StreamConfig {
-            channels: self.channels,
-            sample_rate: self.sample_rate,
-            buffer_size: cpal::BufferSize::Fixed(self.buffer_size as u32),
-        }
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+    // The port to announce over mDNS, the bound one unless set apart
+    pub fn get_advertise_port(&self) -> u16 {
+        self.advertise_port
+    }
+    // The interfaces to advertise on and receive from
+    pub fn get_interfaces(&self) -> &InterfaceSelection {
+        &self.interfaces
+    }
+    pub fn get_bitrate(&self) -> i32 {
+        self.bitrate
+    }
+    pub fn get_service_type(&self) -> &str {
+        &self.service_type
+    }
+    pub fn get_test_tone(&self) -> &TestToneSettings {
+        &self.tone
+    }
+    pub fn get_key(&self) -> Option<CrewKey> {
+        self.key.clone()
+    }
+    pub fn get_framing(&self) -> Framing {
+        self.framing
+    }
+    // The talk groups a server listens to, `all` unless configured
+    pub fn get_groups(&self) -> BTreeSet<TalkGroup> {
+        self.groups.clone()
+    }
+    // How the input device's channels make up the stream
+    pub fn get_input_map(&self) -> Result<InputMap, SettingsError> {
+        InputMap::new(self.input_channel, self.stream_channels, self.input_channels as usize)
+            .map_err(|reason| SettingsError::Invalid { key: "input_channel", reason })
+    }
+    // Which of the output device's channels play the stream
+    pub fn get_output_map(&self) -> Result<OutputMap, SettingsError> {
+        OutputMap::parse(self.output_channels.as_deref(), self.channels as usize)
+            .map_err(|reason| SettingsError::Invalid { key: "output_channels", reason })
+    }
+    pub fn get_duration(&self) -> Duration {
+        self.duration
     }
-
     // Set Functions
-    pub fn set_output_device(self) {
-        todo!()
+    // Device changes reopen the cpal backend; the other backends have no
+    // devices to pick.
+    pub fn set_output_device(&mut self, selector: &DeviceSelector) -> Result<(), SettingsError> {
+        let selectors = (self.selectors.0.clone(), selector.clone());
+        self.reopen_devices(selectors)
     }
-    pub fn set_input_device(self) {
-        todo!()
+    pub fn set_input_device(&mut self, selector: &DeviceSelector) -> Result<(), SettingsError> {
+        let selectors = (selector.clone(), self.selectors.1.clone());
+        self.reopen_devices(selectors)
+    }
+    fn reopen_devices(&mut self, selectors: (DeviceSelector, DeviceSelector)) -> Result<(), SettingsError> {
+        if self.backend.name() != "cpal" {
+            return Err(SettingsError::Invalid {
+                key: "backend",
+                reason: format!("the {} backend has no devices to select", self.backend.name()),
+            });
+        }
+        let host = devices::select_host(self.host_name.as_deref())?;
+        self.backend = Arc::new(CpalBackend::open(&host, &selectors.0, &selectors.1, self.buffer_size)?);
+        self.selectors = selectors;
+        self.update_formats();
+        Ok(())
     }
-    pub fn set_buffer_size(mut self, buffer_size: usize){
-       self.buffer_size = buffer_size;
+    fn update_formats(&mut self) {
+        let (input, output): (AudioFormat, AudioFormat) = (self.backend.input_format(), self.backend.output_format());
+        self.input_sample_rate = cpal::SampleRate(input.sample_rate);
+        self.input_channels = input.channels;
+        self.output_sample_rate = cpal::SampleRate(output.sample_rate);
+        self.channels = output.channels;
+    }
+    // The cpal backend opens its devices with a period of one buffer, so it
+    // is reopened with the new size. Streams already running keep the old
+    // one until they are started again.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) -> Result<(), SettingsError> {
+        validate_buffer_size(buffer_size)?;
+        let previous = std::mem::replace(&mut self.buffer_size, buffer_size);
+        if self.backend.name() == "cpal" {
+            if let Err(e) = self.reopen_devices(self.selectors.clone()) {
+                self.buffer_size = previous;
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct TestToneSettings {
     frequency: f32,
     amplitude: f32
@@ -112,23 +754,170 @@ pub struct TestToneSettings {
 
 impl Settings for TestToneSettings {
     fn get_default_settings() -> Self {
-        let test_tone = Self {
-            amplitude: 1.0,
-            frequency: 400.0,
-        };
-        test_tone
+        Self {
+            amplitude: Self::DEFAULT_AMPLITUDE,
+            frequency: Self::DEFAULT_FREQUENCY,
+        }
     }
 }
 
 impl TestToneSettings {
+    pub const DEFAULT_FREQUENCY: f32 = 400.0;
+    pub const DEFAULT_AMPLITUDE: f32 = 1.0;
+
     pub fn get_amplitude(&self) -> f32 {
         self.amplitude
     }
-    pub fn set_amplitude(mut self, quantity: f32) {
-        self.amplitude = quantity;
+    pub fn get_frequency(&self) -> f32 {
+        self.frequency
+    }
+    // Rejected values leave the settings as they were
+    pub fn set_amplitude(&mut self, quantity: f32) -> Result<(), SettingsError> {
+        let tone = Self { amplitude: quantity, ..*self };
+        tone.validate()?;
+        *self = tone;
+        Ok(())
+    }
+    pub fn set_frequency(&mut self, frequency: f32) -> Result<(), SettingsError> {
+        let tone = Self { frequency, ..*self };
+        tone.validate()?;
+        *self = tone;
+        Ok(())
+    }
+
+    // Audible, below the codec's Nyquist frequency, and not clipping
+    fn validate(&self) -> Result<(), SettingsError> {
+        if !(self.frequency > 0.0 && self.frequency < 24000.0) {
+            return Err(SettingsError::Invalid {
+                key: "tone.frequency",
+                reason: format!("{} Hz is outside 0 to 24000 Hz", self.frequency),
+            });
+        }
+        if !(0.0..=1.0).contains(&self.amplitude) {
+            return Err(SettingsError::Invalid {
+                key: "tone.amplitude",
+                reason: format!("{} is outside 0 to 1", self.amplitude),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+
+    // Builds from `toml`, then an environment holding only `variables`, then
+    // `flags` on the command line, on the null backend
+    fn build(toml: &str, variables: &[(&str, &str)], flags: &[&str]) -> Result<ApplicationSettings, SettingsError> {
+        let args: Vec<String> = std::iter::once("udp_voice").chain(flags.iter().copied()).map(str::to_string).collect();
+        let lookup = |name: &str| variables.iter().find(|(variable, _)| *variable == name).map(|(_, value)| value.to_string());
+        SettingsBuilder::new()
+            .backend(BackendKind::Null)
+            .port(DEFAULT_SERVER_PORT)
+            .toml(toml, Path::new("test.toml"))?
+            .variables(lookup)?
+            .flags(&args)?
+            .build()
+    }
+
+    // The setting a build rejected
+    fn invalid_key(result: Result<ApplicationSettings, SettingsError>) -> &'static str {
+        match result {
+            Err(SettingsError::Invalid { key, .. }) => key,
+            Err(e) => panic!("Expected an invalid value, got {}", e),
+            Ok(_) => panic!("Expected an invalid value, got settings"),
+        }
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let file = "port = 1000\nbitrate = 20000\nframes_per_packet = 2\nduration = 100\n";
+        let variables = [("UDP_VOICE_PORT", "2000"), ("UDP_VOICE_BITRATE", "30000")];
+        let settings = build(file, &variables, &["--port", "3000"]).expect("Failed to build settings");
+        assert_eq!(settings.get_port(), 3000);
+        assert_eq!(settings.get_bitrate(), 30000);
+        assert_eq!(settings.get_frames_per_packet(), 2);
+        assert_eq!(settings.get_duration(), Duration::from_millis(100));
+
+        // Setters called before the sources are defaults
+        let settings = build("", &[], &[]).expect("Failed to build settings");
+        assert_eq!(settings.get_port(), DEFAULT_SERVER_PORT);
+        assert_eq!(settings.get_advertise_port(), DEFAULT_SERVER_PORT);
+        assert_eq!(settings.get_bitrate(), DEFAULT_BITRATE);
+        assert_eq!(settings.get_duration(), Duration::from_millis(DEFAULT_DURATION));
+        assert!(settings.get_key().is_none());
+    }
+
+    #[test]
+    fn switches_turn_on_from_a_flag_or_a_variable() {
+        assert_eq!(build("", &[], &["--rtp"]).expect("Failed to build settings").get_framing(), Framing::Rtp);
+        assert_eq!(build("", &[("UDP_VOICE_RTP", "1")], &[]).expect("Failed to build settings").get_framing(), Framing::Rtp);
+        assert_eq!(build("rtp = true", &[("UDP_VOICE_RTP", "false")], &[]).expect("Failed to build settings").get_framing(), Framing::Native);
+        assert_eq!(invalid_key(build("", &[("UDP_VOICE_RTP", "maybe")], &[])), "rtp");
+    }
+
+    #[test]
+    fn every_key_has_its_own_flag_and_variable() {
+        let table: Vec<(&str, &str, &str)> = KEYS.into_iter().chain(SWITCHES).collect();
+        for (key, flag, variable) in &table {
+            assert!(flag.starts_with("--"), "{} has flag {}", key, flag);
+            assert!(variable.starts_with("UDP_VOICE_"), "{} has variable {}", key, variable);
+            // Overrides::set knows every key, or it would panic
+            let mut overrides = Overrides::default();
+            if let Err(e) = overrides.set(key, "1".to_string()) {
+                panic!("{} rejected 1: {}", key, e);
+            }
+        }
+        let keys: HashSet<&str> = table.iter().map(|(key, _, _)| *key).collect();
+        let flags: HashSet<&str> = table.iter().map(|(_, flag, _)| *flag).collect();
+        let variables: HashSet<&str> = table.iter().map(|(_, _, variable)| *variable).collect();
+        assert_eq!((keys.len(), flags.len(), variables.len()), (table.len(), table.len(), table.len()));
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        assert_eq!(invalid_key(build("", &[("UDP_VOICE_PORT", "abc")], &[])), "port");
+        assert_eq!(invalid_key(build("", &[], &["--port", "0"])), "port");
+        assert_eq!(invalid_key(build("", &[], &["--advertise-port", "0"])), "advertise_port");
+        assert_eq!(invalid_key(build("buffer_size = 1000", &[], &[])), "buffer_size");
+        assert_eq!(invalid_key(build("", &[], &["--frame-duration", "7"])), "frame_duration");
+        assert_eq!(invalid_key(build("", &[], &["--bitrate", "100"])), "bitrate");
+        assert_eq!(invalid_key(build("", &[], &["--frames-per-packet", "4"])), "frames_per_packet");
+        assert_eq!(invalid_key(build("", &[], &["--service-type", "voice"])), "service_type");
+        assert_eq!(invalid_key(build("", &[("UDP_VOICE_STREAM_CHANNELS", "3")], &[])), "stream_channels");
+        assert_eq!(invalid_key(build("", &[], &["--backend", "tape"])), "backend");
+        // Unknown keys and mistyped values in the file
+        assert!(matches!(build("colour = \"red\"", &[], &[]), Err(SettingsError::Parse(..))));
+        assert!(matches!(build("port = \"high\"", &[], &[]), Err(SettingsError::Parse(..))));
+    }
+
+    #[test]
+    fn tone_values_are_checked() {
+        let settings = build("[tone]\nfrequency = 1000.0\namplitude = 0.5\n", &[], &["--amplitude", "0.25"])
+            .expect("Failed to build settings");
+        assert_eq!(settings.get_test_tone().get_frequency(), 1000.0);
+        assert_eq!(settings.get_test_tone().get_amplitude(), 0.25);
+        assert_eq!(invalid_key(build("[tone]\nfrequency = 30000.0\n", &[], &[])), "tone.frequency");
+        assert_eq!(invalid_key(build("", &[("UDP_VOICE_TONE_AMPLITUDE", "1.5")], &[])), "tone.amplitude");
+        assert_eq!(invalid_key(build("", &[], &["--frequency", "NaN"])), "tone.frequency");
+
+        // Rejected values leave the tone as it was
+        let mut tone = TestToneSettings::get_default_settings();
+        assert!(tone.set_amplitude(2.0).is_err());
+        assert!(tone.set_frequency(-5.0).is_err());
+        assert_eq!(tone.get_amplitude(), TestToneSettings::DEFAULT_AMPLITUDE);
+        assert_eq!(tone.get_frequency(), TestToneSettings::DEFAULT_FREQUENCY);
+        tone.set_amplitude(0.5).expect("Rejected a valid amplitude");
+        assert_eq!(tone.get_amplitude(), 0.5);
     }
-    pub fn set_frequency(mut self, frequency: f32) {
-        self.frequency = frequency;
 
+    #[test]
+    fn an_empty_key_means_no_encryption() {
+        let settings = build("", &[("UDP_VOICE_KEY", "crew")], &[]).expect("Failed to build settings");
+        assert!(settings.get_key().is_some());
+        let settings = build("key = \"crew\"", &[("UDP_VOICE_KEY", "")], &[]).expect("Failed to build settings");
+        assert!(settings.get_key().is_none());
     }
 }
@@ -1,14 +1,16 @@
 use std::io::Write;
 use std::time::{Duration, Instant};
 use std::sync::mpsc::{Sender, Receiver};
-use cpal::SampleFormat;
-use cpal::traits::{DeviceTrait, StreamTrait};
 use std::f32::consts::PI;
 use ringbuf::{
     traits::{Consumer, Producer, Split, Observer}, 
     HeapRb,
 };
+use std::thread::JoinHandle;
 use log::{info, warn};
+use crate::session::StopFlag;
+use crate::backend::AudioBackend;
+use crate::resample::Resampler;
 #[allow(unused_imports)]
 use colored::*;
 
@@ -18,6 +20,8 @@ pub struct Sine {
     sample_rate: u32,
     channels: usize,
     value: Option<f32>,
+    stop: StopFlag,
+    generator: Option<JoinHandle<()>>,
 }
 impl Sine {
 
@@ -39,10 +43,13 @@ impl Sine {
             amplitude,
             sample_rate,
             channels,
-            value: None
+            value: None,
+            stop: StopFlag::new(),
+            generator: None,
         };
 
-        std::thread::spawn( move || {
+        let stop = sine.stop.clone();
+        sine.generator = Some(std::thread::spawn( move || {
             // println!("Sine::new - Sine wave generator thread started");
             let mut phase = 0.0 as f32;
             let phase_increment = 2.0 * PI * sine.frequency / sine.sample_rate as f32;
@@ -72,23 +79,32 @@ impl Sine {
                 }
 
                 // Calculate elapsed time and wait for the remainder of the interval
-                let elapsed = start.elapsed();
-                if elapsed < interval {
-                    std::thread::sleep(interval - elapsed);
+                if stop.sleep(interval.saturating_sub(start.elapsed())) {
+                    break;
                 }
             }
-        });
+        }));
 
         info!("Sine::new - Sine Wave generator initialized successfully");
         sine
     }
 
+    // Stops the generator thread; its output channel closes with it
+    pub fn stop(&mut self) {
+        self.stop.stop();
+        if let Some(generator) = self.generator.take() {
+            let _ = generator.join();
+        }
+    }
+
+    // Plays the tone on `backend` until `stop` is set, then stops the
+    // generator along with the playback
     pub fn play(
         self, 
         receiver: Receiver<Vec<f32>>,
         buffer_size: usize,
-        device: cpal::Device,
-        config: cpal::SupportedStreamConfig,
+        backend: &dyn AudioBackend,
+        stop: &StopFlag,
         ) { 
 
         info!("Sine::play - Starting playback with buffer_size: {},
@@ -96,14 +112,20 @@ impl Sine {
 
         let ring = HeapRb::<f32>::new(buffer_size * self.channels);
         let (mut producer, mut consumer) = ring.split();
+        // The tone is generated at `sample_rate`, the device may run at another
+        let mut resampler = Resampler::new(self.sample_rate, backend.output_format().sample_rate, self.channels);
 
+        let producer_stop = stop.clone();
         std::thread::spawn(move || {
             info!("Sine::play - Producer thread started");
             while let Ok(block) = receiver.recv() {
+                let block = resampler.process(&block);
                 info!("Sine::play - Received block of size {}", block.len());
                 for sample in block {
                     while producer.is_full() {
-                        std::thread::sleep(std::time::Duration::from_millis(1));
+                        if producer_stop.sleep(Duration::from_millis(1)) {
+                            return;
+                        }
                     }
                     producer.try_push(sample).expect("Sine::play - Failed to push into producer");
                 }
@@ -114,46 +136,19 @@ impl Sine {
         });
 
 
-        let sample_format = config.sample_format();
-        let config: cpal::StreamConfig = config.into();
-
-        let stream = match sample_format {
-            SampleFormat::F32 => {
-                info!("Sine::play - Building output stream with SampleFormat:::F32");
-                device.build_output_stream(
-                    &config,
-                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                        for sample in data {
-                            info!("Playing sample: {:?}", *sample);
-
-                            *sample = consumer.try_pop().unwrap_or(0.0);
-                        }
-                    },
-                    move |err| {
-                        // react to errors here.
-                        eprintln!("Failed to output samples into stream: {}", err);
-                    },
-                    None //None=blocking, Some(Duration)=timeout
-                )
-            },
-            SampleFormat::I16 => {
-                warn!("Not yet implemented(I16)");
-                todo!();
-            },
-            SampleFormat::U16 => {
-                warn!("Not yet implemented (U16)");
-                todo!();
+        let _stream = backend.start_output(Box::new(move |data: &mut [f32]| {
+            for sample in data {
+                *sample = consumer.try_pop().unwrap_or(0.0);
             }
-            sample_format => panic!("Unsupported sample format '{sample_format}'")
-        }.unwrap();
-
-        info!("Sine::play - Output stream built succcessfully, starting playback");
-
-        stream.play().expect("Sine::play - Failed to play stream");
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(1000));
-        }
+        })).expect("Sine::play - Failed to start output");
 
+        info!("Sine::play - Output stream started, playing");
+        stop.wait();
+        info!("Sine::play - Stopped");
+    }
+}
+impl Drop for Sine {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
-
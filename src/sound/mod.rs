@@ -2,82 +2,88 @@ use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Sender, Receiver};
 use opus::{Encoder, Decoder, Application};
-use cpal::SampleFormat;
-use cpal::traits::{DeviceTrait, StreamTrait};
 use ringbuf::{
     traits::{Consumer, Producer, Split, Observer},
     HeapRb,
 };
 use colored::*;
-use log::{info, warn, error, debug};
+use log::{info, debug};
 #[allow(unused_imports)]
 use crate::settings::{Settings, ApplicationSettings};
-
+use crate::backend::{AudioBackend, AudioStream, BackendError};
+use crate::resample::Resampler;
+use crate::channel_map::InputMap;
+use crate::codec;
+
+// Plays whatever arrives on `receiver`, which carries blocks at
+// `codec_rate` in the output's channel layout. Playback stops when the
+// returned stream is dropped; the feeding thread ends once the sender hangs
+// up.
 pub fn dac(
     receiver: Receiver<Vec<f32>>,
     buffer_size: usize,
-    device: &Arc<Mutex<cpal::Device>>,
-    ) {
+    backend: &dyn AudioBackend,
+    codec_rate: u32,
+    ) -> Result<AudioStream, BackendError> {
 
-    let settings: ApplicationSettings = Settings::get_default_settings();
-    let channels = settings.get_channels();
-    let (_, config) = settings.get_config_files();
+    let format = backend.output_format();
+    let channels = format.channels;
     debug!("DAC: Initialized with Channels: {}, Buffer Size: {}", channels, buffer_size);
 
     let buffer = Arc::new(Mutex::new(VecDeque::with_capacity(buffer_size * channels as usize)));
 
+    // Blocks arrive at the codec rate, the device may run at another
+    let mut resampler = Resampler::new(codec_rate, format.sample_rate, channels as usize);
     let buffer_buffer = Arc::clone(&buffer);
     std::thread::spawn(move || {
         while let Ok(block) = receiver.recv() {
+            let block = resampler.process(&block);
             let mut buffer = buffer_buffer.lock().expect("Failed to lock buffer for producer");
             for sample in block {
                 buffer.push_back(sample);
             }
         }
     });
-    let sample_format = config.sample_format();
-    let config: cpal::StreamConfig = config.into();
-
-    let device = device.lock().unwrap();
-    info!("DAC: Output device locked and ready");
 
     let buffer_for_playback = Arc::clone(&buffer);
 
-    let stream = match sample_format {
-        SampleFormat::F32 => {
-            info!("DAC: Building output stream with format F32");
-            device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                let mut buffer = buffer_for_playback.lock().expect("Failed to lock buffer for consumer");
-                for sample in data.iter_mut() {
-                    println!("Sample: {sample}");
-                    *sample = buffer.pop_front().unwrap_or(0.0);
+    info!("DAC: Starting {} output, {}", backend.name(), format);
+    backend.start_output(Box::new(move |data: &mut [f32]| {
+        let mut buffer = buffer_for_playback.lock().expect("Failed to lock buffer for consumer");
+        for sample in data.iter_mut() {
+            *sample = buffer.pop_front().unwrap_or(0.0);
+        }
+    }))
+}
+// Capture counterpart of `dac`. Samples coming from the backend's input are
+// resampled to `codec_rate`, chunked into blocks of `frame_size` samples per
+// channel, the size the Opus encoder expects, and sent down `sender`. The
+// stream stops when dropped.
+pub fn adc(
+    sender: Sender<Vec<f32>>,
+    frame_size: usize,
+    backend: &dyn AudioBackend,
+    codec_rate: u32,
+    ) -> Result<AudioStream, BackendError> {
+
+    let format = backend.input_format();
+    let block_size = frame_size * format.channels as usize;
+    debug!("ADC: Initialized with Channels: {}, Frame Size: {}", format.channels, frame_size);
+
+    let mut block: Vec<f32> = Vec::with_capacity(block_size);
+    let mut resampler = Resampler::new(format.sample_rate, codec_rate, format.channels as usize);
+    info!("ADC: Starting {} capture, {}", backend.name(), format);
+    backend.start_input(Box::new(move |data: &[f32]| {
+        for sample in resampler.process(data) {
+            block.push(sample);
+            if block.len() == block_size {
+                let full_block = std::mem::replace(&mut block, Vec::with_capacity(block_size));
+                if sender.send(full_block).is_err() {
+                    return;
                 }
-            },
-            move |err| {
-                // react to errors here.
-                error!("DAC: Failed to output samples into stream: {}", err);
-            },
-            None //None=blocking, Some(Duration)=timeout
-        )},
-        SampleFormat::I16 => {
-            info!("DAC: Not yet implemented(I16)");
-            todo!();
-        },
-        SampleFormat::U16 => {
-            info!("DAC: Not yet implemented (U16)");
-            todo!();
+            }
         }
-        sample_format => panic!("DAC: Unsupported sample format '{sample_format}'")
-    }.unwrap();
-
-    info!("DAC: Starting the audio stream");
-    stream.play().expect("Failed to play stream");
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-    }
-
+    }))
 }
 pub fn encode_opus_v1(
     receiver: Receiver<Vec<f32>>,
@@ -123,13 +129,9 @@ pub fn encode_opus_v1(
         info!("ENCODER: Receiver channels closed, producer thread exiting");
     });
 
-    let mut opus_channels: opus::Channels = opus::Channels::Stereo;
-
-    if channels == 1 {
-        opus_channels = opus::Channels::Mono;
-    } else if channels > 2 {
-        warn!("ENCODER: Channels are more than 2");
-    }
+    // Blocks arrive in the device's layout, Opus takes mono or stereo
+    let input_map = InputMap::downmix(channels as usize);
+    let opus_channels = codec::opus_channels(input_map.stream_channels() as u16);
 
     let _opus_info = format!("ENCODER: Opus encoder channels set to: {:?}", opus_channels);
 
@@ -156,6 +158,7 @@ pub fn encode_opus_v1(
             }
         }
         println!("ENCODER: Filled decoded block buffer");
+        let decoded_block = input_map.apply(&decoded_block, channels as usize);
 
         let mut encoded_block = vec![0; buffer_size * channels as usize];
         let len = opus_encoder.encode_float(&decoded_block, &mut encoded_block)?;
@@ -176,11 +179,13 @@ pub fn encode_opus(
     let channels = settings.get_channels();
     let buffer_size = settings.get_buffer_size();
     let sample_rate = settings.get_sample_rate();
-    let opus_channels = if channels == 1 { opus::Channels::Mono } else { opus::Channels::Stereo };
+    let input_map = InputMap::downmix(channels as usize);
+    let opus_channels = codec::opus_channels(input_map.stream_channels() as u16);
 
     // println!("Encoder initialized with sample rate: {}, channels: {}", sample_rate, channels);
     // Double buffers for storing audio chunks
     while let Ok(block) = receiver.recv() {
+        let block = input_map.apply(&block, channels as usize);
         let mut opus_encoder = Encoder::new(
             sample_rate as u32,
             opus_channels,
@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use crate::settings::ApplicationSettings;
+use crate::session::{Session, StopFlag};
+use crate::sine::Sine;
+use crate::sound;
+use crate::backend;
+
+// Where the audio a client transmits comes from
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum AudioSource {
+    #[default]
+    Microphone,
+    // Test tone for line checks
+    Sine { frequency: f32 },
+    // A WAV file, looped
+    File(PathBuf),
+}
+
+impl fmt::Display for AudioSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioSource::Microphone => write!(f, "mic"),
+            AudioSource::Sine { frequency } => write!(f, "sine {}", frequency),
+            AudioSource::File(path) => write!(f, "file {}", path.display()),
+        }
+    }
+}
+
+// Parses "mic", "sine [frequency]" or "file <path>"
+impl FromStr for AudioSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        match parts.next() {
+            None | Some("mic") => Ok(AudioSource::Microphone),
+            Some("sine") => {
+                let frequency = match parts.next() {
+                    Some(frequency) => frequency
+                        .parse()
+                        .map_err(|_| format!("Invalid sine frequency '{}'", frequency))?,
+                    None => 440.0,
+                };
+                Ok(AudioSource::Sine { frequency })
+            }
+            Some("file") => {
+                let path: Vec<&str> = parts.collect();
+                if path.is_empty() {
+                    return Err("Missing file path".to_string());
+                }
+                Ok(AudioSource::File(PathBuf::from(path.join(" "))))
+            }
+            Some(other) => Err(format!("Unknown source '{}'", other)),
+        }
+    }
+}
+
+// Starts `source` as part of `session`. Whatever drives it, the microphone
+// stream or a generator, stops with the session, closing `sender`. Every
+// source produces frames laid out like the input device, so the channel map
+// treats them alike.
+pub fn start_source(
+    source: &AudioSource,
+    settings: &ApplicationSettings,
+    sender: Sender<Vec<f32>>,
+    buffer_size: usize,
+    session: &mut Session,
+) -> Result<(), Box<dyn Error>> {
+    let sample_rate = settings.get_sample_rate();
+    let channels = settings.get_input_channels();
+    info!("SOURCE: Starting {} source", source);
+
+    match source {
+        AudioSource::Microphone => {
+            let stream = sound::adc(sender, buffer_size, &*settings.get_backend(), sample_rate as u32)?;
+            session.hold(stream);
+        }
+        AudioSource::Sine { frequency } => {
+            let amplitude = settings.get_test_tone().get_amplitude();
+            session.hold(Sine::new(*frequency, amplitude, sample_rate as u32, channels as usize, sender, buffer_size));
+        }
+        AudioSource::File(path) => {
+            let samples = read_wav(path, sample_rate as u32, channels as usize)?;
+            session.spawn("file source", move |stop| {
+                play_file(samples, sample_rate, channels as usize, sender, buffer_size, stop)
+            });
+        }
+    }
+    Ok(())
+}
+
+// Reads a whole WAV file as interleaved f32 with `channels` channels
+fn read_wav(path: &Path, sample_rate: u32, channels: usize) -> Result<Vec<f32>, Box<dyn Error>> {
+    let (format, samples) = backend::file::read_wav(path)?;
+    if format.sample_rate != sample_rate {
+        warn!("SOURCE: {} is {} Hz, stream runs at {} Hz", path.display(), format.sample_rate, sample_rate);
+    }
+    if samples.is_empty() {
+        return Err(format!("{} contains no audio", path.display()).into());
+    }
+
+    // Repeat or drop channels so the frame layout matches the stream
+    let file_channels = format.channels as usize;
+    Ok(samples
+        .chunks(file_channels)
+        .flat_map(|frame| (0..channels).map(move |ch| frame[ch.min(frame.len() - 1)]))
+        .collect())
+}
+
+fn play_file(
+    samples: Vec<f32>,
+    sample_rate: f32,
+    channels: usize,
+    output: Sender<Vec<f32>>,
+    buffer_size: usize,
+    stop: StopFlag,
+) {
+    let interval = Duration::from_secs_f32(buffer_size as f32 / sample_rate);
+    let block_size = buffer_size * channels;
+    let mut position = 0;
+    loop {
+        let start = Instant::now();
+        let block: Vec<f32> = (0..block_size)
+            .map(|i| samples[(position + i) % samples.len()])
+            .collect();
+        position = (position + block_size) % samples.len();
+
+        if output.send(block).is_err() {
+            warn!("SOURCE: Failed to send block, terminating file playback thread");
+            break;
+        }
+
+        if stop.sleep(interval.saturating_sub(start.elapsed())) {
+            break;
+        }
+    }
+}
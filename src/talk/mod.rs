@@ -0,0 +1,268 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use log::debug;
+
+pub const DEFAULT_VOX_THRESHOLD_DB: f32 = -40.0;
+pub const DEFAULT_VOX_HANG: Duration = Duration::from_millis(500);
+pub const SQUELCH_TAIL: Duration = Duration::from_millis(150);
+
+// When the client is allowed to transmit
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TransmitMode {
+    // Always on, like the original `send`
+    #[default]
+    Continuous,
+    // Walkie-talkie: only while the talk switch is on
+    PushToTalk,
+    // Voice activated: opens above `threshold_db` (dBFS) and stays open for
+    // `hang` after the level drops back down
+    Vox { threshold_db: f32, hang: Duration },
+}
+
+impl fmt::Display for TransmitMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransmitMode::Continuous => write!(f, "continuous"),
+            TransmitMode::PushToTalk => write!(f, "ptt"),
+            TransmitMode::Vox { threshold_db, hang } => {
+                write!(f, "vox {} dB {} ms", threshold_db, hang.as_millis())
+            }
+        }
+    }
+}
+
+// Parses "continuous", "ptt" or "vox [threshold_db] [hang_ms]"
+impl FromStr for TransmitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        match parts.next() {
+            Some("continuous") => Ok(TransmitMode::Continuous),
+            Some("ptt") => Ok(TransmitMode::PushToTalk),
+            Some("vox") => {
+                let threshold_db = match parts.next() {
+                    Some(value) => value
+                        .parse()
+                        .map_err(|_| format!("Invalid VOX threshold '{}'", value))?,
+                    None => DEFAULT_VOX_THRESHOLD_DB,
+                };
+                let hang = match parts.next() {
+                    Some(value) => Duration::from_millis(
+                        value.parse().map_err(|_| format!("Invalid VOX hang time '{}'", value))?
+                    ),
+                    None => DEFAULT_VOX_HANG,
+                };
+                Ok(TransmitMode::Vox { threshold_db, hang })
+            }
+            Some(other) => Err(format!("Unknown transmit mode '{}'", other)),
+            None => Err("Missing transmit mode".to_string()),
+        }
+    }
+}
+
+// The talk button, shared between the CLI and the encoder thread
+#[derive(Debug, Clone, Default)]
+pub struct TalkSwitch(Arc<AtomicBool>);
+
+impl TalkSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn press(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    pub fn release(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+    // Returns the new state
+    pub fn toggle(&self) -> bool {
+        !self.0.fetch_xor(true, Ordering::SeqCst)
+    }
+    pub fn is_pressed(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// What the encoder should do with the frame it just handed to the gate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateDecision {
+    // Transmission begins with this frame, send a start marker first
+    Start,
+    // Keep transmitting
+    Open,
+    // Transmission ended, send a stop marker and drop this frame
+    Stop,
+    // Nobody is talking, drop this frame
+    Closed,
+}
+
+// What the encoder hands on to the packetizer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outgoing {
+    TalkStart,
+    Frame(Vec<u8>),
+    TalkStop,
+}
+
+pub struct TalkGate {
+    mode: TransmitMode,
+    switch: TalkSwitch,
+    open: bool,
+    last_voice: Option<Instant>,
+}
+
+impl TalkGate {
+    pub fn new(mode: TransmitMode, switch: TalkSwitch) -> Self {
+        Self {
+            mode,
+            switch,
+            open: false,
+            last_voice: None,
+        }
+    }
+
+    pub fn process(&mut self, frame: &[f32]) -> GateDecision {
+        self.process_at(frame, Instant::now())
+    }
+
+    // `process` as if the frame was captured at `now`
+    pub fn process_at(&mut self, frame: &[f32], now: Instant) -> GateDecision {
+        let talking = match self.mode {
+            TransmitMode::Continuous => true,
+            TransmitMode::PushToTalk => self.switch.is_pressed(),
+            TransmitMode::Vox { threshold_db, hang } => {
+                if frame_level_db(frame) >= threshold_db {
+                    self.last_voice = Some(now);
+                }
+                self.last_voice.is_some_and(|last| now.duration_since(last) <= hang)
+            }
+        };
+
+        let decision = match (self.open, talking) {
+            (false, true) => GateDecision::Start,
+            (true, true) => GateDecision::Open,
+            (true, false) => GateDecision::Stop,
+            (false, false) => GateDecision::Closed,
+        };
+        if decision == GateDecision::Start || decision == GateDecision::Stop {
+            debug!("TALK: {:?} ({})", decision, self.mode);
+        }
+        self.open = talking;
+        decision
+    }
+}
+
+// RMS level of a block of samples in dBFS
+pub fn frame_level_db(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let mean_square = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+    10.0 * mean_square.max(1e-12).log10()
+}
+
+// A short burst of decaying noise, the radio "kssh" that tells the listener
+// the other side let go of the talk button.
+pub fn squelch_tail(sample_rate: f32, channels: usize, duration: Duration) -> Vec<f32> {
+    let frames = (duration.as_secs_f32() * sample_rate) as usize;
+    let mut noise_state: u32 = 0x1234_5678;
+    let mut tail = Vec::with_capacity(frames * channels);
+    for i in 0..frames {
+        // xorshift32, plenty for noise
+        noise_state ^= noise_state << 13;
+        noise_state ^= noise_state >> 17;
+        noise_state ^= noise_state << 5;
+        let noise = (noise_state as f32 / u32::MAX as f32) * 2.0 - 1.0;
+        let envelope = 1.0 - i as f32 / frames as f32;
+        let sample = noise * 0.05 * envelope * envelope;
+        tail.extend(std::iter::repeat_n(sample, channels));
+    }
+    tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 20 ms at 48 kHz, about -6 dBFS and -66 dBFS
+    const LOUD: [f32; 960] = [0.5; 960];
+    const QUIET: [f32; 960] = [0.0005; 960];
+
+    #[test]
+    fn vox_opens_on_voice_and_hangs_on() {
+        let hang = Duration::from_millis(500);
+        let mut gate = TalkGate::new(TransmitMode::Vox { threshold_db: -40.0, hang }, TalkSwitch::new());
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        assert_eq!(gate.process_at(&QUIET, at(0)), GateDecision::Closed);
+        assert_eq!(gate.process_at(&LOUD, at(20)), GateDecision::Start);
+        assert_eq!(gate.process_at(&LOUD, at(40)), GateDecision::Open);
+        // Quiet again, but within the hang time of the last loud frame
+        assert_eq!(gate.process_at(&QUIET, at(60)), GateDecision::Open);
+        assert_eq!(gate.process_at(&QUIET, at(540)), GateDecision::Open);
+        assert_eq!(gate.process_at(&QUIET, at(560)), GateDecision::Stop);
+        assert_eq!(gate.process_at(&QUIET, at(580)), GateDecision::Closed);
+        // Voice during the hang time keeps it open for another hang time
+        assert_eq!(gate.process_at(&LOUD, at(600)), GateDecision::Start);
+        assert_eq!(gate.process_at(&QUIET, at(900)), GateDecision::Open);
+        assert_eq!(gate.process_at(&LOUD, at(1000)), GateDecision::Open);
+        assert_eq!(gate.process_at(&QUIET, at(1400)), GateDecision::Open);
+        assert_eq!(gate.process_at(&QUIET, at(1520)), GateDecision::Stop);
+    }
+
+    #[test]
+    fn vox_ignores_what_stays_below_the_threshold() {
+        let mut gate = TalkGate::new(TransmitMode::Vox { threshold_db: -6.0, hang: DEFAULT_VOX_HANG }, TalkSwitch::new());
+        let start = Instant::now();
+        for i in 0..10 {
+            assert_eq!(gate.process_at(&LOUD, start + Duration::from_millis(20 * i)), GateDecision::Closed);
+        }
+    }
+
+    #[test]
+    fn push_to_talk_follows_the_switch() {
+        let switch = TalkSwitch::new();
+        let mut gate = TalkGate::new(TransmitMode::PushToTalk, switch.clone());
+        // The level makes no difference
+        assert_eq!(gate.process(&LOUD), GateDecision::Closed);
+        assert!(switch.toggle());
+        assert_eq!(gate.process(&QUIET), GateDecision::Start);
+        assert_eq!(gate.process(&QUIET), GateDecision::Open);
+        assert!(!switch.toggle());
+        assert_eq!(gate.process(&LOUD), GateDecision::Stop);
+        assert_eq!(gate.process(&LOUD), GateDecision::Closed);
+        switch.press();
+        assert_eq!(gate.process(&QUIET), GateDecision::Start);
+        switch.release();
+        assert!(!switch.is_pressed());
+        assert_eq!(gate.process(&QUIET), GateDecision::Stop);
+    }
+
+    #[test]
+    fn continuous_never_closes() {
+        let mut gate = TalkGate::new(TransmitMode::Continuous, TalkSwitch::new());
+        assert_eq!(gate.process(&QUIET), GateDecision::Start);
+        assert_eq!(gate.process(&[]), GateDecision::Open);
+    }
+
+    #[test]
+    fn levels_are_in_dbfs() {
+        assert!(frame_level_db(&[1.0, -1.0]).abs() < 1e-4);
+        assert!((frame_level_db(&LOUD) + 6.02).abs() < 0.01);
+        assert_eq!(frame_level_db(&[]), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn modes_parse() {
+        assert_eq!("ptt".parse(), Ok(TransmitMode::PushToTalk));
+        assert_eq!("vox".parse(), Ok(TransmitMode::Vox { threshold_db: DEFAULT_VOX_THRESHOLD_DB, hang: DEFAULT_VOX_HANG }));
+        assert_eq!("vox -30 200".parse(), Ok(TransmitMode::Vox { threshold_db: -30.0, hang: Duration::from_millis(200) }));
+        assert!("vox loud".parse::<TransmitMode>().is_err());
+        assert!("shout".parse::<TransmitMode>().is_err());
+    }
+}
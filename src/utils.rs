@@ -7,11 +7,12 @@ pub fn  clear_terminal() {
 }
 
 pub fn username_take()-> String {
-    // Take user input (instance name)
+    // Take user input (display name), see mdns_service::name for what is
+    // allowed
     let reader = std::io::stdin();
-    let mut instance_name = String::new();
-    reader.read_line(&mut instance_name).unwrap();
-    let instance_name = instance_name.replace("\n", "").replace(" ", "_");
-    instance_name
+    let mut username = String::new();
+    reader.read_line(&mut username).unwrap();
+    let username = username.trim().to_string();
+    username
 }
 
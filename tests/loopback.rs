@@ -0,0 +1,52 @@
+use std::time::Duration;
+use selflib::netsim::{Impairment, LossModel};
+use selflib::pipeline::loopback::{self, LoopbackConfig};
+
+// A packet waits for its frames before it is sent, the jitter buffer holds
+// back about two more, and the mixer tick and the sound card blocks add a
+// little on top. Anything past ten packets means audio is piling up
+// somewhere.
+const LATENCY_PACKETS: u32 = 10;
+// Opus at the default bitrate keeps a steady tone well above this
+const MIN_SNR_DB: f32 = 10.0;
+// Under jitter the buffer holds back a few packets more
+const IMPAIRED_LATENCY_PACKETS: u32 = 15;
+// Concealed and FEC-recovered packets only touch a few windows, the median
+// stays close to a clean run
+const IMPAIRED_MIN_SNR_DB: f32 = 6.0;
+
+#[test]
+fn tone_survives_the_round_trip() {
+    let report = loopback::run(&LoopbackConfig::default()).expect("Failed to start the loopback");
+
+    let latency = report.latency.expect("The tone never came out of the server");
+    let bound = report.packet_duration * LATENCY_PACKETS;
+    assert!(latency < bound, "Latency {:?} exceeds {:?}", latency, bound);
+
+    let snr_db = report.snr_db.expect("Too little of the tone came out to measure");
+    assert!(snr_db > MIN_SNR_DB, "SNR {:.1} dB is below {} dB", snr_db, MIN_SNR_DB);
+}
+
+#[test]
+fn tone_survives_bursty_loss_and_jitter() {
+    let impairment = Impairment {
+        // About 5% lost, in short bursts
+        loss: LossModel::GilbertElliott { to_bad: 0.03, to_good: 0.3, good_loss: 0.0, bad_loss: 0.6 },
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(10),
+        seed: 7,
+        ..Impairment::default()
+    };
+    let config = LoopbackConfig { impairment: Some(impairment), ..LoopbackConfig::default() };
+    let report = loopback::run(&config).expect("Failed to start the loopback");
+
+    let (upstream, _) = report.network.expect("The loopback bypassed the simulator");
+    assert!(upstream.lost > 0, "Nothing was lost: {}", upstream);
+
+    let latency = report.latency.expect("The tone never came out of the server");
+    let bound = report.packet_duration * IMPAIRED_LATENCY_PACKETS;
+    assert!(latency < bound, "Latency {:?} exceeds {:?} with {}", latency, bound, upstream);
+
+    let snr_db = report.snr_db.expect("Too little of the tone came out to measure");
+    assert!(snr_db > IMPAIRED_MIN_SNR_DB, "SNR {:.1} dB is below {} dB with {}", snr_db, IMPAIRED_MIN_SNR_DB, upstream);
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use selflib::backend::{AudioFormat, MemoryBackend};
+use selflib::channel_map::InputMap;
+use selflib::group::Membership;
+use selflib::mdns_service::{Peer, Role};
+use selflib::jitter_buffer::{JitterBuffer, Playout, SequenceNumber};
+use selflib::pipeline::receive::{self, StreamFormat, StreamKey};
+use selflib::pipeline::send::{self, Directory, Transport};
+use selflib::protocol;
+use selflib::rtp::Framing;
+use selflib::settings::{ApplicationSettings, SettingsBuilder};
+use selflib::source::AudioSource;
+use selflib::talk::{TalkGate, TalkSwitch, TransmitMode};
+
+// How long the client sends for
+const SEND_TIME: Duration = Duration::from_millis(400);
+
+#[test]
+fn sequence_numbers_compare_across_the_wrap() {
+    assert!(u32::MAX.precedes(0));
+    assert!(!0u32.precedes(u32::MAX));
+    assert_eq!(0u32.distance(u32::MAX - 1), 2);
+    assert!(65535u16.precedes(0));
+    assert_eq!(3u16.distance(65534), 5);
+    assert!(!5u16.precedes(5));
+}
+
+#[test]
+fn jitter_buffer_plays_through_the_wrap() {
+    let frame = Duration::from_millis(20);
+    let mut buffer = JitterBuffer::new(frame);
+    let start = Instant::now();
+    let sequence: Vec<u32> = (0..6).map(|i| (u32::MAX - 2).wrapping_add(i)).collect();
+    for (i, sequence_number) in sequence.iter().enumerate() {
+        buffer.push_at(*sequence_number, *sequence_number, start + frame * i as u32);
+    }
+
+    let mut played = Vec::new();
+    while played.len() < sequence.len() {
+        match buffer.pop() {
+            Playout::Frame(sequence_number) => played.push(sequence_number),
+            Playout::Buffering => panic!("Buffering with {} packets held", buffer.len()),
+            other => panic!("Expected a frame after {:?}, got {:?}", played, other),
+        }
+    }
+    assert_eq!(played, sequence);
+    assert_eq!(buffer.stats().late, 0);
+}
+
+#[test]
+fn every_peer_sees_a_contiguous_sequence() {
+    for peers in [1, 3] {
+        let received = send_to_peers(peers);
+        for (peer, sequence) in &received {
+            assert!(!sequence.is_empty(), "{} of {} got nothing", peer, peers);
+            let expected: Vec<u32> = (0..sequence.len() as u32).collect();
+            assert_eq!(sequence, &expected, "{} of {} saw gaps", peer, peers);
+        }
+        let lengths: HashSet<usize> = received.values().map(Vec::len).collect();
+        assert_eq!(lengths.len(), 1, "Peers got different packets: {:?}", lengths);
+    }
+}
+
+#[test]
+fn a_second_send_from_the_same_address_is_heard() {
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("Failed to bind the server");
+    server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let directory = directory_of(std::slice::from_ref(&server));
+    let settings = memory_settings();
+    let format = StreamFormat {
+        sample_rate: settings.get_sample_rate(),
+        channels: 1,
+        buffer_size: settings.get_buffer_size(),
+        framing: Framing::Native,
+    };
+    // Both sends go out from the same port, as a client's do. Each restarts
+    // its sequence at 0.
+    let local = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap();
+
+    let mut streams = HashMap::new();
+    for send in 0..2 {
+        let transport = Transport { framing: Framing::Native, frames_per_packet: 1, key: None };
+        let gate = TalkGate::new(TransmitMode::Continuous, TalkSwitch::new());
+        let mut client = send::start_sending(&settings, &AudioSource::Microphone, InputMap::downmix(1), gate, local, directory.clone(), transport)
+            .expect("Failed to start sending");
+        // Short enough for the whole send to fit in the jitter buffer
+        std::thread::sleep(SEND_TIME / 2);
+        client.stop();
+
+        let mut received = 0;
+        let mut buf = [0u8; protocol::MAX_PACKET_SIZE];
+        while let Ok((amount, src)) = server.recv_from(&mut buf) {
+            let packet = protocol::open(&buf[..amount], None).expect("Failed to decode a packet");
+            receive::file_packet(&mut streams, src, packet, &format);
+            received += 1;
+        }
+        assert!(received > 0, "Send {} got nothing through", send);
+
+        let stream = streams.get_mut(&StreamKey::Address(local)).expect("No stream for the client");
+        while receive::next_block(stream, &format).is_some() {}
+        let stats = stream.stats();
+        assert_eq!(stats.late, 0, "Send {} was dropped as late", send);
+        assert_eq!(stats.played, received, "Send {} wasn't played out", send);
+    }
+}
+
+// The peers mDNS would list for servers on `sockets`
+fn directory_of(sockets: &[UdpSocket]) -> Directory {
+    let peer_table = sockets
+        .iter()
+        .enumerate()
+        .map(|(i, socket)| {
+            let name = format!("peer{}", i);
+            let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+            let peer = Peer::new(&name, Role::Server, vec![address], socket.local_addr().unwrap().port());
+            (name, peer)
+        })
+        .collect();
+    Directory {
+        peers: Arc::new(Mutex::new(peer_table)),
+        membership: Arc::new(Mutex::new(Membership::new())),
+    }
+}
+
+// Captures a second of constant signal in real time
+fn memory_settings() -> ApplicationSettings {
+    let backend = Arc::new(MemoryBackend::new(AudioFormat { sample_rate: 48000, channels: 1 }, vec![0.1; 48000]));
+    SettingsBuilder::new().audio_backend(backend).build().expect("Failed to build settings")
+}
+
+// Runs a client against `peers` servers on 127.0.0.1, each on its own
+// port, and returns the sequence numbers each one received by port
+fn send_to_peers(peers: usize) -> HashMap<u16, Vec<u32>> {
+    let sockets: Vec<UdpSocket> = (0..peers)
+        .map(|_| UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("Failed to bind a peer"))
+        .collect();
+    let directory = directory_of(&sockets);
+    let settings = memory_settings();
+    let transport = Transport { framing: Framing::Native, frames_per_packet: 1, key: None };
+    let gate = TalkGate::new(TransmitMode::Continuous, TalkSwitch::new());
+    let local = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let mut client = send::start_sending(&settings, &AudioSource::Microphone, InputMap::downmix(1), gate, local, directory, transport)
+        .expect("Failed to start sending");
+    std::thread::sleep(SEND_TIME);
+    client.stop();
+
+    sockets
+        .into_iter()
+        .map(|socket| {
+            socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut sequence = Vec::new();
+            let mut buf = [0u8; protocol::MAX_PACKET_SIZE];
+            while let Ok(amount) = socket.recv(&mut buf) {
+                let packet = protocol::open(&buf[..amount], None).expect("Failed to decode a packet");
+                sequence.push(packet.sequence_number);
+            }
+            (socket.local_addr().unwrap().port(), sequence)
+        })
+        .collect()
+}